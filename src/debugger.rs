@@ -0,0 +1,382 @@
+use std::cmp;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+use filter::Filter;
+use replay::{Control, Until, Stop};
+use transport::Transport;
+
+const HELP: &'static str = "\
+commands:
+    s, step [N]                 replay the next N transactions (default 1)
+    c, continue                 replay until a breakpoint or a differing response
+    u, until N                  replay until before transaction N
+    u, until request REQUEST    replay until after a control transfer with bRequest REQUEST
+    u, until filter EXPR        replay until after a transaction whose Submit matches EXPR
+    skip [N]                    skip the next N transactions (default 1)
+    r, resend                   send the last transaction again
+    e, edit OFFSET BYTES..      overwrite the payload of the next transaction, e.g. `edit 4 ff 00`
+    show                        show the next transaction and the last response next to the recorded one
+    b, break REQUEST            stop before control transfers with bRequest REQUEST
+    d, delete REQUEST           remove a breakpoint
+    breakpoints                 list breakpoints
+    h, help                     print this help
+    q, quit                     stop replaying
+REQUEST is a number or a name like GET_DESCRIPTOR or SET_REPORT";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Step(usize),
+    Until(Until),
+    Skip(u8),
+    Resend,
+    Edit(usize, Vec<u8>),
+    Show,
+    Break(u8),
+    Delete(u8),
+    Breakpoints,
+    Help,
+    Quit,
+}
+
+fn parse_number(s: &str) -> Result<u64, String> {
+    let res = if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16)
+    } else {
+        u64::from_str(s)
+    };
+    res.map_err(|_| format!("invalid number '{}'", s))
+}
+
+/// `bRequest` of standard and HID requests by name
+fn parse_request(s: &str) -> Result<u8, String> {
+    Ok(match &*s.to_uppercase() {
+        "GET_STATUS" => 0x00,
+        "CLEAR_FEATURE" | "GET_REPORT" => 0x01,
+        "GET_IDLE" => 0x02,
+        "SET_FEATURE" | "GET_PROTOCOL" => 0x03,
+        "SET_ADDRESS" => 0x05,
+        "GET_DESCRIPTOR" => 0x06,
+        "SET_DESCRIPTOR" => 0x07,
+        "GET_CONFIGURATION" => 0x08,
+        "SET_CONFIGURATION" | "SET_REPORT" => 0x09,
+        "GET_INTERFACE" | "SET_IDLE" => 0x0a,
+        "SET_INTERFACE" | "SET_PROTOCOL" => 0x0b,
+        "SYNCH_FRAME" => 0x0c,
+        _ => match parse_number(s) {
+            Ok(n) if n <= 0xff => n as u8,
+            _ => return Err(format!("unknown request '{}'", s)),
+        },
+    })
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let cmd = words.next().unwrap_or("step");
+    let args: Vec<_> = words.collect();
+    let arg = |i: usize| args.get(i).cloned().ok_or_else(|| format!("{} needs more arguments", cmd));
+    let count = || args.get(0).map_or(Ok(1), |&n| parse_number(n));
+    Ok(match cmd {
+        "s" | "step" => Command::Step(try!(count()) as usize),
+        "c" | "continue" => Command::Until(Until::End),
+        "u" | "until" => match try!(arg(0)) {
+            "request" => Command::Until(Until::Request(try!(parse_request(try!(arg(1)))))),
+            "filter" => {
+                let expr = args[1..].join(" ");
+                match Filter::parse(&expr) {
+                    Ok(filter) => Command::Until(Until::Filter(filter)),
+                    Err(e) => return Err(e.highlight(&expr)),
+                }
+            },
+            n => Command::Until(Until::Index(try!(parse_number(n)) as usize)),
+        },
+        "skip" => Command::Skip(cmp::min(try!(count()), 0xff) as u8),
+        "r" | "resend" => Command::Resend,
+        "e" | "edit" => {
+            let offset = try!(parse_number(try!(arg(0)))) as usize;
+            let mut bytes = Vec::new();
+            for b in &args[1..] {
+                match u8::from_str_radix(b.trim_left_matches("0x"), 16) {
+                    Ok(b) => bytes.push(b),
+                    Err(_) => return Err(format!("invalid byte '{}'", b)),
+                }
+            }
+            if bytes.is_empty() {
+                return Err("edit needs bytes to write".to_string());
+            }
+            Command::Edit(offset, bytes)
+        },
+        "show" => Command::Show,
+        "b" | "break" => Command::Break(try!(parse_request(try!(arg(0))))),
+        "d" | "delete" => Command::Delete(try!(parse_request(try!(arg(0))))),
+        "breakpoints" => Command::Breakpoints,
+        "h" | "help" => Command::Help,
+        "q" | "quit" => Command::Quit,
+        _ => return Err(format!("unknown command '{}', try help", cmd)),
+    })
+}
+
+/// Prints two payloads as hex next to each other, marking differing rows
+fn print_side_by_side(expected: &[u8], actual: &[u8]) {
+    let hex = |data: &[u8], row: usize| {
+        let bytes: Vec<_> = data.iter().skip(row * 16).take(16).map(|b| format!("{:02x}", b)).collect();
+        bytes.join(" ")
+    };
+    println!("offset  {:<47}   {}", "expected", "actual");
+    let rows = (cmp::max(expected.len(), actual.len()) + 15) / 16;
+    for row in 0..rows {
+        let (e, a) = (hex(expected, row), hex(actual, row));
+        let marker = if e != a { "*" } else { " " };
+        println!("0x{:04x}  {:<47} {} {}", row * 16, e, marker, a);
+    }
+}
+
+/// Interactive replay, reading commands from stdin
+pub struct Debugger<'a, T: Transport + 'a> {
+    control: &'a mut Control<T>,
+    breakpoints: Vec<u8>,
+}
+
+impl<'a, T: Transport> Debugger<'a, T> {
+    pub fn new(control: &'a mut Control<T>) -> Debugger<'a, T> {
+        Debugger {
+            control: control,
+            breakpoints: Vec::new(),
+        }
+    }
+
+    /// Runs until all transactions are replayed or the user quits. If
+    /// `prompt` is false, replay continues until it stops for the first time.
+    pub fn run(&mut self, prompt: bool) {
+        if prompt {
+            self.prompt();
+        } else {
+            self.run_to(&[Until::End]);
+        }
+    }
+
+    /// Replays until each of `targets` in turn and returns once the last
+    /// one is reached. Stopping anywhere else, e.g. at a differing
+    /// response, hands over to the prompt for the rest of the replay.
+    pub fn run_to(&mut self, targets: &[Until]) {
+        for until in targets {
+            let stop = self.control.replay_until(until, &self.breakpoints);
+            if stop == Stop::Reached {
+                continue;
+            }
+            if self.report(stop) {
+                self.prompt();
+            }
+            return;
+        }
+    }
+
+    /// Reads and executes commands until stdin is closed or the user quits
+    fn prompt(&mut self) {
+        let stdin = io::stdin();
+        loop {
+            print!("({}) ", self.control.position());
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {},
+            }
+            let cmd = match parse_command(&line) {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                }
+            };
+            if !self.execute(cmd) {
+                return;
+            }
+        }
+    }
+
+    /// Returns false if the debugger should exit
+    fn execute(&mut self, cmd: Command) -> bool {
+        // the time spent at the prompt isn't part of the recording
+        self.control.resync();
+        match cmd {
+            Command::Step(n) => {
+                let stop = self.control.replay_until(&Until::Count(n), &self.breakpoints);
+                return self.report(stop);
+            },
+            Command::Until(until) => {
+                let stop = self.control.replay_until(&until, &self.breakpoints);
+                return self.report(stop);
+            },
+            Command::Skip(n) => {
+                let n = cmp::min(n as usize, self.control.remaining()) as u8;
+                self.control.skip(n);
+                println!("skipped {} transactions", n);
+            },
+            Command::Resend => match self.control.rewind() {
+                Ok(()) => {
+                    let stop = self.control.replay_until(&Until::Count(1), &[]);
+                    return self.report(stop);
+                },
+                Err(e) => println!("{}", e),
+            },
+            Command::Edit(offset, bytes) => match self.control.edit_next(offset, &bytes) {
+                Ok(()) => self.show_next(),
+                Err(e) => println!("{}", e),
+            },
+            Command::Show => {
+                self.show_last();
+                self.show_next();
+            },
+            Command::Break(b) => {
+                if !self.breakpoints.contains(&b) {
+                    self.breakpoints.push(b);
+                }
+            },
+            Command::Delete(b) => self.breakpoints.retain(|&x| x != b),
+            Command::Breakpoints => {
+                let breakpoints: Vec<_> = self.breakpoints.iter().map(|b| format!("0x{:02x}", b)).collect();
+                println!("breakpoints: {}", breakpoints.join(", "));
+            },
+            Command::Help => println!("{}", HELP),
+            Command::Quit => return false,
+        }
+        true
+    }
+
+    /// Prints why replay stopped. Returns false if all transactions were
+    /// replayed.
+    fn report(&self, stop: Stop) -> bool {
+        match stop {
+            Stop::Reached => {},
+            Stop::Breakpoint(b) => println!("breakpoint at bRequest 0x{:02x}", b),
+            Stop::Mismatch => self.show_last(),
+            Stop::Error(e) => println!("Error replaying packet: {}", e),
+            Stop::End => {
+                println!("all transactions replayed");
+                return false;
+            },
+        }
+        self.show_next();
+        true
+    }
+
+    fn show_next(&self) {
+        match self.control.peek() {
+            Some(t) => {
+                println!("next {}: {}", self.control.position(), t);
+                if let Some(s) = t.submit() {
+                    if !s.get_data().is_empty() {
+                        let bytes: Vec<_> = s.get_data().iter().map(|b| format!("{:02x}", b)).collect();
+                        println!("    {}", bytes.join(" "));
+                    }
+                }
+            },
+            None => println!("no transactions left"),
+        }
+    }
+
+    fn show_last(&self) {
+        let (index, t, received) = match self.control.last() {
+            Some(last) => last,
+            None => return println!("nothing was received yet"),
+        };
+        println!("last {}: {}", index, t);
+        match *received {
+            Ok(ref data) => print_side_by_side(t.response_data(), data),
+            Err(e) => println!("    received {}, expected {:?}", e, t.status()),
+        }
+        if self.control.in_flight() > 0 {
+            println!("{} transfers in flight", self.control.in_flight());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use filter::Filter;
+    use replay::{Control, Until, SET_REPORT};
+    use synth::{self, Transfer};
+    use transport::MockTransport;
+    use super::{parse_command, Command, Debugger};
+
+    #[test]
+    fn parses_steps_and_request_names() {
+        assert_eq!(parse_command("\n"), Ok(Command::Step(1)));
+        assert_eq!(parse_command("s 0x10"), Ok(Command::Step(16)));
+        assert_eq!(parse_command("b set_report"), Ok(Command::Break(SET_REPORT)));
+        assert_eq!(parse_command("d 0x21"), Ok(Command::Delete(0x21)));
+        assert_eq!(parse_command("until request GET_DESCRIPTOR"), Ok(Command::Until(Until::Request(0x06))));
+        assert_eq!(parse_command("u 12"), Ok(Command::Until(Until::Index(12))));
+        assert!(parse_command("b 256").is_err());
+        assert!(parse_command("b SET_COLOR").is_err());
+        assert!(parse_command("jump").is_err());
+    }
+
+    #[test]
+    fn parses_until_filter() {
+        let filter = Filter::parse("request == 0x09 && len > 2").unwrap();
+        assert_eq!(parse_command("u filter request == 0x09 && len > 2"), Ok(Command::Until(Until::Filter(filter))));
+        assert!(parse_command("until filter request ==").is_err());
+    }
+
+    #[test]
+    fn parses_edit() {
+        assert_eq!(parse_command("e 4 ff 0x00"), Ok(Command::Edit(4, vec![0xff, 0x00])));
+        assert_eq!(parse_command("edit 4"), Err("edit needs bytes to write".to_string()));
+        assert_eq!(parse_command("edit 4 zz"), Err("invalid byte 'zz'".to_string()));
+        assert!(parse_command("edit").is_err());
+    }
+
+    /// Two GET_DESCRIPTORs and a SET_REPORT, answered like recorded
+    fn control() -> Control<MockTransport> {
+        let transactions = synth::synthesize(&[
+            Transfer::control(0x80, 0x06, 0x0100, 0, vec![18, 1]),
+            Transfer::control(0x80, 0x06, 0x0200, 0, vec![9, 2]),
+            Transfer::control(0x21, 0x09, 0x0211, 1, vec![0x11, 0xff]),
+        ]).unwrap();
+        Control::from_transactions(transactions.clone(), MockTransport::new(transactions))
+    }
+
+    #[test]
+    fn executes_steps_breakpoints_and_edits() {
+        let mut control = control();
+        {
+            let mut debugger = Debugger::new(&mut control);
+            assert!(debugger.execute(Command::Step(1)));
+            assert_eq!(debugger.control.position(), 1);
+            assert!(debugger.execute(Command::Resend));
+            assert_eq!(debugger.control.position(), 1);
+            assert!(debugger.execute(Command::Break(SET_REPORT)));
+            assert!(debugger.execute(Command::Until(Until::End)));
+            assert_eq!(debugger.control.position(), 2);
+            assert!(debugger.execute(Command::Edit(1, vec![0xaa])));
+            assert_eq!(debugger.control.peek().unwrap().request_data(), &[0x11, 0xaa]);
+            assert!(debugger.execute(Command::Delete(SET_REPORT)));
+            assert!(debugger.breakpoints.is_empty());
+            // the edited SET_REPORT is the last one
+            assert!(debugger.execute(Command::Step(1)));
+            assert!(!debugger.execute(Command::Step(1)));
+            assert!(!debugger.execute(Command::Quit));
+        }
+        assert_eq!(control.report().totals.total, 4);
+    }
+
+    #[test]
+    fn runs_to_each_target() {
+        let mut control = control();
+        Debugger::new(&mut control).run_to(&[Until::Request(0x06), Until::Request(0x06)]);
+        assert_eq!(control.position(), 2);
+        assert_eq!(control.report().totals.correct, 2);
+    }
+
+    #[test]
+    fn skips_transactions() {
+        let mut control = control();
+        {
+            let mut debugger = Debugger::new(&mut control);
+            assert!(debugger.execute(Command::Skip(5)));
+            assert_eq!(debugger.control.position(), 3);
+        }
+        assert_eq!(control.report().totals.total, 0);
+    }
+}
@@ -0,0 +1,289 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::path::Path;
+use byteorder::{ByteOrder, LittleEndian};
+use libusb::Version;
+use capture::CaptureError;
+use filter::Filter;
+use print::{self, DeviceTree, Descriptor, Config, InterfaceTree, AltSetting, Endpoint, Hid};
+use transaction::{self, Transaction};
+use usb::{SetupRequest, DescriptorType, UrbStatus};
+
+/// Longest GET_DESCRIPTOR responses of a device. Hosts usually read the
+/// first bytes of a descriptor to learn its length before reading it whole.
+#[derive(Default)]
+struct Responses {
+    device: Vec<u8>,
+    configs: BTreeMap<u8, Vec<u8>>,
+    strings: BTreeMap<u8, String>,
+    /// Report descriptors by interface
+    reports: BTreeMap<u16, Vec<u8>>,
+}
+
+fn keep_longest(old: &mut Vec<u8>, new: &[u8]) {
+    if new.len() > old.len() {
+        *old = new.to_vec();
+    }
+}
+
+impl Responses {
+    fn add(&mut self, kind: DescriptorType, index: u8, lang: u16, data: &[u8]) {
+        match kind {
+            DescriptorType::Device => keep_longest(&mut self.device, data),
+            DescriptorType::Configuration => keep_longest(self.configs.entry(index).or_insert_with(Vec::new), data),
+            // string 0 is the list of languages
+            DescriptorType::String if index != 0 && data.len() >= 2 => {
+                let len = cmp::min(data[0] as usize, data.len());
+                // bLength covers itself and bDescriptorType
+                if len < 2 {
+                    return;
+                }
+                let utf16: Vec<u16> = data[2..len].chunks(2).filter(|c| c.len() == 2)
+                    .map(LittleEndian::read_u16).collect();
+                self.strings.insert(index, String::from_utf16_lossy(&utf16));
+            },
+            // wIndex is the interface
+            DescriptorType::Report => keep_longest(self.reports.entry(lang).or_insert_with(Vec::new), data),
+            _ => {},
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.device.is_empty() && self.configs.is_empty() && self.strings.is_empty() && self.reports.is_empty()
+    }
+
+    /// Adds the responses of `other`, e.g. read before the device got its
+    /// address
+    fn merge(&mut self, other: Responses) {
+        keep_longest(&mut self.device, &other.device);
+        for (index, config) in other.configs {
+            keep_longest(self.configs.entry(index).or_insert_with(Vec::new), &config);
+        }
+        for (index, string) in other.strings {
+            self.strings.entry(index).or_insert(string);
+        }
+        for (interface, report) in other.reports {
+            keep_longest(self.reports.entry(interface).or_insert_with(Vec::new), &report);
+        }
+    }
+
+    fn string(&self, index: u8) -> Option<String> {
+        self.strings.get(&index).cloned()
+    }
+
+    /// Fields of a device descriptor which wasn't read completely are 0
+    fn tree(&self, bus: u16, address: u8) -> DeviceTree {
+        let mut d = self.device.clone();
+        if d.len() < 18 {
+            d.resize(18, 0);
+        }
+        let descriptor = Descriptor {
+            usb_version: bcd(LittleEndian::read_u16(&d[2..])),
+            device_version: bcd(LittleEndian::read_u16(&d[12..])),
+            class_code: d[4],
+            sub_class_code: d[5],
+            protocol_code: d[6],
+            vendor_id: LittleEndian::read_u16(&d[8..]),
+            product_id: LittleEndian::read_u16(&d[10..]),
+            max_packet_size: d[7],
+            num_configurations: d[17],
+            manufacturer: self.string(d[14]),
+            product: self.string(d[15]),
+            serial_number: self.string(d[16]),
+        };
+        DeviceTree {
+            bus: bus,
+            address: address,
+            speed: "Unknown".to_string(),
+            descriptor: descriptor,
+            configs: self.configs.values().filter_map(|c| self.config(c)).collect(),
+        }
+    }
+
+    /// Parses a configuration descriptor with all interface, HID and
+    /// endpoint descriptors following it
+    fn config(&self, bytes: &[u8]) -> Option<Config> {
+        if bytes.len() < 9 {
+            return None;
+        }
+        let mut config = Config {
+            number: bytes[5],
+            max_power: bytes[8] as u16 * 2,
+            self_powered: bytes[7] & 0x40 != 0,
+            remote_wakeup: bytes[7] & 0x20 != 0,
+            num_interfaces: bytes[4],
+            description: self.string(bytes[6]),
+            interfaces: Vec::new(),
+        };
+        let mut i = bytes[0] as usize;
+        while i + 2 <= bytes.len() {
+            let len = bytes[i] as usize;
+            if len < 2 {
+                break;
+            }
+            let d = &bytes[i..cmp::min(i + len, bytes.len())];
+            i += len;
+            match DescriptorType::from(d[1]) {
+                DescriptorType::Interface if d.len() >= 9 => {
+                    let alt = AltSetting {
+                        number: d[2],
+                        setting_number: d[3],
+                        class_code: d[5],
+                        sub_class_code: d[6],
+                        protocol_code: d[7],
+                        num_endpoints: d[4],
+                        description: self.string(d[8]),
+                        hid: None,
+                        endpoints: Vec::new(),
+                    };
+                    if config.interfaces.last().map_or(true, |i| i.number != alt.number) {
+                        config.interfaces.push(InterfaceTree { number: alt.number, alt_settings: Vec::new() });
+                    }
+                    config.interfaces.last_mut().unwrap().alt_settings.push(alt);
+                },
+                DescriptorType::Hid if d.len() >= 9 => {
+                    let interface = config.interfaces.last().map(|i| i.number);
+                    if let Some(alt) = config.interfaces.last_mut().and_then(|i| i.alt_settings.last_mut()) {
+                        alt.hid = Some(Hid {
+                            hid_version: bcd(LittleEndian::read_u16(&d[2..])),
+                            country_code: d[4],
+                            report_descriptor_length: LittleEndian::read_u16(&d[7..]),
+                            report_descriptor: interface.and_then(|i| self.reports.get(&(i as u16)).cloned()),
+                        });
+                    }
+                },
+                DescriptorType::Endpoint if d.len() >= 7 => {
+                    if let Some(alt) = config.interfaces.last_mut().and_then(|i| i.alt_settings.last_mut()) {
+                        alt.endpoints.push(endpoint(d));
+                    }
+                },
+                _ => {},
+            }
+        }
+        Some(config)
+    }
+}
+
+/// Version of a BCD field as libusb decodes it
+fn bcd(raw: u16) -> String {
+    let major = ((raw >> 12) & 0x0f) * 10 + ((raw >> 8) & 0x0f);
+    print::version_to_string(&Version(major as u8, ((raw >> 4) & 0x0f) as u8, (raw & 0x0f) as u8))
+}
+
+fn endpoint(d: &[u8]) -> Endpoint {
+    let attributes = d[3];
+    Endpoint {
+        address: d[2],
+        number: d[2] & 0x0f,
+        direction: if d[2] & 0x80 != 0 { "In" } else { "Out" }.to_string(),
+        transfer_type: ["Control", "Isochronous", "Bulk", "Interrupt"][(attributes & 0x03) as usize].to_string(),
+        sync_type: ["NoSync", "Asynchronous", "Adaptive", "Synchronous"][((attributes >> 2) & 0x03) as usize].to_string(),
+        usage_type: ["Data", "Feedback", "FeedbackData", "Reserved"][((attributes >> 4) & 0x03) as usize].to_string(),
+        max_packet_size: LittleEndian::read_u16(&d[4..]),
+        interval: d[6],
+    }
+}
+
+/// Rebuilds the descriptor trees of all devices which completed a
+/// GET_DESCRIPTOR request. Descriptors which weren't captured are missing
+/// from the trees.
+///
+/// Requests to address 0 are made while a device is enumerated, they are
+/// added to the device which is assigned an address by the following
+/// SET_ADDRESS, or else to the next device with traffic on the bus. The speed
+/// isn't recorded and reported as `Unknown`.
+pub fn from_transactions(transactions: &[Transaction]) -> Vec<DeviceTree> {
+    let mut devices: BTreeMap<(u16, u8), Responses> = BTreeMap::new();
+    // responses at address 0 by bus, and the address assigned afterwards
+    let mut enumerating: BTreeMap<u16, (Responses, Option<u8>)> = BTreeMap::new();
+    for t in transactions {
+        let submit = match t.submit() {
+            Some(submit) => submit,
+            None => continue,
+        };
+        let (bus, address) = (submit.get_bus_id(), submit.get_device());
+        let success = t.status() == Some(UrbStatus::Success);
+        if address == 0 {
+            if let (Some(SetupRequest::SetAddress { address: assigned }), true) = (t.request(), success) {
+                enumerating.entry(bus).or_insert_with(|| (Responses::default(), None)).1 = Some(assigned as u8);
+            }
+        } else if enumerating.get(&bus).map_or(false, |&(_, a)| a.map_or(true, |a| a == address)) {
+            let (early, _) = enumerating.remove(&bus).unwrap();
+            devices.entry((bus, address)).or_insert_with(Responses::default).merge(early);
+        }
+        let (kind, index, lang) = match t.request() {
+            Some(SetupRequest::GetDescriptor { kind, index, lang, .. }) => (kind, index, lang),
+            _ => continue,
+        };
+        if !success {
+            continue;
+        }
+        let responses = if address == 0 {
+            &mut enumerating.entry(bus).or_insert_with(|| (Responses::default(), None)).0
+        } else {
+            devices.entry((bus, address)).or_insert_with(Responses::default)
+        };
+        responses.add(kind, index, lang, t.response_data());
+    }
+    // devices which never got an address
+    for (bus, (responses, _)) in enumerating {
+        if responses.is_empty() {
+            continue;
+        }
+        devices.entry((bus, 0)).or_insert_with(Responses::default).merge(responses);
+    }
+    devices.iter().map(|(&(bus, device), r)| r.tree(bus, device)).collect()
+}
+
+pub fn from_capture(path: &Path, filter: &Filter) -> Result<Vec<DeviceTree>, CaptureError> {
+    Ok(from_transactions(&try!(transaction::from_capture(path, filter))))
+}
+
+#[cfg(test)]
+mod tests {
+    use synth::Transfer;
+    use transaction::Transaction;
+    use super::from_transactions;
+
+    fn device_descriptor(len: usize) -> Vec<u8> {
+        let d = [18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x6d, 0x04, 0x5f, 0xc3, 0x01, 0x00, 0, 0, 0, 1];
+        d[..len].to_vec()
+    }
+
+    fn at(address: u8, transfer: Transfer) -> Transaction {
+        let mut t = transfer.to_transaction(1, 0);
+        t.submit_mut().unwrap().set_address(1, address);
+        t
+    }
+
+    #[test]
+    fn merges_enumeration_into_assigned_address() {
+        let transactions = vec![
+            at(0, Transfer::control(0x80, 0x06, 0x0100, 0, device_descriptor(8))),
+            at(0, Transfer::control(0x00, 0x05, 5, 0, Vec::new())),
+            at(5, Transfer::control(0x00, 0x09, 1, 0, Vec::new())),
+        ];
+        let trees = from_transactions(&transactions);
+        assert_eq!(trees.len(), 1);
+        assert_eq!(trees[0].address, 5);
+        assert_eq!(trees[0].descriptor.max_packet_size, 64);
+    }
+
+    #[test]
+    fn emits_partial_trees() {
+        let config = vec![9, 2, 9, 0, 0, 1, 0, 0xa0, 50];
+        let trees = from_transactions(&[at(3, Transfer::control(0x80, 0x06, 0x0200, 0, config))]);
+        assert_eq!(trees.len(), 1);
+        assert_eq!(trees[0].descriptor.vendor_id, 0);
+        assert_eq!(trees[0].configs[0].max_power, 100);
+    }
+
+    #[test]
+    fn skips_strings_shorter_than_their_header() {
+        let mut t = at(3, Transfer::control(0x80, 0x06, 0x0301, 0x0409, vec![1, 3, b'a', 0]));
+        t.submit_mut().unwrap().set_address(300, 3);
+        let trees = from_transactions(&[t]);
+        assert_eq!(trees.len(), 1);
+        assert_eq!(trees[0].bus, 300);
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use transaction::Transaction;
+
+/// Difference between two captures, from the first one's point of view
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edit<'a> {
+    Same(&'a Transaction, &'a Transaction),
+    /// Only in the first capture
+    Deleted(&'a Transaction),
+    /// Only in the second capture
+    Inserted(&'a Transaction),
+    /// Same kind of request with different headers or payload
+    Modified(&'a Transaction, &'a Transaction),
+}
+
+/// Transactions are of the same kind if they go to the same endpoint with
+/// the same control request
+fn similar(a: &Transaction, b: &Transaction) -> bool {
+    let request = |t: &Transaction| t.submit().map(|s| (s.get_bm_request_type(), s.get_b_request()));
+    a.transfer_type() == b.transfer_type()
+        && a.endpoint() == b.endpoint()
+        && request(a) == request(b)
+}
+
+/// Aligns two transaction sequences by their longest common subsequence of
+/// `Transaction::same` transactions. Between two aligned transactions,
+/// deleted and inserted transactions of the same kind are paired in order
+/// and reported as modified.
+pub fn diff<'a>(a: &'a [Transaction], b: &'a [Transaction]) -> Vec<Edit<'a>> {
+    let (n, m) = (a.len(), b.len());
+    // lcs[i * (m+1) + j]: length of the LCS of a[i..] and b[j..]
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i * (m + 1) + j] = if a[i].same(&b[j]) {
+                lcs[(i + 1) * (m + 1) + j + 1] + 1
+            } else {
+                ::std::cmp::max(lcs[(i + 1) * (m + 1) + j], lcs[i * (m + 1) + j + 1])
+            };
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut deleted, mut inserted) = (Vec::new(), Vec::new());
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[i].same(&b[j]) {
+            flush(&mut edits, &mut deleted, &mut inserted);
+            edits.push(Edit::Same(&a[i], &b[j]));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1]) {
+            deleted.push(&a[i]);
+            i += 1;
+        } else {
+            inserted.push(&b[j]);
+            j += 1;
+        }
+    }
+    flush(&mut edits, &mut deleted, &mut inserted);
+    edits
+}
+
+/// Emits the deleted and inserted transactions between two aligned ones
+fn flush<'a>(edits: &mut Vec<Edit<'a>>, deleted: &mut Vec<&'a Transaction>,
+             inserted: &mut Vec<&'a Transaction>) {
+    let mut inserted = inserted.drain(..).peekable();
+    for d in deleted.drain(..) {
+        // pair with the next inserted transaction of the same kind,
+        // everything inserted before it stays inserted
+        while let Some(&i) = inserted.peek() {
+            if similar(d, i) {
+                break;
+            }
+            edits.push(Edit::Inserted(i));
+            inserted.next();
+        }
+        match inserted.next() {
+            Some(i) => edits.push(Edit::Modified(d, i)),
+            None => edits.push(Edit::Deleted(d)),
+        }
+    }
+    edits.extend(inserted.map(Edit::Inserted));
+}
+
+/// Byte-level difference of two payloads
+pub struct ByteDiff<'a> {
+    pub a: &'a [u8],
+    pub b: &'a [u8],
+}
+
+impl<'a> ByteDiff<'a> {
+    pub fn new(a: &'a [u8], b: &'a [u8]) -> ByteDiff<'a> {
+        ByteDiff { a: a, b: b }
+    }
+
+    /// Offsets at which the payloads differ, including the surplus of the
+    /// longer one
+    pub fn offsets(&self) -> Vec<usize> {
+        (0..::std::cmp::max(self.a.len(), self.b.len()))
+            .filter(|&i| self.a.get(i) != self.b.get(i))
+            .collect()
+    }
+
+    fn write_side(&self, f: &mut fmt::Formatter, data: &[u8], offsets: &[usize]) -> fmt::Result {
+        for (i, b) in data.iter().enumerate() {
+            if offsets.contains(&i) {
+                try!(write!(f, "[{:02x}]", b));
+            } else {
+                try!(write!(f, " {:02x} ", b));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders both payloads as hex with differing bytes in brackets, followed
+/// by the differing offsets
+impl<'a> fmt::Display for ByteDiff<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let offsets = self.offsets();
+        try!(write!(f, "    - "));
+        try!(self.write_side(f, self.a, &offsets));
+        try!(write!(f, "\n    + "));
+        try!(self.write_side(f, self.b, &offsets));
+        let offsets: Vec<_> = offsets.iter().map(|o| format!("0x{:02x}", o)).collect();
+        write!(f, "\n    offsets: {}", offsets.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use filter::Filter;
+    use transaction::{self, Transaction};
+    use super::{diff, Edit, ByteDiff};
+
+    fn handshake(name: &str) -> Vec<Transaction> {
+        transaction::from_capture(&Path::new("pcap/g910/handshake").join(name), &Filter::all()).unwrap()
+    }
+
+    #[test]
+    fn aligns_handshakes() {
+        let (a, b) = (handshake("handshake2.pcap"), handshake("handshake3.pcap"));
+        let edits = diff(&a, &b);
+        // the edits walk both captures in order
+        let (mut i, mut j) = (0, 0);
+        let (mut same, mut deleted, mut inserted) = (0, 0, 0);
+        let mut modified = Vec::new();
+        for edit in &edits {
+            match *edit {
+                Edit::Same(x, y) => {
+                    assert!(x == &a[i] && y == &b[j] && x.same(y));
+                    same += 1;
+                    i += 1;
+                    j += 1;
+                },
+                Edit::Deleted(x) => {
+                    assert!(x == &a[i]);
+                    deleted += 1;
+                    i += 1;
+                },
+                Edit::Inserted(y) => {
+                    assert!(y == &b[j]);
+                    inserted += 1;
+                    j += 1;
+                },
+                Edit::Modified(x, y) => {
+                    assert!(x == &a[i] && y == &b[j] && !x.same(y));
+                    assert_eq!((x.transfer_type(), x.endpoint()), (y.transfer_type(), y.endpoint()));
+                    modified.push((i, j));
+                    i += 1;
+                    j += 1;
+                },
+            }
+        }
+        assert_eq!((i, j), (a.len(), b.len()));
+        assert_eq!((same, deleted, inserted, modified.len()), (233, 7, 7, 12));
+        // the HID++ answers differing in their payload stay aligned
+        assert_eq!(&modified[..4], &[(39, 39), (40, 40), (149, 149), (204, 204)]);
+        assert_eq!(ByteDiff::new(a[149].response_data(), b[149].response_data()).offsets(), vec![4, 5]);
+        // four transfers more before the color reports shift the rest
+        assert_eq!(modified[4], (208, 212));
+    }
+
+    #[test]
+    fn aligns_identical_captures() {
+        let a = handshake("handshake3.pcap");
+        let edits = diff(&a, &a);
+        assert_eq!(edits.len(), a.len());
+        assert!(edits.iter().all(|e| match *e {
+            Edit::Same(..) => true,
+            _ => false,
+        }));
+        assert!(diff(&a, &[]).iter().all(|e| match *e {
+            Edit::Deleted(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn lists_differing_offsets() {
+        assert_eq!(ByteDiff::new(&[1, 2, 3], &[1, 4, 3, 5]).offsets(), vec![1, 3]);
+        assert!(ByteDiff::new(&[1, 2], &[1, 2]).offsets().is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use capture::{Capture, CaptureError};
+use hidpp::{self, Message};
+use usb::{Packet, UrbType, Direction};
+
+pub const ROOT: u16 = 0x0000;
+pub const FEATURE_SET: u16 = 0x0001;
+
+/// IRoot.getFeature(featureId) -> featureIndex
+const ROOT_GET_FEATURE: u8 = 0;
+/// IFeatureSet.getFeatureId(featureIndex) -> featureId
+const FEATURE_SET_GET_FEATURE_ID: u8 = 1;
+
+/// Name of a HID++ 2.0 feature as used in Logitech's documentation
+pub fn feature_name(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x0000 => "IRoot",
+        0x0001 => "IFeatureSet",
+        0x0002 => "IFeatureInfo",
+        0x0003 => "DeviceFwVersion",
+        0x0005 => "DeviceName",
+        0x0007 => "DeviceFriendlyName",
+        0x0020 => "ConfigChange",
+        0x00c0 => "DfuControl",
+        0x00c1 => "DfuControlSigned",
+        0x00d0 => "Dfu",
+        0x1000 => "BatteryStatus",
+        0x1001 => "BatteryVoltage",
+        0x1300 => "LedControl",
+        0x1802 => "DeviceReset",
+        0x1814 => "ChangeHost",
+        0x1815 => "HostsInfo",
+        0x1b00 => "ReprogControls",
+        0x1b04 => "ReprogControlsV4",
+        0x1bc0 => "PersistentRemappableAction",
+        0x1d4b => "WirelessDeviceStatus",
+        0x1e00 => "EnableHiddenFeatures",
+        0x1f20 => "AdcMeasurement",
+        0x2100 => "VerticalScrolling",
+        0x2200 => "MousePointer",
+        0x2201 => "AdjustableDpi",
+        0x40a0 => "FnInversion",
+        0x4100 => "Encryption",
+        0x4520 => "KeyboardLayout",
+        0x4521 => "DisableKeys",
+        0x4522 => "DisableKeysByUsage",
+        0x4530 => "DualPlatform",
+        0x4540 => "KeyboardInternationalLayouts",
+        0x8010 => "GKeys",
+        0x8020 => "MKeys",
+        0x8030 => "MacroRecord",
+        0x8040 => "BrightnessControl",
+        0x8060 => "AdjustableReportRate",
+        0x8070 => "ColorLedEffects",
+        0x8071 => "RgbEffects",
+        0x8080 => "PerKeyLighting",
+        0x8081 => "PerKeyLightingV2",
+        0x8090 => "ModeStatus",
+        0x8100 => "OnboardProfiles",
+        0x8110 => "MouseButtonSpy",
+        _ => return None,
+    })
+}
+
+/// Key matching a response to its request
+type CallKey = (u8, u8, u8, u8);
+
+fn call_key(msg: &Message) -> CallKey {
+    (msg.device_index, msg.feature_index, msg.function, msg.sw_id)
+}
+
+/// Feature index to feature id mapping of every HID++ 2.0 device index,
+/// learned from IRoot and IFeatureSet calls seen in a capture.
+pub struct FeatureTable {
+    devices: HashMap<u8, BTreeMap<u8, u16>>,
+    /// Parameters of requests waiting for their response
+    pending: HashMap<CallKey, Vec<u8>>,
+}
+
+impl FeatureTable {
+    pub fn new() -> FeatureTable {
+        FeatureTable {
+            devices: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Learns the feature table from all packets of a capture, usually
+    /// the device's handshake.
+    pub fn from_capture(path: &Path) -> Result<FeatureTable, CaptureError> {
+        let mut table = FeatureTable::new();
+        let mut cap = try!(Capture::from_file(path));
+        while let Some(packet) = cap.next() {
+            table.feed(&try!(packet));
+        }
+        Ok(table)
+    }
+
+    pub fn feature_id(&self, device_index: u8, feature_index: u8) -> Option<u16> {
+        if feature_index == 0 {
+            // IRoot is always at index 0
+            return Some(ROOT);
+        }
+        self.devices.get(&device_index).and_then(|d| d.get(&feature_index)).cloned()
+    }
+
+    fn feature_index(&self, device_index: u8, id: u16) -> Option<u8> {
+        self.devices.get(&device_index)
+            .and_then(|d| d.iter().find(|&(_, &i)| i == id).map(|(&index, _)| index))
+    }
+
+    /// Index of IFeatureSet. Hosts often skip looking it up, as it is at
+    /// index 1 on every known device.
+    fn feature_set_index(&self, device_index: u8) -> u8 {
+        self.feature_index(device_index, FEATURE_SET).unwrap_or(1)
+    }
+
+    fn insert(&mut self, device_index: u8, feature_index: u8, id: u16) {
+        self.devices.entry(device_index).or_insert_with(BTreeMap::new).insert(feature_index, id);
+    }
+
+    /// Learns from a packet if it carries a HID++ message. Requests are sent
+    /// by the host, responses are received with the same feature index,
+    /// function and software id. Error responses drop the failed request.
+    pub fn feed(&mut self, packet: &Packet) {
+        let msg = match Message::parse(packet.get_data()) {
+            Some(msg) => msg,
+            None => return,
+        };
+        let is_request = packet.get_urb_type() == UrbType::Submit
+            && packet.get_direction() == Direction::Out;
+        if is_request {
+            self.pending.insert(call_key(&msg), msg.params.to_vec());
+            return;
+        }
+        match msg.error() {
+            Some(hidpp::Error::Feature { feature_index, function, sw_id, .. }) => {
+                self.pending.remove(&(msg.device_index, feature_index, function, sw_id));
+                return;
+            },
+            Some(hidpp::Error::Register { sub_id, address, .. }) => {
+                self.pending.remove(&(msg.device_index, sub_id, address >> 4, address & 0x0f));
+                return;
+            },
+            None => {},
+        }
+        let request = match self.pending.remove(&call_key(&msg)) {
+            Some(request) => request,
+            None => return,
+        };
+        let dev = msg.device_index;
+        if msg.feature_index == 0 && msg.function == ROOT_GET_FEATURE {
+            let id = (request[0] as u16) << 8 | request[1] as u16;
+            let index = msg.params[0];
+            // index 0 means the feature is not supported
+            if index != 0 || id == ROOT {
+                self.insert(dev, index, id);
+            }
+        } else if msg.feature_index == self.feature_set_index(dev)
+                && msg.function == FEATURE_SET_GET_FEATURE_ID {
+            let id = (msg.params[0] as u16) << 8 | msg.params[1] as u16;
+            self.insert(dev, request[0], id);
+        }
+    }
+
+    /// Renders a message like `Message`'s `Display`, naming its feature.
+    pub fn describe(&self, msg: &Message) -> String {
+        if msg.error().is_some() {
+            return msg.to_string();
+        }
+        let feature = match self.feature_id(msg.device_index, msg.feature_index) {
+            Some(id) => format!("{}(0x{:04x})", feature_name(id).unwrap_or("?"), id),
+            None => "?".to_string(),
+        };
+        format!("dev=0x{:02x} {}@0x{:02x} fn={} sw=0x{:x} [{}]", msg.device_index, feature,
+                msg.feature_index, msg.function, msg.sw_id, hidpp::to_hex(msg.trimmed_params()))
+    }
+
+    pub fn print(&self) {
+        let mut devices: Vec<_> = self.devices.iter().collect();
+        devices.sort_by_key(|&(dev, _)| *dev);
+        for (dev, features) in devices {
+            println!("Device 0x{:02x}:", dev);
+            for (index, id) in features {
+                println!("    0x{:02x}: 0x{:04x} {}", index, id, feature_name(*id).unwrap_or(""));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use synth::Transfer;
+    use super::FeatureTable;
+
+    fn features(path: &str, device_index: u8) -> Vec<(u8, u16)> {
+        let table = FeatureTable::from_capture(Path::new(path)).unwrap();
+        assert!(table.pending.is_empty(), "{}: unanswered requests", path);
+        table.devices.get(&device_index).map_or_else(BTreeMap::new, |d| d.clone()).into_iter().collect()
+    }
+
+    #[test]
+    fn learns_the_g910_features() {
+        let expected = vec![(0x02, 0x0003), (0x03, 0x4522), (0x04, 0x0005), (0x08, 0x8010), (0x09, 0x8020),
+                            (0x0a, 0x8030), (0x0f, 0x8080), (0x10, 0x8070)];
+        assert_eq!(features("pcap/g910/handshake/handshake.pcap", 0xff), expected);
+        assert_eq!(features("pcap/g910/handshake/handshake2.pcap", 0xff), expected);
+    }
+
+    #[test]
+    fn learns_the_g602_features() {
+        assert_eq!(features("pcap/g602/handshake/handshake.pcap", 0x01), vec![
+            (0x01, 0x0001), (0x02, 0x0003), (0x03, 0x0005), (0x04, 0x00c0), (0x05, 0x1000), (0x06, 0x1d4b),
+            (0x07, 0x1df3), (0x08, 0x1e00), (0x09, 0x1e80), (0x0a, 0x1f03), (0x0b, 0x2100), (0x0c, 0x2200),
+            (0x0d, 0x2201), (0x0e, 0x8080), (0x0f, 0x8060), (0x10, 0x8070), (0x11, 0x1810), (0x12, 0x1830),
+            (0x13, 0x1850), (0x14, 0x1860), (0x15, 0x1890), (0x16, 0x18a0)]);
+        // the receiver answers the HID++ 1.0 ping of the switched off mouse
+        // with an error
+        assert_eq!(features("pcap/g602/handshake/handshake-off.pcap", 0x01), vec![]);
+    }
+
+    #[test]
+    fn errors_drop_their_request() {
+        let mut table = FeatureTable::new();
+        let mut feed = |transfer: Transfer| {
+            let t = transfer.to_transaction(1, 0);
+            table.feed(t.submit().unwrap());
+            table.feed(t.complete().unwrap());
+        };
+        // IRoot.getFeature(PerKeyLighting) failing
+        feed(Transfer::control(0x21, 0x09, 0x0210, 1, vec![0x10, 0xff, 0x00, 0x01, 0x80, 0x80, 0x00]));
+        let mut error = vec![0x11, 0xff, 0xff, 0x00, 0x01, 0x05];
+        error.resize(20, 0);
+        feed(Transfer::interrupt(0x83, error));
+        // must not be taken as its answer
+        feed(Transfer::interrupt(0x83, vec![0x10, 0xff, 0x00, 0x01, 0x05, 0x00, 0x00]));
+        assert!(table.pending.is_empty());
+        assert_eq!(table.feature_id(0xff, 0x05), None);
+    }
+}
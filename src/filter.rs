@@ -0,0 +1,545 @@
+use std::error::Error;
+use std::fmt;
+use std::iter;
+use std::str::{CharIndices, FromStr};
+use transaction::Transaction;
+use usb::{Packet, UrbType, TransferType, Direction};
+
+/// Error while parsing a filter, `pos` is the byte offset into the
+/// expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterError {
+    pub pos: usize,
+    pub msg: String,
+}
+
+impl FilterError {
+    fn new<S: Into<String>>(pos: usize, msg: S) -> FilterError {
+        FilterError { pos: pos, msg: msg.into() }
+    }
+
+    /// Renders the expression with a marker below the error position
+    pub fn highlight(&self, expr: &str) -> String {
+        let column = expr.char_indices().take_while(|&(i, _)| i < self.pos).count();
+        let indent: String = iter::repeat(' ').take(column).collect();
+        format!("{}\n{}\n{}^", self, expr, indent)
+    }
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "filter error at column {}: {}", self.pos + 1, self.msg)
+    }
+}
+
+impl Error for FilterError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+/// Integer of a filter, negative numbers are in `Negative` only so the
+/// derived order is numeric. `Positive` holds e.g. URB ids above `i64::MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Number {
+    Negative(i64),
+    Positive(u64),
+}
+
+impl From<i64> for Number {
+    fn from(n: i64) -> Number {
+        if n < 0 { Number::Negative(n) } else { Number::Positive(n as u64) }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Number::Negative(n) => write!(f, "{}", n),
+            Number::Positive(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(Number),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Not,
+    And,
+    Or,
+    Cmp(CmpOp),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Ident(ref s) => write!(f, "'{}'", s),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::LBracket => write!(f, "'['"),
+            Token::RBracket => write!(f, "']'"),
+            Token::Not => write!(f, "'!'"),
+            Token::And => write!(f, "'&&'"),
+            Token::Or => write!(f, "'||'"),
+            Token::Cmp(op) => write!(f, "'{}'", op.symbol()),
+            Token::End => write!(f, "end of filter"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq, Ne, Lt, Le, Gt, Ge
+}
+
+impl CmpOp {
+    fn symbol(&self) -> &'static str {
+        match *self {
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        }
+    }
+
+    fn apply<T: PartialOrd>(&self, a: T, b: T) -> bool {
+        match *self {
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+            CmpOp::Lt => a < b,
+            CmpOp::Le => a <= b,
+            CmpOp::Gt => a > b,
+            CmpOp::Ge => a >= b,
+        }
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let rest = &expr[start..];
+        let token = match c {
+            ' ' | '\t' | '\n' => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            _ if rest.starts_with("&&") => { chars.next(); Token::And },
+            _ if rest.starts_with("||") => { chars.next(); Token::Or },
+            _ if rest.starts_with("==") => { chars.next(); Token::Cmp(CmpOp::Eq) },
+            _ if rest.starts_with("!=") => { chars.next(); Token::Cmp(CmpOp::Ne) },
+            _ if rest.starts_with("<=") => { chars.next(); Token::Cmp(CmpOp::Le) },
+            _ if rest.starts_with(">=") => { chars.next(); Token::Cmp(CmpOp::Ge) },
+            '<' => Token::Cmp(CmpOp::Lt),
+            '>' => Token::Cmp(CmpOp::Gt),
+            '!' => Token::Not,
+            '=' => return Err(FilterError::new(start, "unexpected '=', use '==' to compare")),
+            '&' | '|' => return Err(FilterError::new(start, format!("unexpected '{}', use '{}{}'", c, c, c))),
+            '0'...'9' | '-' => {
+                let end = skip_while(&mut chars, expr.len(), |c| c.is_alphanumeric());
+                let s = &expr[start..end];
+                let digits = if c == '-' { &s[1..] } else { s };
+                let n = if digits.starts_with("0x") || digits.starts_with("0X") {
+                    u64::from_str_radix(&digits[2..], 16)
+                } else {
+                    u64::from_str(digits)
+                };
+                match n {
+                    Ok(n) if c != '-' || n == 0 => Token::Number(Number::Positive(n)),
+                    Ok(n) if n <= i64::max_value() as u64 => Token::Number(Number::Negative(-(n as i64))),
+                    _ => return Err(FilterError::new(start, format!("invalid number '{}'", s))),
+                }
+            },
+            'a'...'z' | 'A'...'Z' | '_' => {
+                let end = skip_while(&mut chars, expr.len(), |c| c.is_alphanumeric() || c == '_');
+                Token::Ident(expr[start..end].to_lowercase())
+            },
+            _ => return Err(FilterError::new(start, format!("unexpected character '{}'", c))),
+        };
+        tokens.push((start, token));
+    }
+    tokens.push((expr.len(), Token::End));
+    Ok(tokens)
+}
+
+/// Consumes the characters matching `pred` and returns the offset after
+/// them
+fn skip_while<F>(chars: &mut iter::Peekable<CharIndices>, len: usize, pred: F) -> usize
+        where F: Fn(char) -> bool {
+    while let Some(&(i, c)) = chars.peek() {
+        if !pred(c) {
+            return i;
+        }
+        chars.next();
+    }
+    len
+}
+
+/// A packet field which can be used in filters
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Direction,
+    UrbType,
+    TransferType,
+    Endpoint,
+    EndpointAddress,
+    Id,
+    Device,
+    Bus,
+    Status,
+    Length,
+    RequestType,
+    Request,
+    Value,
+    Index,
+    WLength,
+    Sec,
+    Usec,
+    Data(usize),
+}
+
+/// Fields and their names, fields with symbolic values also list those
+const FIELDS: &'static [(&'static str, Field, &'static [&'static str])] = &[
+    ("dir", Field::Direction, &["in", "out"]),
+    ("urb", Field::UrbType, &["submit", "complete", "error"]),
+    ("transfer", Field::TransferType, &["isochronous", "interrupt", "control", "bulk"]),
+    ("ep", Field::Endpoint, &[]),
+    ("epaddr", Field::EndpointAddress, &[]),
+    ("id", Field::Id, &[]),
+    ("device", Field::Device, &[]),
+    ("bus", Field::Bus, &[]),
+    ("status", Field::Status, &[]),
+    ("len", Field::Length, &[]),
+    ("request_type", Field::RequestType, &[]),
+    ("request", Field::Request, &[]),
+    ("value", Field::Value, &[]),
+    ("index", Field::Index, &[]),
+    ("wlength", Field::WLength, &[]),
+    ("sec", Field::Sec, &[]),
+    ("usec", Field::Usec, &[]),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(Number),
+    Symbol(&'static str),
+}
+
+impl Field {
+    /// Value of the field, `None` for bytes beyond the data
+    fn get(&self, packet: &Packet) -> Option<Value> {
+        let n = |n: u64| Some(Value::Number(Number::Positive(n)));
+        match *self {
+            Field::Direction => Some(Value::Symbol(match packet.get_direction() {
+                Direction::In => "in",
+                Direction::Out => "out",
+            })),
+            Field::UrbType => match packet.get_urb_type() {
+                UrbType::Submit => Some(Value::Symbol("submit")),
+                UrbType::Complete => Some(Value::Symbol("complete")),
+                UrbType::Error => Some(Value::Symbol("error")),
+                UrbType::Unknown(_) => None,
+            },
+            Field::TransferType => match packet.get_transfer_type() {
+                TransferType::Isochronous => Some(Value::Symbol("isochronous")),
+                TransferType::Interrupt => Some(Value::Symbol("interrupt")),
+                TransferType::Control => Some(Value::Symbol("control")),
+                TransferType::Bulk => Some(Value::Symbol("bulk")),
+                TransferType::Unknown(_) => None,
+            },
+            Field::Endpoint => n(packet.get_endpoint() as u64),
+            Field::EndpointAddress => n(packet.get_endpoint_direction() as u64),
+            Field::Id => n(packet.get_id()),
+            Field::Device => n(packet.get_device() as u64),
+            Field::Bus => n(packet.get_bus_id() as u64),
+            Field::Status => Some(Value::Number(Number::from(packet.get_status().errno() as i64))),
+            Field::Length => n(packet.get_data().len() as u64),
+            Field::RequestType => n(packet.get_bm_request_type() as u64),
+            Field::Request => n(packet.get_b_request() as u64),
+            Field::Value => n(packet.get_value() as u64),
+            Field::Index => n(packet.get_index() as u64),
+            Field::WLength => n(packet.get_w_length() as u64),
+            Field::Sec => n(packet.get_sec()),
+            Field::Usec => n(packet.get_usec() as u64),
+            Field::Data(i) => packet.get_data().get(i).map(|&b| Value::Number(Number::Positive(b as u64))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    True,
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Cmp(Field, CmpOp, Value),
+}
+
+impl Expr {
+    fn eval(&self, packet: &Packet) -> bool {
+        match *self {
+            Expr::True => true,
+            Expr::Not(ref e) => !e.eval(packet),
+            Expr::And(ref a, ref b) => a.eval(packet) && b.eval(packet),
+            Expr::Or(ref a, ref b) => a.eval(packet) || b.eval(packet),
+            Expr::Cmp(field, op, ref value) => match (field.get(packet), value) {
+                (Some(Value::Number(a)), &Value::Number(b)) => op.apply(a, b),
+                (Some(Value::Symbol(a)), &Value::Symbol(b)) => op.apply(a, b),
+                // e.g. data[4] of a shorter packet
+                _ => false,
+            },
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].1
+    }
+
+    fn next(&mut self) -> (usize, Token) {
+        let t = self.tokens[self.pos].clone();
+        if t.1 != Token::End {
+            self.pos += 1;
+        }
+        t
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), FilterError> {
+        let (pos, t) = self.next();
+        if t == token {
+            Ok(())
+        } else {
+            Err(FilterError::new(pos, format!("expected {}, found {}", token, t)))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, FilterError> {
+        let mut e = try!(self.and());
+        while *self.peek() == Token::Or {
+            self.next();
+            e = Expr::Or(Box::new(e), Box::new(try!(self.and())));
+        }
+        Ok(e)
+    }
+
+    fn and(&mut self) -> Result<Expr, FilterError> {
+        let mut e = try!(self.not());
+        while *self.peek() == Token::And {
+            self.next();
+            e = Expr::And(Box::new(e), Box::new(try!(self.not())));
+        }
+        Ok(e)
+    }
+
+    fn not(&mut self) -> Result<Expr, FilterError> {
+        match *self.peek() {
+            Token::Not => {
+                self.next();
+                Ok(Expr::Not(Box::new(try!(self.not()))))
+            },
+            Token::LParen => {
+                self.next();
+                let e = try!(self.or());
+                try!(self.expect(Token::RParen));
+                Ok(e)
+            },
+            _ => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, FilterError> {
+        let (pos, t) = self.next();
+        let name = match t {
+            Token::Ident(name) => name,
+            t => return Err(FilterError::new(pos, format!("expected a field, found {}", t))),
+        };
+        let (field, symbols) = if name == "data" {
+            try!(self.expect(Token::LBracket));
+            let (pos, t) = self.next();
+            let i = match t {
+                Token::Number(Number::Positive(i)) => i as usize,
+                t => return Err(FilterError::new(pos, format!("expected a byte offset, found {}", t))),
+            };
+            try!(self.expect(Token::RBracket));
+            (Field::Data(i), &[][..])
+        } else {
+            match FIELDS.iter().find(|&&(n, _, _)| n == name) {
+                Some(&(_, field, symbols)) => (field, symbols),
+                None => {
+                    let names: Vec<_> = FIELDS.iter().map(|&(n, _, _)| n).collect();
+                    return Err(FilterError::new(pos, format!("unknown field '{}', expected one of \
+                        data[n], {}", name, names.join(", "))));
+                }
+            }
+        };
+        let (pos, t) = self.next();
+        let op = match t {
+            Token::Cmp(op) => op,
+            t => return Err(FilterError::new(pos, format!("expected a comparison after '{}', found {}",
+                                                           name, t))),
+        };
+        let (pos, t) = self.next();
+        let value = match (t, symbols.is_empty()) {
+            (Token::Number(n), true) => Value::Number(n),
+            (Token::Ident(ref s), false) if symbols.contains(&&s[..]) => {
+                if op != CmpOp::Eq && op != CmpOp::Ne {
+                    return Err(FilterError::new(pos, format!("'{}' can only be compared with \
+                        '==' or '!='", name)));
+                }
+                Value::Symbol(symbols.iter().find(|&&sym| sym == s).unwrap())
+            },
+            (t, true) => return Err(FilterError::new(pos, format!("expected a number to compare \
+                '{}' with, found {}", name, t))),
+            (t, false) => return Err(FilterError::new(pos, format!("expected one of {} to compare \
+                '{}' with, found {}", symbols.join(", "), name, t))),
+        };
+        Ok(Expr::Cmp(field, op, value))
+    }
+}
+
+/// Packet filter like `dir == out && ep == 0 && data[0] == 0x12 && len > 0`.
+///
+/// Comparisons of fields with numbers or, for `dir`, `urb` and `transfer`,
+/// their symbolic values are combined with `&&`, `||`, `!` and parentheses.
+/// Comparisons with bytes beyond a packet's data are false. The empty
+/// filter matches all packets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl Filter {
+    pub fn parse(expr: &str) -> Result<Filter, FilterError> {
+        let mut parser = Parser { tokens: try!(tokenize(expr)), pos: 0 };
+        if *parser.peek() == Token::End {
+            return Ok(Filter::all());
+        }
+        let e = try!(parser.or());
+        let (pos, t) = parser.next();
+        if t != Token::End {
+            return Err(FilterError::new(pos, format!("expected '&&', '||' or end of filter, found {}", t)));
+        }
+        Ok(Filter { expr: e })
+    }
+
+    /// Filter matching all packets
+    pub fn all() -> Filter {
+        Filter { expr: Expr::True }
+    }
+
+    pub fn matches(&self, packet: &Packet) -> bool {
+        self.expr.eval(packet)
+    }
+
+    /// Whether the Submit or the Complete of `t` matches, so predicates on
+    /// e.g. the response data keep the whole transaction
+    pub fn matches_transaction(&self, t: &Transaction) -> bool {
+        t.submit().into_iter().chain(t.complete()).any(|p| self.matches(p))
+    }
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Filter, FilterError> {
+        Filter::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use synth::Transfer;
+    use usb::{Packet, UrbStatus};
+    use super::Filter;
+
+    /// Submit of a HID++ SET_REPORT with the URB id `id`
+    fn set_report(id: u64) -> Packet<'static> {
+        let t = Transfer::control(0x21, 0x09, 0x0211, 1, vec![0x11, 0xff, 0x0f, 0x3b]).to_transaction(id, 0);
+        t.submit().unwrap().clone()
+    }
+
+    fn matches(expr: &str, packet: &Packet) -> bool {
+        Filter::parse(expr).unwrap().matches(packet)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let p = set_report(1);
+        assert!(matches("len == 0 && request == 1 || request == 9", &p));
+        assert!(matches("request == 9 || len == 0 && request == 1", &p));
+        assert!(!matches("(request == 9 || len == 0) && request == 1", &p));
+        assert!(!matches("!request == 9 || len == 0", &p));
+        assert!(matches("!(request == 9 && len == 0)", &p));
+        assert!(matches("!!(dir == out)", &p));
+        assert!(matches("", &p));
+    }
+
+    #[test]
+    fn compares_data_bytes_and_length() {
+        let p = set_report(1);
+        assert!(matches("data[0] == 0x11 && data[3] == 59 && len == 4", &p));
+        assert!(matches("data[1] > 0xfe && len >= 4 && len < 5", &p));
+        // bytes beyond the data compare false either way
+        assert!(!matches("data[4] == 0", &p));
+        assert!(!matches("data[4] != 0", &p));
+        assert!(matches("!(data[4] == 0)", &p));
+        assert!(matches("status == -115 && len > -1", &p));
+        let stalled = Transfer { status: UrbStatus::Pipe, ..Transfer::interrupt(0x83, vec![]) }.to_transaction(1, 0);
+        assert!(matches("status == -32 && urb == complete", stalled.complete().unwrap()));
+    }
+
+    #[test]
+    fn compares_ids_as_unsigned() {
+        let p = set_report(0xffff8800_12345678);
+        assert!(matches("id == 0xffff880012345678", &p));
+        assert!(matches("id > 0x7fffffffffffffff && id > -1", &p));
+        assert!(!matches("id < 0", &p));
+        assert_eq!(Filter::parse("id == 0x10000000000000000").unwrap_err().pos, 6);
+        assert_eq!(Filter::parse("len > -0x8000000000000000").unwrap_err().pos, 6);
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        let pos = |expr: &str| Filter::parse(expr).unwrap_err().pos;
+        assert_eq!(pos("len = 3"), 4);
+        assert_eq!(pos("len > 3 & dir == in"), 8);
+        assert_eq!(pos("lenght > 3"), 0);
+        assert_eq!(pos("len 3"), 4);
+        assert_eq!(pos("dir == sideways"), 7);
+        assert_eq!(pos("dir < in"), 6);
+        assert_eq!(pos("data[-1] == 0"), 5);
+        assert_eq!(pos("data 1 == 0"), 5);
+        assert_eq!(pos("(len > 3"), 8);
+        assert_eq!(pos("len > 3)"), 7);
+        assert_eq!(pos("len > 3 &&"), 10);
+        assert_eq!(pos("len > 0x"), 6);
+        let err = Filter::parse("len > 3)").unwrap_err();
+        assert!(err.highlight("len > 3)").ends_with("len > 3)\n       ^"));
+    }
+
+    #[test]
+    fn non_ascii_is_a_parse_error() {
+        for expr in &["len > 0 && ü", "ü", "len >ü", "0xü", "dirü == in"] {
+            let err = Filter::parse(expr).unwrap_err();
+            assert!(expr.is_char_boundary(err.pos), "{}: {:?}", expr, err);
+            err.highlight(expr);
+        }
+        assert_eq!(Filter::parse("len > 0 && ü").unwrap_err().pos, 11);
+    }
+}
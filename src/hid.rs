@@ -0,0 +1,544 @@
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::time::Duration;
+use libusb::{DeviceHandle, Result as UsbResult};
+
+/// Type of an item, bits 2 and 3 of its prefix
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ItemType {
+    Main, Global, Local, Reserved
+}
+
+impl From<u8> for ItemType {
+    fn from(prefix: u8) -> Self {
+        match (prefix >> 2) & 0x03 {
+            0 => ItemType::Main,
+            1 => ItemType::Global,
+            2 => ItemType::Local,
+            _ => ItemType::Reserved,
+        }
+    }
+}
+
+/// Kind of report a main item adds a field to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReportKind {
+    Input, Output, Feature
+}
+
+/// Reason why a report descriptor could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HidError {
+    /// An item announces more data than the descriptor contains
+    Truncated { offset: usize, needed: usize, available: usize },
+    /// End Collection without a Collection
+    UnbalancedCollection { offset: usize },
+    /// Pop without a Push
+    EmptyStack { offset: usize },
+    /// A Main item makes its report longer than 2^32 bits
+    ReportTooLong { offset: usize },
+}
+
+impl fmt::Display for HidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HidError::Truncated { offset, needed, available } =>
+                write!(f, "item at offset {} needs {} bytes but only {} are available",
+                       offset, needed, available),
+            HidError::UnbalancedCollection { offset } =>
+                write!(f, "End Collection without Collection at offset {}", offset),
+            HidError::EmptyStack { offset } =>
+                write!(f, "Pop without Push at offset {}", offset),
+            HidError::ReportTooLong { offset } =>
+                write!(f, "report exceeds 2^32 bits at offset {}", offset),
+        }
+    }
+}
+
+impl StdError for HidError {
+    fn description(&self) -> &str {
+        match *self {
+            HidError::Truncated { .. } => "report descriptor is truncated",
+            HidError::UnbalancedCollection { .. } => "unbalanced collection",
+            HidError::EmptyStack { .. } => "pop without push",
+            HidError::ReportTooLong { .. } => "report too long",
+        }
+    }
+}
+
+/// A short item of a report descriptor. Long items are kept with their
+/// raw bytes but never interpreted, as no HID version defines any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub offset: usize,
+    pub kind: ItemType,
+    pub tag: u8,
+    /// Prefix and data
+    pub bytes: Vec<u8>,
+    pub long: bool,
+}
+
+impl Item {
+    fn data(&self) -> &[u8] {
+        if self.long { &self.bytes[3..] } else { &self.bytes[1..] }
+    }
+
+    /// Data as unsigned little endian
+    pub fn value(&self) -> u32 {
+        self.data().iter().rev().fold(0, |v, &b| v << 8 | b as u32)
+    }
+
+    /// Data as two's complement of its size
+    pub fn signed(&self) -> i32 {
+        let v = self.value();
+        match self.data().len() {
+            1 => v as u8 as i8 as i32,
+            2 => v as u16 as i16 as i32,
+            _ => v as i32,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        if self.long {
+            return "Long Item";
+        }
+        match (self.kind, self.tag) {
+            (ItemType::Main, 0x08) => "Input",
+            (ItemType::Main, 0x09) => "Output",
+            (ItemType::Main, 0x0b) => "Feature",
+            (ItemType::Main, 0x0a) => "Collection",
+            (ItemType::Main, 0x0c) => "End Collection",
+            (ItemType::Global, 0x00) => "Usage Page",
+            (ItemType::Global, 0x01) => "Logical Minimum",
+            (ItemType::Global, 0x02) => "Logical Maximum",
+            (ItemType::Global, 0x03) => "Physical Minimum",
+            (ItemType::Global, 0x04) => "Physical Maximum",
+            (ItemType::Global, 0x05) => "Unit Exponent",
+            (ItemType::Global, 0x06) => "Unit",
+            (ItemType::Global, 0x07) => "Report Size",
+            (ItemType::Global, 0x08) => "Report ID",
+            (ItemType::Global, 0x09) => "Report Count",
+            (ItemType::Global, 0x0a) => "Push",
+            (ItemType::Global, 0x0b) => "Pop",
+            (ItemType::Local, 0x00) => "Usage",
+            (ItemType::Local, 0x01) => "Usage Minimum",
+            (ItemType::Local, 0x02) => "Usage Maximum",
+            (ItemType::Local, 0x03) => "Designator Index",
+            (ItemType::Local, 0x04) => "Designator Minimum",
+            (ItemType::Local, 0x05) => "Designator Maximum",
+            (ItemType::Local, 0x07) => "String Index",
+            (ItemType::Local, 0x08) => "String Minimum",
+            (ItemType::Local, 0x09) => "String Maximum",
+            (ItemType::Local, 0x0a) => "Delimiter",
+            _ => "Reserved",
+        }
+    }
+
+    /// Local usages of 4 bytes include their usage page
+    fn usage(&self, page: u16) -> u32 {
+        if self.data().len() == 4 { self.value() } else { (page as u32) << 16 | self.value() }
+    }
+}
+
+/// Splits a report descriptor into its items
+pub fn parse_items(bytes: &[u8]) -> Result<Vec<Item>, HidError> {
+    let mut items = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let prefix = bytes[i];
+        let (len, long) = if prefix == 0xfe {
+            // bDataSize and bLongItemTag follow
+            match bytes.get(i + 1) {
+                Some(&size) => (3 + size as usize, true),
+                None => return Err(HidError::Truncated { offset: i, needed: 3, available: bytes.len() - i }),
+            }
+        } else {
+            (1 + [0, 1, 2, 4][(prefix & 0x03) as usize], false)
+        };
+        if i + len > bytes.len() {
+            return Err(HidError::Truncated { offset: i, needed: len, available: bytes.len() - i });
+        }
+        items.push(Item {
+            offset: i,
+            kind: ItemType::from(prefix),
+            tag: prefix >> 4,
+            bytes: bytes[i..i + len].to_vec(),
+            long: long,
+        });
+        i += len;
+    }
+    Ok(items)
+}
+
+/// Name of a usage page
+pub fn usage_page_name(page: u16) -> Option<&'static str> {
+    Some(match page {
+        0x01 => "Generic Desktop",
+        0x02 => "Simulation Controls",
+        0x05 => "Game Controls",
+        0x06 => "Generic Device Controls",
+        0x07 => "Keyboard",
+        0x08 => "LEDs",
+        0x09 => "Button",
+        0x0c => "Consumer",
+        0x0d => "Digitizer",
+        0xff00...0xffff => "Vendor Defined",
+        _ => return None,
+    })
+}
+
+/// Name of a usage, with the usage page in the high 16 bits
+pub fn usage_name(usage: u32) -> String {
+    let (page, id) = ((usage >> 16) as u16, usage as u16);
+    let name = match (page, id) {
+        (0x01, 0x01) => "Pointer",
+        (0x01, 0x02) => "Mouse",
+        (0x01, 0x04) => "Joystick",
+        (0x01, 0x05) => "Game Pad",
+        (0x01, 0x06) => "Keyboard",
+        (0x01, 0x07) => "Keypad",
+        (0x01, 0x30) => "X",
+        (0x01, 0x31) => "Y",
+        (0x01, 0x32) => "Z",
+        (0x01, 0x38) => "Wheel",
+        (0x01, 0x80) => "System Control",
+        (0x01, 0x81) => "System Power Down",
+        (0x01, 0x82) => "System Sleep",
+        (0x01, 0x83) => "System Wake Up",
+        (0x07, 0xe0) => "Left Control",
+        (0x07, 0xe1) => "Left Shift",
+        (0x07, 0xe2) => "Left Alt",
+        (0x07, 0xe3) => "Left GUI",
+        (0x07, 0xe4) => "Right Control",
+        (0x07, 0xe5) => "Right Shift",
+        (0x07, 0xe6) => "Right Alt",
+        (0x07, 0xe7) => "Right GUI",
+        (0x08, 0x01) => "Num Lock",
+        (0x08, 0x02) => "Caps Lock",
+        (0x08, 0x03) => "Scroll Lock",
+        (0x08, 0x04) => "Compose",
+        (0x08, 0x05) => "Kana",
+        (0x09, _) => return format!("Button {}", id),
+        (0x0c, 0x01) => "Consumer Control",
+        (0x0c, 0xb5) => "Scan Next Track",
+        (0x0c, 0xb6) => "Scan Previous Track",
+        (0x0c, 0xb7) => "Stop",
+        (0x0c, 0xcd) => "Play/Pause",
+        (0x0c, 0xe2) => "Mute",
+        (0x0c, 0xe9) => "Volume Increment",
+        (0x0c, 0xea) => "Volume Decrement",
+        (0x0c, 0x238) => "AC Pan",
+        _ => return format!("0x{:02x}", id),
+    };
+    name.to_string()
+}
+
+fn collection_name(kind: u32) -> String {
+    match kind {
+        0x00 => "Physical".to_string(),
+        0x01 => "Application".to_string(),
+        0x02 => "Logical".to_string(),
+        0x03 => "Report".to_string(),
+        0x04 => "Named Array".to_string(),
+        0x05 => "Usage Switch".to_string(),
+        0x06 => "Usage Modifier".to_string(),
+        k => format!("0x{:02x}", k),
+    }
+}
+
+/// Flags of Input, Output and Feature items like `Data,Var,Abs`
+fn flags_to_string(flags: u32) -> String {
+    let mut names = vec![
+        if flags & 0x01 == 0 { "Data" } else { "Cnst" },
+        if flags & 0x02 == 0 { "Arr" } else { "Var" },
+        if flags & 0x04 == 0 { "Abs" } else { "Rel" },
+    ];
+    let optional = [(0x08, "Wrap"), (0x10, "NonLin"), (0x20, "NoPref"), (0x40, "Null"), (0x80, "Vol"), (0x100, "Buff")];
+    names.extend(optional.iter().filter(|&&(bit, _)| flags & bit != 0).map(|&(_, name)| name));
+    names.join(",")
+}
+
+/// Global state, saved by Push
+#[derive(Debug, Clone, Default)]
+struct Global {
+    usage_page: u16,
+    logical_min: i32,
+    logical_max: i32,
+    /// Logical Maximum read as unsigned, used if the minimum isn't negative
+    logical_max_unsigned: u32,
+    report_size: u32,
+    report_id: u8,
+    report_count: u32,
+}
+
+/// Local state, reset after each main item
+#[derive(Debug, Clone, Default)]
+struct Local {
+    usages: Vec<u32>,
+    usage_min: Option<u32>,
+    usage_max: Option<u32>,
+}
+
+/// A field of a report defined by an Input, Output or Feature item
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportField {
+    pub kind: ReportKind,
+    /// 0 if the descriptor doesn't use report ids
+    pub report_id: u8,
+    /// Offset in bits, after the report id byte
+    pub offset: u32,
+    pub size: u32,
+    pub count: u32,
+    pub flags: u32,
+    pub usage_page: u16,
+    pub usages: Vec<u32>,
+    pub usage_range: Option<(u32, u32)>,
+    pub logical_min: i32,
+    pub logical_max: i32,
+}
+
+impl ReportField {
+    pub fn is_constant(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+}
+
+impl fmt::Display for ReportField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let end = self.offset.saturating_add(self.size.saturating_mul(self.count));
+        try!(write!(f, "bits {}..{}: {} x {} bit ({})", self.offset, end.saturating_sub(1),
+                    self.count, self.size, flags_to_string(self.flags)));
+        if self.is_constant() {
+            return write!(f, " padding");
+        }
+        try!(write!(f, ", {}", usage_page_name(self.usage_page).map(|n| n.to_string())
+                    .unwrap_or_else(|| format!("page 0x{:04x}", self.usage_page))));
+        if let Some((min, max)) = self.usage_range {
+            try!(write!(f, " {}..{}", usage_name(min), usage_name(max)));
+        }
+        if !self.usages.is_empty() {
+            let usages: Vec<_> = self.usages.iter().map(|&u| usage_name(u)).collect();
+            try!(write!(f, " {}", usages.join(", ")));
+        }
+        write!(f, ", logical {}..{}", self.logical_min, self.logical_max)
+    }
+}
+
+/// Decoded HID report descriptor
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportDescriptor {
+    pub items: Vec<Item>,
+    pub fields: Vec<ReportField>,
+}
+
+impl ReportDescriptor {
+    pub fn parse(bytes: &[u8]) -> Result<ReportDescriptor, HidError> {
+        let items = try!(parse_items(bytes));
+        let mut global = Global::default();
+        let mut stack = Vec::new();
+        let mut local = Local::default();
+        let mut depth = 0;
+        let mut offsets: BTreeMap<(ReportKind, u8), u32> = BTreeMap::new();
+        let mut fields = Vec::new();
+        for item in items.iter().filter(|i| !i.long) {
+            match (item.kind, item.tag) {
+                (ItemType::Main, 0x08) | (ItemType::Main, 0x09) | (ItemType::Main, 0x0b) => {
+                    let kind = match item.tag {
+                        0x08 => ReportKind::Input,
+                        0x09 => ReportKind::Output,
+                        _ => ReportKind::Feature,
+                    };
+                    let offset = offsets.entry((kind, global.report_id)).or_insert(0);
+                    let end = match global.report_size.checked_mul(global.report_count)
+                            .and_then(|bits| offset.checked_add(bits)) {
+                        Some(end) => end,
+                        None => return Err(HidError::ReportTooLong { offset: item.offset }),
+                    };
+                    // e.g. `25 ff` is 255 and not -1 for a minimum of 0
+                    let logical_max = if global.logical_min >= 0 {
+                        global.logical_max_unsigned as i32
+                    } else {
+                        global.logical_max
+                    };
+                    fields.push(ReportField {
+                        kind: kind,
+                        report_id: global.report_id,
+                        offset: *offset,
+                        size: global.report_size,
+                        count: global.report_count,
+                        flags: item.value(),
+                        usage_page: global.usage_page,
+                        usages: local.usages.clone(),
+                        usage_range: match (local.usage_min, local.usage_max) {
+                            (Some(min), Some(max)) => Some((min, max)),
+                            _ => None,
+                        },
+                        logical_min: global.logical_min,
+                        logical_max: logical_max,
+                    });
+                    *offset = end;
+                    local = Local::default();
+                },
+                (ItemType::Main, 0x0a) => {
+                    depth += 1;
+                    local = Local::default();
+                },
+                (ItemType::Main, 0x0c) => {
+                    if depth == 0 {
+                        return Err(HidError::UnbalancedCollection { offset: item.offset });
+                    }
+                    depth -= 1;
+                    local = Local::default();
+                },
+                (ItemType::Global, 0x00) => global.usage_page = item.value() as u16,
+                (ItemType::Global, 0x01) => global.logical_min = item.signed(),
+                (ItemType::Global, 0x02) => {
+                    global.logical_max = item.signed();
+                    global.logical_max_unsigned = item.value();
+                },
+                (ItemType::Global, 0x07) => global.report_size = item.value(),
+                (ItemType::Global, 0x08) => global.report_id = item.value() as u8,
+                (ItemType::Global, 0x09) => global.report_count = item.value(),
+                (ItemType::Global, 0x0a) => stack.push(global.clone()),
+                (ItemType::Global, 0x0b) => global = match stack.pop() {
+                    Some(g) => g,
+                    None => return Err(HidError::EmptyStack { offset: item.offset }),
+                },
+                (ItemType::Local, 0x00) => local.usages.push(item.usage(global.usage_page)),
+                (ItemType::Local, 0x01) => local.usage_min = Some(item.usage(global.usage_page)),
+                (ItemType::Local, 0x02) => local.usage_max = Some(item.usage(global.usage_page)),
+                _ => {},
+            }
+        }
+        Ok(ReportDescriptor { items: items, fields: fields })
+    }
+
+    /// Length of each report in bits, without the report id
+    pub fn report_lengths(&self) -> BTreeMap<(ReportKind, u8), u32> {
+        let mut lengths = BTreeMap::new();
+        for field in &self.fields {
+            let length = lengths.entry((field.kind, field.report_id)).or_insert(0u32);
+            *length = length.saturating_add(field.size.saturating_mul(field.count));
+        }
+        lengths
+    }
+
+    /// Prints the items with their raw bytes like `hid-decode`, followed
+    /// by the fields of each report
+    pub fn print(&self) {
+        let mut usage_page = 0;
+        let mut depth = 0usize;
+        for item in &self.items {
+            if !item.long && item.kind == ItemType::Main && item.tag == 0x0c {
+                depth = depth.saturating_sub(1);
+            }
+            let bytes: Vec<_> = item.bytes.iter().map(|b| format!("0x{:02x}, ", b)).collect();
+            let indent: String = ::std::iter::repeat(' ').take(depth * 2).collect();
+            let text = format!("{}{}", indent, self.describe(item, &mut usage_page));
+            println!("{:<30} // {:<40} {}", bytes.concat(), text, item.offset);
+            if !item.long && item.kind == ItemType::Main && item.tag == 0x0a {
+                depth += 1;
+            }
+        }
+        let lengths = self.report_lengths();
+        for (&(kind, id), &bits) in &lengths {
+            println!("");
+            if id == 0 {
+                println!("{:?} report ({} bits):", kind, bits);
+            } else {
+                println!("{:?} report 0x{:02x} ({} bits):", kind, id, bits);
+            }
+            for field in self.fields.iter().filter(|f| f.kind == kind && f.report_id == id) {
+                println!("    {}", field);
+            }
+        }
+    }
+
+    fn describe(&self, item: &Item, usage_page: &mut u16) -> String {
+        if item.long {
+            return item.name().to_string();
+        }
+        let arg = match (item.kind, item.tag) {
+            (ItemType::Main, 0x08) | (ItemType::Main, 0x09) | (ItemType::Main, 0x0b) =>
+                flags_to_string(item.value()),
+            (ItemType::Main, 0x0a) => collection_name(item.value()),
+            (ItemType::Main, 0x0c) | (ItemType::Global, 0x0a) | (ItemType::Global, 0x0b) =>
+                return item.name().to_string(),
+            (ItemType::Global, 0x00) => {
+                *usage_page = item.value() as u16;
+                usage_page_name(*usage_page).map(|n| n.to_string())
+                    .unwrap_or_else(|| format!("0x{:04x}", *usage_page))
+            },
+            (ItemType::Global, 0x01) | (ItemType::Global, 0x02) | (ItemType::Global, 0x03)
+                | (ItemType::Global, 0x04) | (ItemType::Global, 0x05) => item.signed().to_string(),
+            (ItemType::Local, 0x00) | (ItemType::Local, 0x01) | (ItemType::Local, 0x02) =>
+                usage_name(item.usage(*usage_page)),
+            _ => item.value().to_string(),
+        };
+        format!("{} ({})", item.name(), arg)
+    }
+}
+
+/// Reads the report descriptor of an interface with GET_DESCRIPTOR
+pub fn read_report_descriptor(handle: &DeviceHandle, interface: u8, timeout: Duration) -> UsbResult<Vec<u8>> {
+    let mut buf = vec![0u8; 4096];
+    let len = try!(handle.read_control(0x81, 0x06, 0x2200, interface as u16, &mut buf, timeout));
+    buf.truncate(len);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use descriptor;
+    use filter::Filter;
+    use hidpp;
+    use super::{ReportDescriptor, ReportKind, HidError};
+
+    /// Report descriptors of each interface recorded in the G910's handshake
+    fn g910_descriptors() -> Vec<(u8, ReportDescriptor)> {
+        let trees = descriptor::from_capture(Path::new("pcap/g910/handshake/handshake.pcap"), &Filter::all()).unwrap();
+        let mut descriptors = Vec::new();
+        for c in trees.iter().flat_map(|d| d.configs.iter()) {
+            for alt in c.interfaces.iter().flat_map(|i| i.alt_settings.iter()) {
+                if let Some(report) = alt.hid.as_ref().and_then(|h| h.report_descriptor.as_ref()) {
+                    descriptors.push((alt.number, ReportDescriptor::parse(report).unwrap()));
+                }
+            }
+        }
+        descriptors
+    }
+
+    #[test]
+    fn parses_the_g910_report_descriptors() {
+        let descriptors = g910_descriptors();
+        assert_eq!(descriptors.iter().map(|&(i, _)| i).collect::<Vec<_>>(), vec![0, 1]);
+        // boot keyboard: modifiers, reserved byte and 6 keys in, LEDs out
+        let keyboard: Vec<_> = descriptors[0].1.report_lengths().into_iter().collect();
+        assert_eq!(keyboard, vec![((ReportKind::Input, 0), 64), ((ReportKind::Output, 0), 8)]);
+        assert!(descriptors[0].1.fields.iter().all(|f| f.report_id == 0));
+        let vendor: Vec<_> = descriptors[1].1.report_lengths().into_iter().collect();
+        assert_eq!(vendor, vec![
+            ((ReportKind::Input, 1), 160),
+            ((ReportKind::Input, 2), 8),
+            ((ReportKind::Input, hidpp::REPORT_LONG), 152),
+            ((ReportKind::Input, hidpp::REPORT_VERY_LONG), 504),
+            ((ReportKind::Output, hidpp::REPORT_LONG), 152),
+            ((ReportKind::Output, hidpp::REPORT_VERY_LONG), 504),
+        ]);
+        // the HID++ reports have the length hidpp expects, with their id
+        let lengths = descriptors[1].1.report_lengths();
+        for &id in &[hidpp::REPORT_LONG, hidpp::REPORT_VERY_LONG] {
+            let kind = hidpp::ReportKind::from_report_id(id).unwrap();
+            assert_eq!(lengths[&(ReportKind::Output, id)] as usize / 8 + 1, kind.len());
+        }
+    }
+
+    #[test]
+    fn overlong_report_is_an_error() {
+        // Report Size 0xffffffff, Report Count 2, Input
+        let bytes = [0x77, 0xff, 0xff, 0xff, 0xff, 0x97, 0x02, 0x00, 0x00, 0x00, 0x81, 0x02];
+        assert_eq!(ReportDescriptor::parse(&bytes), Err(HidError::ReportTooLong { offset: 10 }));
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+
+pub const REPORT_SHORT: u8 = 0x10;
+pub const REPORT_LONG: u8 = 0x11;
+pub const REPORT_VERY_LONG: u8 = 0x12;
+
+/// Feature index used by HID++ 2.0 error responses
+const ERROR_FEATURE_INDEX: u8 = 0xff;
+/// Sub id used by HID++ 1.0 error responses
+const ERROR_SUB_ID: u8 = 0x8f;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportKind {
+    Short, Long, VeryLong
+}
+
+impl ReportKind {
+    pub fn from_report_id(id: u8) -> Option<ReportKind> {
+        match id {
+            REPORT_SHORT => Some(ReportKind::Short),
+            REPORT_LONG => Some(ReportKind::Long),
+            REPORT_VERY_LONG => Some(ReportKind::VeryLong),
+            _ => None,
+        }
+    }
+
+    /// Length of the whole report including the report id
+    pub fn len(&self) -> usize {
+        match *self {
+            ReportKind::Short => 7,
+            ReportKind::Long => 20,
+            ReportKind::VeryLong => 64,
+        }
+    }
+}
+
+/// HID++ 2.0 error codes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    NoError,
+    Unknown,
+    InvalidArgument,
+    OutOfRange,
+    HwError,
+    LogitechInternal,
+    InvalidFeatureIndex,
+    InvalidFunctionId,
+    Busy,
+    Unsupported,
+    Other(u8),
+}
+
+impl From<u8> for ErrorCode {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0 => ErrorCode::NoError,
+            1 => ErrorCode::Unknown,
+            2 => ErrorCode::InvalidArgument,
+            3 => ErrorCode::OutOfRange,
+            4 => ErrorCode::HwError,
+            5 => ErrorCode::LogitechInternal,
+            6 => ErrorCode::InvalidFeatureIndex,
+            7 => ErrorCode::InvalidFunctionId,
+            8 => ErrorCode::Busy,
+            9 => ErrorCode::Unsupported,
+            b => ErrorCode::Other(b),
+        }
+    }
+}
+
+/// Error response, telling which request failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// HID++ 2.0 error for a call of `function` on `feature_index`
+    Feature { feature_index: u8, function: u8, sw_id: u8, code: ErrorCode },
+    /// HID++ 1.0 error for an access of `address` with `sub_id`
+    Register { sub_id: u8, address: u8, code: u8 },
+}
+
+/// A HID++ report as sent in SET_REPORT requests or received on the
+/// vendor interrupt endpoint.
+///
+/// Byte 2 and 3 are interpreted the HID++ 2.0 way as feature index and
+/// function id / software id. For HID++ 1.0 they are the sub id and the
+/// register address, which `register()` returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Message<'a> {
+    pub kind: ReportKind,
+    pub device_index: u8,
+    pub feature_index: u8,
+    pub function: u8,
+    pub sw_id: u8,
+    pub params: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    /// Parses `data` if it starts with a HID++ report id and has the
+    /// report's length.
+    pub fn parse(data: &'a [u8]) -> Option<Message<'a>> {
+        let kind = match data.first().and_then(|&id| ReportKind::from_report_id(id)) {
+            Some(kind) => kind,
+            None => return None,
+        };
+        if data.len() != kind.len() {
+            return None;
+        }
+        Some(Message {
+            kind: kind,
+            device_index: data[1],
+            feature_index: data[2],
+            function: data[3] >> 4,
+            sw_id: data[3] & 0x0f,
+            params: &data[4..],
+        })
+    }
+
+    /// HID++ 1.0 view of this message as sub id and register address
+    pub fn register(&self) -> (u8, u8) {
+        (self.feature_index, self.function << 4 | self.sw_id)
+    }
+
+    /// Error response, whose byte 3 is the feature index or sub id of the
+    /// failed request
+    pub fn error(&self) -> Option<Error> {
+        let failed = self.function << 4 | self.sw_id;
+        match self.feature_index {
+            ERROR_FEATURE_INDEX => Some(Error::Feature {
+                feature_index: failed,
+                function: self.params[0] >> 4,
+                sw_id: self.params[0] & 0x0f,
+                code: ErrorCode::from(self.params[1]),
+            }),
+            ERROR_SUB_ID => Some(Error::Register {
+                sub_id: failed,
+                address: self.params[0],
+                code: self.params[1],
+            }),
+            _ => None,
+        }
+    }
+
+    /// Parameters without trailing zero padding
+    pub fn trimmed_params(&self) -> &'a [u8] {
+        let len = self.params.iter().rposition(|&b| b != 0).map(|i| i + 1).unwrap_or(0);
+        &self.params[..len]
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+impl<'a> fmt::Display for Message<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            ReportKind::Short => "short",
+            ReportKind::Long => "long",
+            ReportKind::VeryLong => "very long",
+        };
+        try!(write!(f, "{} dev=0x{:02x} ", kind, self.device_index));
+        match self.error() {
+            Some(Error::Feature { feature_index, function, sw_id, code }) =>
+                write!(f, "ERROR feature=0x{:02x} fn={} sw=0x{:x}: {:?}",
+                       feature_index, function, sw_id, code),
+            Some(Error::Register { sub_id, address, code }) =>
+                write!(f, "ERROR sub_id=0x{:02x} address=0x{:02x}: 0x{:02x}", sub_id, address, code),
+            None => write!(f, "feature=0x{:02x} fn={} sw=0x{:x} [{}]", self.feature_index,
+                           self.function, self.sw_id, to_hex(self.trimmed_params())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use capture::Capture;
+    use super::{Message, ReportKind, Error, ErrorCode};
+
+    #[test]
+    fn parses_reports_of_their_length() {
+        let mut data = vec![0x10, 0xff, 0x02, 0x1a, 0x01, 0x00, 0x00];
+        let msg = Message::parse(&data).unwrap();
+        assert_eq!((msg.kind, msg.device_index, msg.feature_index), (ReportKind::Short, 0xff, 0x02));
+        assert_eq!((msg.function, msg.sw_id, msg.params), (1, 0xa, &[1, 0, 0][..]));
+        assert_eq!(msg.trimmed_params(), &[1]);
+        assert_eq!(msg.to_string(), "short dev=0xff feature=0x02 fn=1 sw=0xa [01]");
+        // a long report id with the length of a short report
+        data[0] = 0x11;
+        assert!(Message::parse(&data).is_none());
+        data.resize(20, 0);
+        assert_eq!(Message::parse(&data).unwrap().kind, ReportKind::Long);
+        data[0] = 0x12;
+        assert!(Message::parse(&data).is_none());
+        data.resize(64, 0);
+        assert_eq!(Message::parse(&data).unwrap().kind, ReportKind::VeryLong);
+        data[0] = 0x01;
+        assert!(Message::parse(&data).is_none());
+        assert!(Message::parse(&[]).is_none());
+    }
+
+    #[test]
+    fn parses_errors() {
+        let feature = [0x11, 0xff, 0xff, 0x03, 0x1a, 0x05, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(Message::parse(&feature).unwrap().error(), Some(Error::Feature {
+            feature_index: 0x03, function: 1, sw_id: 0xa, code: ErrorCode::LogitechInternal,
+        }));
+        // the G602 receiver rejecting a HID++ 1.0 ping of device 1
+        let register = [0x10, 0x01, 0x8f, 0x01, 0x02, 0x09, 0x00];
+        let msg = Message::parse(&register).unwrap();
+        assert_eq!(msg.error(), Some(Error::Register { sub_id: 0x01, address: 0x02, code: 0x09 }));
+        assert_eq!(msg.to_string(), "short dev=0x01 ERROR sub_id=0x01 address=0x02: 0x09");
+        assert_eq!(Message::parse(&feature[..7]), None);
+    }
+
+    /// Counts the short, long and very long reports and the errors of a
+    /// capture
+    fn count(path: &str) -> ([usize; 3], usize) {
+        let (mut kinds, mut errors) = ([0; 3], 0);
+        for packet in Capture::from_file(path).unwrap().read_all().unwrap() {
+            if let Some(msg) = Message::parse(packet.get_data()) {
+                kinds[msg.kind as usize] += 1;
+                errors += msg.error().is_some() as usize;
+            }
+        }
+        (kinds, errors)
+    }
+
+    #[test]
+    fn parses_captured_reports() {
+        assert_eq!(count("pcap/g910/handshake/handshake.pcap"), ([0, 482, 51], 0));
+        assert_eq!(count("pcap/g910/color/e-red.pcap"), ([0, 19, 9], 0));
+        assert_eq!(count("pcap/g602/handshake/handshake-off.pcap"), ([9, 1, 0], 1));
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use capture::CaptureError;
+use diff::{self, Edit};
+use filter::Filter;
+use pcapng::PcapngError;
+use transaction::{self, Transaction};
+
+/// A capture of an action together with what was varied, e.g.
+/// `key=space, color=red`
+pub struct Sample {
+    pub labels: BTreeMap<String, String>,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Sample {
+    pub fn new(labels: BTreeMap<String, String>, transactions: Vec<Transaction>) -> Sample {
+        Sample {
+            labels: labels,
+            transactions: transactions,
+        }
+    }
+
+    /// Reads the transactions matching `filter` of a capture named
+    /// `<key>-<color>[n].pcap` like the ones in `pcap/g910/color`
+    pub fn from_color_capture(path: &Path, filter: &Filter) -> Result<Sample, CaptureError> {
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        let mut split = stem.splitn(2, '-');
+        let key = split.next().unwrap_or("");
+        let color = split.next().unwrap_or("").trim_right_matches(|c: char| c.is_digit(10));
+        let mut labels = BTreeMap::new();
+        labels.insert("key".to_string(), key.to_string());
+        labels.insert("color".to_string(), color.to_string());
+        Ok(Sample::new(labels, try!(transaction::from_capture(path, filter))))
+    }
+
+    /// Reads all `.pcap` color captures of `dir`, ordered by name
+    pub fn from_color_dir(dir: &Path, filter: &Filter) -> Result<Vec<Sample>, CaptureError> {
+        let mut paths = Vec::new();
+        for entry in try!(fs::read_dir(dir).map_err(PcapngError::Io)) {
+            let path = try!(entry.map_err(PcapngError::Io)).path();
+            if path.extension().map_or(false, |e| e == "pcap") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        let mut samples = Vec::new();
+        for p in &paths {
+            samples.push(try!(Sample::from_color_capture(p, filter)));
+        }
+        Ok(samples)
+    }
+}
+
+/// RGB value of a color name used in the capture names
+fn rgb(color: &str) -> Option<[u8; 3]> {
+    Some(match color {
+        "black" => [0x00, 0x00, 0x00],
+        "white" => [0xff, 0xff, 0xff],
+        "red" => [0xff, 0x00, 0x00],
+        "green" => [0x00, 0xff, 0x00],
+        "blue" => [0x00, 0x00, 0xff],
+        "yellow" => [0xff, 0xff, 0x00],
+        "cyan" => [0x00, 0xff, 0xff],
+        "magenta" => [0xff, 0x00, 0xff],
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldKind {
+    /// Red, green and blue component of the `color` label
+    Rgb,
+    /// The color of the samples with `label` only, e.g. of the key which
+    /// was changed. `id` is the byte before, usually the key id.
+    LabelRgb { name: String, label: String, id: Option<u8> },
+    /// Value determined by a label, with the value of each label
+    Label { name: String, values: BTreeMap<String, u8> },
+    /// Varies without relation to any label
+    Unknown,
+}
+
+/// A varying part of the request payload of a transaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    /// Index of the transaction in the first sample
+    pub transaction: usize,
+    pub offset: usize,
+    pub len: usize,
+    pub kind: FieldKind,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "transaction {} ", self.transaction + 1));
+        if self.len == 1 {
+            try!(write!(f, "byte 0x{:02x}", self.offset));
+        } else {
+            try!(write!(f, "bytes 0x{:02x}..0x{:02x}", self.offset, self.offset + self.len - 1));
+        }
+        match self.kind {
+            FieldKind::Rgb => write!(f, " are RGB"),
+            FieldKind::LabelRgb { ref name, ref label, id } => {
+                try!(write!(f, " are RGB of {}={}", name, label));
+                match id {
+                    Some(id) => write!(f, " with id 0x{:02x} at 0x{:02x}", id, self.offset - 1),
+                    None => Ok(()),
+                }
+            },
+            FieldKind::Label { ref name, ref values } => {
+                let values: Vec<_> = values.iter().map(|(l, v)| format!("{}=0x{:02x}", l, v)).collect();
+                write!(f, " is the {} ({})", name, values.join(", "))
+            },
+            FieldKind::Unknown => write!(f, " vary"),
+        }
+    }
+}
+
+/// Request payloads of the transactions of `sample` aligned to the
+/// transactions of `reference`
+fn align<'a>(reference: &'a [Transaction], sample: &'a [Transaction]) -> Vec<Option<&'a [u8]>> {
+    let mut aligned = vec![None; reference.len()];
+    let mut i = 0;
+    for edit in diff::diff(reference, sample) {
+        match edit {
+            Edit::Same(_, t) | Edit::Modified(_, t) => {
+                aligned[i] = Some(t.request_data());
+                i += 1;
+            },
+            Edit::Deleted(_) => i += 1,
+            Edit::Inserted(_) => {},
+        }
+    }
+    aligned
+}
+
+/// Returns the value each label of `name` maps to if the values are
+/// determined by that label and differ for at least two labels
+fn label_function(samples: &[(&Sample, u8)], name: &str) -> Option<BTreeMap<String, u8>> {
+    let mut values = BTreeMap::new();
+    for &(sample, value) in samples {
+        let label = match sample.labels.get(name) {
+            Some(label) => label.clone(),
+            None => return None,
+        };
+        if *values.entry(label).or_insert(value) != value {
+            return None;
+        }
+    }
+    let first = values.values().next().cloned();
+    if values.values().all(|&v| Some(v) == first) {
+        return None;
+    }
+    Some(values)
+}
+
+/// Whether `bytes` look like the sample's color. Only tells apart on and off
+/// components, as the vendor software's colors aren't exact (its yellow
+/// is `ff dc 00`).
+fn matches_color(sample: &Sample, bytes: &[u8]) -> Option<bool> {
+    sample.labels.get("color").and_then(|c| rgb(c)).map(|rgb| {
+        rgb.iter().zip(bytes).all(|(&c, &b)| if c == 0 { b == 0 } else { b >= 0xc0 })
+    })
+}
+
+/// Whether the bytes at `offset` are the color of all samples
+fn is_rgb(payloads: &[(&Sample, &[u8])], offset: usize) -> bool {
+    let mut colors = BTreeSet::new();
+    let all = payloads.iter().all(|&(s, p)| {
+        let bytes = &p[offset..offset + 3];
+        colors.insert(bytes);
+        matches_color(s, bytes) != Some(false)
+    });
+    // a single color can't tell the components apart
+    all && colors.len() > 1
+}
+
+/// Returns the labels of `name` whose samples have their color at `offset`
+/// when other labels' samples haven't
+fn rgb_labels(payloads: &[(&Sample, &[u8])], offset: usize, name: &str) -> Vec<String> {
+    let mut labels: BTreeMap<&str, bool> = BTreeMap::new();
+    for &(s, p) in payloads {
+        let label = match s.labels.get(name) {
+            Some(label) => label,
+            None => return Vec::new(),
+        };
+        let matches = matches_color(s, &p[offset..offset + 3]) == Some(true);
+        *labels.entry(label).or_insert(matches) &= matches;
+    }
+    if labels.values().all(|&m| m) {
+        return Vec::new();
+    }
+    labels.iter().filter(|&(_, &m)| m).map(|(label, _)| label.to_string()).collect()
+}
+
+/// Most common value of each label
+fn modal_labels(samples: &[Sample]) -> BTreeMap<String, String> {
+    let mut counts: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+    for sample in samples {
+        for (name, label) in &sample.labels {
+            *counts.entry((name, label)).or_insert(0) += 1;
+        }
+    }
+    let mut modal: BTreeMap<String, (String, usize)> = BTreeMap::new();
+    for (&(name, label), &count) in &counts {
+        let entry = modal.entry(name.to_string()).or_insert((label.to_string(), count));
+        if count > entry.1 {
+            *entry = (label.to_string(), count);
+        }
+    }
+    modal.into_iter().map(|(name, (label, _))| (name, label)).collect()
+}
+
+/// Finds the request payload bytes varying between the samples and relates
+/// them to the samples' labels.
+///
+/// Each label is analysed on the samples which have the most common value
+/// for all other labels, so captures should vary one label at a time.
+/// Transactions are aligned to a sample with the most common labels. Bytes
+/// equal to the color's red, green and blue components in a row are
+/// reported as RGB.
+pub fn infer(samples: &[Sample]) -> Vec<Field> {
+    let modal = modal_labels(samples);
+    let reference = match samples.iter().find(|s| s.labels == modal).or(samples.first()) {
+        Some(s) => &s.transactions,
+        None => return Vec::new(),
+    };
+    let aligned: Vec<_> = samples.iter().map(|s| align(reference, &s.transactions)).collect();
+    let mut fields = Vec::new();
+    for name in modal.keys() {
+        let subset: Vec<_> = samples.iter().zip(aligned.iter())
+            .filter(|&(s, _)| modal.iter().all(|(n, l)| n == name || s.labels.get(n) == Some(l)))
+            .collect();
+        for t in 0..reference.len() {
+            let payloads: Vec<_> = subset.iter().filter_map(|&(s, a)| a[t].map(|p| (s, p))).collect();
+            let len = payloads.iter().map(|&(_, p)| p.len()).min().unwrap_or(0);
+            let column = |offset: usize| payloads.iter().map(|&(s, p)| (s, p[offset])).collect::<Vec<_>>();
+            let mut offset = 0;
+            while offset < len {
+                let values = column(offset);
+                if values.iter().all(|&(_, v)| v == values[0].1) {
+                    offset += 1;
+                    continue;
+                }
+                if offset + 3 <= len {
+                    if name == "color" && is_rgb(&payloads, offset) {
+                        fields.push(Field { transaction: t, offset: offset, len: 3, kind: FieldKind::Rgb });
+                        offset += 3;
+                        continue;
+                    }
+                    let labels = rgb_labels(&payloads, offset, name);
+                    for label in &labels {
+                        let id = payloads.iter()
+                            .find(|&&(s, _)| s.labels.get(name) == Some(label))
+                            .and_then(|&(_, p)| if offset > 0 { Some(p[offset - 1]) } else { None });
+                        let kind = FieldKind::LabelRgb { name: name.clone(), label: label.clone(), id: id };
+                        fields.push(Field { transaction: t, offset: offset, len: 3, kind: kind });
+                    }
+                    if !labels.is_empty() {
+                        offset += 3;
+                        continue;
+                    }
+                }
+                let kind = match label_function(&values, name) {
+                    Some(values) => FieldKind::Label { name: name.clone(), values: values },
+                    None => FieldKind::Unknown,
+                };
+                fields.push(Field { transaction: t, offset: offset, len: 1, kind: kind });
+                offset += 1;
+            }
+        }
+    }
+    fields.sort_by_key(|f| (f.transaction, f.offset));
+    fields
+}
+
+/// Assigns each label of `name` the id of its `LabelRgb` fields.
+///
+/// Captures may build on each other (`w-red` still has `q` red), so a
+/// field can belong to several labels. Labels with a single unassigned id
+/// are assigned first, until no more ids can be told apart.
+pub fn label_ids(fields: &[Field], name: &str) -> BTreeMap<String, u8> {
+    let mut candidates: BTreeMap<&str, Vec<u8>> = BTreeMap::new();
+    for field in fields {
+        if let FieldKind::LabelRgb { name: ref n, ref label, id: Some(id) } = field.kind {
+            if n == name {
+                let ids = candidates.entry(label).or_insert_with(Vec::new);
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+    }
+    let mut assigned = BTreeMap::new();
+    loop {
+        let next = candidates.iter()
+            .map(|(&label, ids)| (label, ids.iter().filter(|id| !assigned.values().any(|a| a == *id))
+                                  .cloned().collect::<Vec<_>>()))
+            .find(|&(label, ref ids)| ids.len() == 1 && !assigned.contains_key(label));
+        match next {
+            Some((label, ids)) => { assigned.insert(label.to_string(), ids[0]); },
+            None => return assigned,
+        }
+    }
+}
+
+/// Where the color of a label is set, e.g. of a key in the reports setting
+/// key colors
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct ColorLocation {
+    pub label: String,
+    /// Index of the transaction in the reference sample
+    pub transaction: usize,
+    pub id: u8,
+    /// Request payload offsets of the id and the red, green and blue
+    /// components following it
+    pub id_offset: usize,
+    pub red: usize,
+    pub green: usize,
+    pub blue: usize,
+}
+
+/// Locations of the colors of the labels of `name` whose id `label_ids`
+/// tells apart, ordered by label
+pub fn color_locations(fields: &[Field], name: &str) -> Vec<ColorLocation> {
+    let mut locations = Vec::new();
+    for (label, id) in label_ids(fields, name) {
+        for field in fields {
+            if let FieldKind::LabelRgb { name: ref n, label: ref l, id: Some(i) } = field.kind {
+                if n == name && *l == label && i == id {
+                    locations.push(ColorLocation {
+                        label: label.clone(),
+                        transaction: field.transaction,
+                        id: id,
+                        id_offset: field.offset - 1,
+                        red: field.offset,
+                        green: field.offset + 1,
+                        blue: field.offset + 2,
+                    });
+                }
+            }
+        }
+    }
+    locations
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use filter::Filter;
+    use super::{infer, color_locations, label_ids, Sample, Field, FieldKind, ColorLocation};
+
+    fn location(label: &str, transaction: usize, id: u8, id_offset: usize) -> ColorLocation {
+        ColorLocation {
+            label: label.to_string(),
+            transaction: transaction,
+            id: id,
+            id_offset: id_offset,
+            red: id_offset + 1,
+            green: id_offset + 2,
+            blue: id_offset + 3,
+        }
+    }
+
+    #[test]
+    fn infers_key_colors() {
+        let samples = Sample::from_color_dir(Path::new("pcap/g910/color"), &Filter::all()).unwrap();
+        assert_eq!(samples.len(), 22);
+        let fields = infer(&samples);
+        // the colors of space, which was captured in all colors
+        assert!(fields.contains(&Field { transaction: 20, offset: 0x11, len: 3, kind: FieldKind::Rgb }));
+        assert_eq!(label_ids(&fields, "key").into_iter().collect::<Vec<_>>(), vec![
+            ("e".to_string(), 0x08), ("q".to_string(), 0x14), ("r".to_string(), 0x15),
+            ("space".to_string(), 0x2c), ("w".to_string(), 0x1a)]);
+        assert_eq!(color_locations(&fields, "key"), vec![
+            location("e", 16, 0x08, 0x1c),
+            location("q", 18, 0x14, 0x28),
+            location("r", 18, 0x15, 0x2c),
+            location("space", 20, 0x2c, 0x10),
+            location("w", 18, 0x1a, 0x10),
+        ]);
+    }
+
+    #[test]
+    fn labels_capture_names() {
+        let sample = Sample::from_color_capture(Path::new("pcap/g910/color/space-red2.pcap"), &Filter::all()).unwrap();
+        assert_eq!(sample.labels.get("key").map(|s| &s[..]), Some("space"));
+        assert_eq!(sample.labels.get("color").map(|s| &s[..]), Some("red"));
+    }
+}
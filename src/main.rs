@@ -2,49 +2,436 @@
 
 extern crate libusb;
 extern crate pcap;
+extern crate byteorder;
+extern crate clap;
+extern crate rustc_serialize;
+extern crate regex;
 extern crate g910;
 extern crate g910_handler;
 
+mod capture;
+mod debugger;
+mod descriptor;
+mod diff;
+mod features;
+mod filter;
+mod hid;
+mod hidpp;
+mod infer;
+mod pcapng;
 mod print;
+mod record;
 mod replay;
+mod report;
+mod rules;
+mod simulator;
+mod synth;
 mod usb;
 mod test;
+mod transaction;
+mod transport;
 
+use std::fs::File;
+use std::io::{self, Write};
 use std::path::Path;
+use std::process;
+use std::time::Duration;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use libusb::{Context, DeviceHandle};
+use capture::Capture;
+use debugger::Debugger;
 use replay::Control;
+use filter::Filter;
+use hid::ReportDescriptor;
+use print::{DeviceTree, Format};
+use record::Recorder;
+use rules::Rules;
+use simulator::Simulator;
+use transaction::Transaction;
+use transport::{Transport, LibusbTransport, MockTransport};
 
 use g910::{Keyboard, Color, KeyEvent, KeyboardImpl};
 use g910_handler::{HeatmapHandler, UinputHandler, FlashHandler, Snake};
 
+const LOGITECH: u16 = 0x046d;
+const G910: u16 = 0xc32b;
+
 fn main() {
-    //test::print_memory_layout();
-    //return;
-    //let p = Path::new("pcap/g910/handshake/handshake2.pcap");
-    //test::print_all_data(&p);
-    //return;
-
-    //let p1 = Path::new("pcap/g910/color/space-red.pcap");
-    //let p2 = Path::new("pcap/g910/color/space-blue.pcap");
-    //test::compare(&p1, &p2);
-    //return;
-    
-    let mut keyboard = KeyboardImpl::new().unwrap();
-    keyboard.add_handler(HeatmapHandler::new().into());
-    keyboard.add_handler(UinputHandler::new().into());
-    //keyboard.add_handler(FlashHandler::new().into());
-    //keyboard.add_handler(Snake::new().into());
-    keyboard.start_handle_loop().unwrap();
-    return;
-
-
-    //let p = Path::new("pcap/g910/handshake/handshake.pcap");
-
-    //{
-        //let mut ctrl = Control::new(&p, &context, &*handle);
-        //// first 6 packets are from wireshark
-        //ctrl.skip(6);
-        //ctrl.test().unwrap();
-        //ctrl.replay_handshake().unwrap();
-    //}
+    let filter = Arg::with_name("filter")
+        .short("f")
+        .long("filter")
+        .takes_value(true)
+        .value_name("EXPR")
+        .help("Only uses packets matching EXPR, e.g. 'dir == out && ep == 0 && data[0] == 0x12'");
+    let matches = App::new("usbtest")
+        .about("Analyses and replays USB captures of Logitech keyboards and mice")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("dump")
+            .about("Prints the data of all packets of a capture")
+            .arg(Arg::with_name("CAPTURE").required(true))
+            .arg(filter.clone())
+            .arg(Arg::with_name("features")
+                .long("features")
+                .takes_value(true)
+                .value_name("HANDSHAKE")
+                .help("Prints HID++ messages with the feature names learned from HANDSHAKE")))
+        .subcommand(SubCommand::with_name("diff")
+            .about("Prints the differences between two captures")
+            .arg(Arg::with_name("CAPTURE1").required(true))
+            .arg(Arg::with_name("CAPTURE2").required(true))
+            .arg(filter.clone()))
+        .subcommand(SubCommand::with_name("fields")
+            .about("Infers the fields varying between the <key>-<color>.pcap captures of a directory")
+            .arg(Arg::with_name("DIR").required(true))
+            .arg(filter.clone())
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Prints where each key's id and color are set as JSON")))
+        .subcommand(SubCommand::with_name("hid")
+            .about("Decodes the HID report descriptors of the G910 or of a capture")
+            .arg(Arg::with_name("CAPTURE")
+                .help("Decodes the report descriptors returned in CAPTURE instead of reading them from the G910"))
+            .arg(filter.clone().requires("CAPTURE")))
+        .subcommand(SubCommand::with_name("replay")
+            .about("Replays a capture to the G910 and compares the responses")
+            .arg(Arg::with_name("CAPTURE")
+                .required(true)
+                .help("Capture to replay, or a .txt file of transfers as compiled by synth"))
+            .arg(Arg::with_name("skip")
+                .long("skip")
+                .takes_value(true)
+                .value_name("N")
+                .help("Skips the first N transactions, by default the 3 of wireshark in unfiltered captures and none \
+                       in .txt files"))
+            .arg(filter.clone().help("Only replays transactions whose Submit or Complete matches EXPR"))
+            .arg(Arg::with_name("handshake")
+                .long("handshake")
+                .help("Stops after the handshake"))
+            .arg(Arg::with_name("interactive")
+                .short("i")
+                .long("interactive")
+                .conflicts_with("handshake")
+                .help("Starts in the replay debugger instead of replaying until the first difference"))
+            .arg(Arg::with_name("timing")
+                .long("timing")
+                .help("Keeps the recorded gaps between Submits and reports how far the replay drifted"))
+            .arg(Arg::with_name("scale")
+                .long("scale")
+                .takes_value(true)
+                .value_name("FACTOR")
+                .requires("timing")
+                .help("Multiplies the recorded gaps, e.g. 0.5 to replay twice as fast"))
+            .arg(Arg::with_name("mock")
+                .long("mock")
+                .takes_value(true)
+                .value_name("DEVICE")
+                .conflicts_with("simulate")
+                .help("Replays to a device answering strictly as recorded in the capture or .txt file DEVICE"))
+            .arg(Arg::with_name("simulate")
+                .long("simulate")
+                .takes_value(true)
+                .multiple(true)
+                .value_name("HANDSHAKE")
+                .help("Replays to a G910 simulated from the HANDSHAKE captures"))
+            .arg(Arg::with_name("rules")
+                .long("rules")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Decides which responses are correct with the rules in FILE instead of CAPTURE.rules"))
+            .arg(Arg::with_name("report")
+                .long("report")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Writes the result of each transaction into FILE, as JSON if it ends in .json"))
+            .arg(Arg::with_name("record")
+                .long("record")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Writes all transfers of the replay into the usbmon pcap FILE")))
+        .subcommand(SubCommand::with_name("synth")
+            .about("Compiles a text file of transfers, one per line, into a usbmon pcap")
+            .arg(Arg::with_name("SOURCE").required(true))
+            .arg(Arg::with_name("OUT").required(true)))
+        .subcommand(SubCommand::with_name("layout")
+            .about("Prints the G910's memory layout of key colors"))
+        .subcommand(SubCommand::with_name("devices")
+            .about("Prints all connected USB devices and their descriptors")
+            .arg(Arg::with_name("capture")
+                .long("capture")
+                .takes_value(true)
+                .value_name("CAPTURE")
+                .help("Rebuilds the descriptors from the GET_DESCRIPTOR responses in CAPTURE instead"))
+            .arg(filter.clone().requires("capture"))
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Prints the descriptor trees as JSON")))
+        .subcommand(SubCommand::with_name("run")
+            .about("Runs the G910 with handlers")
+            .arg(Arg::with_name("handler")
+                .long("handler")
+                .takes_value(true)
+                .multiple(true)
+                .possible_values(&["heatmap", "uinput", "flash", "snake"])
+                .default_value("heatmap,uinput")
+                .use_delimiter(true)
+                .help("Handlers to add to the keyboard")))
+        .get_matches();
+
+    match matches.subcommand() {
+        ("dump", Some(m)) => {
+            let p = Path::new(m.value_of("CAPTURE").unwrap());
+            match m.value_of("features") {
+                Some(h) => test::print_features(Path::new(h), p, &parse_filter(m)),
+                None => test::print_all_data(p, &parse_filter(m)),
+            }.unwrap_or_else(|e| fail(e))
+        },
+        ("diff", Some(m)) => test::compare(Path::new(m.value_of("CAPTURE1").unwrap()),
+                                           Path::new(m.value_of("CAPTURE2").unwrap()), &parse_filter(m))
+            .unwrap_or_else(|e| fail(e)),
+        ("fields", Some(m)) => {
+            let format = if m.is_present("json") { Format::Json } else { Format::Text };
+            test::print_color_fields(Path::new(m.value_of("DIR").unwrap()), &parse_filter(m), format)
+                .unwrap_or_else(|e| fail(e))
+        },
+        ("hid", Some(m)) => match m.value_of("CAPTURE") {
+            Some(p) => test::print_report_descriptors(Path::new(p), &parse_filter(m)).unwrap_or_else(|e| fail(e)),
+            None => hid(),
+        },
+        ("replay", Some(m)) => replay(m),
+        ("synth", Some(m)) => {
+            let (source, out) = (m.value_of("SOURCE").unwrap(), m.value_of("OUT").unwrap());
+            let count = synth::compile(Path::new(source), Path::new(out)).unwrap_or_else(|e| fail(e));
+            println!("Wrote {} transactions to {}", count, out);
+        },
+        ("layout", Some(_)) => test::print_memory_layout(),
+        ("devices", Some(m)) => devices(m),
+        ("run", Some(m)) => run(m),
+        _ => unreachable!(),
+    }
+}
+
+fn fail<D: std::fmt::Display>(msg: D) -> ! {
+    let _ = writeln!(io::stderr(), "{}", msg);
+    process::exit(1);
+}
+
+fn parse_filter(m: &ArgMatches) -> Filter {
+    let expr = m.value_of("filter").unwrap_or("");
+    Filter::parse(expr).unwrap_or_else(|e| fail(e.highlight(expr)))
+}
+
+fn replay(m: &ArgMatches) {
+    let filter = parse_filter(m);
+    let transactions = read_transactions(m.value_of("CAPTURE").unwrap()).into_iter()
+        .filter(|t| filter.matches_transaction(t))
+        .collect();
+    if let Some(device) = m.value_of("mock") {
+        let mock = MockTransport::new(read_transactions(device));
+        return record_replay(transactions, mock, None, m).unwrap_or_else(|e| fail(e));
+    }
+    if let Some(handshakes) = m.values_of("simulate") {
+        let handshakes: Vec<_> = handshakes.collect();
+        let sim = Simulator::from_files(&handshakes).unwrap_or_else(|e| fail(e));
+        return record_replay(transactions, MockTransport::with_script(sim), None, m).unwrap_or_else(|e| fail(e));
+    }
+
+    let context = Context::new().unwrap_or_else(|e| fail(e));
+    let mut handle = match context.open_device_with_vid_pid(LOGITECH, G910) {
+        Some(handle) => handle,
+        None => fail("No G910 found"),
+    };
+    let detached = claim(&mut handle).unwrap_or_else(|e| fail(e));
+    let res = {
+        let transport = LibusbTransport::new(&context, &handle, Duration::from_secs(10));
+        record_replay(transactions, transport, g910_address(&context), m)
+    };
+    release(&mut handle, &detached);
+    res.unwrap_or_else(|e| fail(e));
+}
+
+/// Replays to `transport`, recording the transfers if requested. `address`
+/// is the bus and device number of the device.
+fn record_replay<T: Transport>(transactions: Vec<Transaction>, transport: T, address: Option<(u16, u8)>,
+                               m: &ArgMatches) -> Result<(), String> {
+    let out = match m.value_of("record") {
+        Some(out) => out,
+        None => return run_replay(&mut Control::from_transactions(transactions, transport), m),
+    };
+    let mut recorder = try!(Recorder::create(transport, Path::new(out))
+        .map_err(|e| format!("Could not create {}: {}", out, e)));
+    if let Some((bus_id, device)) = address {
+        recorder.set_address(bus_id, device);
+    }
+    let mut ctrl = Control::from_transactions(transactions, recorder);
+    let res = run_replay(&mut ctrl, m);
+    if let Some(e) = ctrl.transport().error() {
+        return Err(format!("Could not record to {}: {}", out, e));
+    }
+    res
+}
+
+/// Whether `path` is a .txt file of transfers instead of a capture
+fn is_synth(path: &Path) -> bool {
+    path.extension().map_or(false, |e| e == "txt")
+}
+
+/// Transactions of a capture, or synthesized from a .txt file of transfers
+fn read_transactions(path: &str) -> Vec<Transaction> {
+    let path = Path::new(path);
+    if is_synth(path) {
+        return synth::from_file(path).unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e)));
+    }
+    let mut capture = Capture::from_file(path).unwrap_or_else(|e| fail(e));
+    transaction::pair(capture.read_all().unwrap_or_else(|e| fail(e)))
+}
+
+/// Bus and device number of the G910
+fn g910_address(context: &Context) -> Option<(u16, u8)> {
+    let devices = match context.devices() {
+        Ok(devices) => devices,
+        Err(_) => return None,
+    };
+    let found = devices.iter().find(|d| match d.device_descriptor() {
+        Ok(desc) => desc.vendor_id() == LOGITECH && desc.product_id() == G910,
+        Err(_) => false,
+    });
+    found.map(|d| (d.bus_number() as u16, d.address()))
+}
+
+fn run_replay<T: Transport>(ctrl: &mut Control<T>, m: &ArgMatches) -> Result<(), String> {
+    let sidecar = Rules::sidecar(Path::new(m.value_of("CAPTURE").unwrap()));
+    let rules = match m.value_of("rules") {
+        Some(p) => Some(Path::new(p).to_path_buf()),
+        None if sidecar.exists() => Some(sidecar),
+        None => None,
+    };
+    if let Some(p) = rules {
+        let rules = try!(Rules::from_file(&p).map_err(|e| format!("{}: {}", p.display(), e)));
+        println!("Using {} matching rules of {}", rules.len(), p.display());
+        ctrl.set_rules(rules);
+    }
+    let skip = match m.value_of("skip") {
+        Some(skip) => try!(skip.parse().map_err(|e| format!("Invalid skip: {}", e))),
+        // wireshark's transactions are unlikely to match a filter
+        None if is_synth(Path::new(m.value_of("CAPTURE").unwrap())) || m.is_present("filter") => 0,
+        None => 3,
+    };
+    if skip as usize > ctrl.remaining() {
+        return Err(format!("Cannot skip {} of {} transactions", skip, ctrl.remaining()));
+    }
+    ctrl.skip(skip);
+    if m.is_present("timing") {
+        let scale = m.value_of("scale").unwrap_or("1").parse::<f64>().ok().and_then(|s| match s {
+            s if s >= 0.0 && s.is_finite() => Some(s),
+            _ => None,
+        });
+        ctrl.set_timing(try!(scale.ok_or("Invalid scale, expected a non-negative number")));
+    }
+    let res = if m.is_present("handshake") {
+        match ctrl.handshake() {
+            Ok(until) => {
+                Debugger::new(ctrl).run_to(&until);
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    } else {
+        // halt in the debugger on differing responses and errors
+        Debugger::new(ctrl).run(m.is_present("interactive"));
+        Ok(())
+    };
+    if let Some(drift) = ctrl.drift() {
+        println!("{}", drift);
+    }
+    println!("{}", ctrl.report().summary());
+    if let Some(p) = m.value_of("report") {
+        let format = if p.ends_with(".json") { Format::Json } else { Format::Text };
+        try!(File::create(p).and_then(|mut f| ctrl.report().write(&mut f, format))
+            .map_err(|e| format!("Could not write {}: {}", p, e)));
+    }
+    res.map_err(|e| e.to_string())
+}
+
+fn hid() {
+    let context = Context::new().unwrap_or_else(|e| fail(e));
+    let mut handle = match context.open_device_with_vid_pid(LOGITECH, G910) {
+        Some(handle) => handle,
+        None => fail("No G910 found"),
+    };
+    let detached = claim(&mut handle).unwrap_or_else(|e| fail(e));
+    let descriptors: libusb::Result<Vec<_>> = (0..2)
+        .map(|iface| hid::read_report_descriptor(&handle, iface, Duration::from_secs(1)))
+        .collect();
+    release(&mut handle, &detached);
+    for (iface, bytes) in descriptors.unwrap_or_else(|e| fail(e)).iter().enumerate() {
+        println!("Interface {}:", iface);
+        match ReportDescriptor::parse(bytes) {
+            Ok(desc) => desc.print(),
+            Err(e) => println!("Invalid report descriptor: {}", e),
+        }
+        println!("");
+    }
 }
 
+/// Detaches the kernel driver from and claims both interfaces of the G910.
+/// Returns the interfaces whose kernel driver was detached.
+fn claim(handle: &mut DeviceHandle) -> libusb::Result<Vec<u8>> {
+    let mut detached = Vec::new();
+    for iface in 0..2 {
+        if try!(test::detach(handle, iface)) {
+            detached.push(iface);
+        }
+        try!(handle.claim_interface(iface));
+    }
+    Ok(detached)
+}
+
+/// Releases both interfaces and gives the `detached` ones back to the kernel
+fn release(handle: &mut DeviceHandle, detached: &[u8]) {
+    for iface in 0..2 {
+        if let Err(e) = handle.release_interface(iface) {
+            let _ = writeln!(io::stderr(), "Could not release interface {}: {}", iface, e);
+        }
+    }
+    for &iface in detached {
+        if let Err(e) = handle.attach_kernel_driver(iface) {
+            let _ = writeln!(io::stderr(), "Could not reattach kernel driver to interface {}: {}", iface, e);
+        }
+    }
+}
+
+fn devices(m: &ArgMatches) {
+    let format = if m.is_present("json") { Format::Json } else { Format::Text };
+    if let Some(p) = m.value_of("capture") {
+        let trees = descriptor::from_capture(Path::new(p), &parse_filter(m)).unwrap_or_else(|e| fail(e));
+        return print::print_trees(&trees, format);
+    }
+    let context = Context::new().unwrap_or_else(|e| fail(e));
+    if format == Format::Text {
+        print::print_libusb();
+        print::print_context(&context);
+    }
+    let mut trees = Vec::new();
+    for mut device in context.devices().unwrap_or_else(|e| fail(e)).iter() {
+        match DeviceTree::from_device(&mut device) {
+            Ok(tree) => trees.push(tree),
+            Err(e) => { let _ = writeln!(io::stderr(), "Error accessing descriptor: {:?}", e); },
+        }
+    }
+    print::print_trees(&trees, format);
+}
+
+fn run(m: &ArgMatches) {
+    // the errors of the g910 crate are only Debug
+    let mut keyboard = KeyboardImpl::new().unwrap_or_else(|e| fail(format!("Could not open the G910: {:?}", e)));
+    for handler in m.values_of("handler").unwrap() {
+        match handler {
+            "heatmap" => keyboard.add_handler(HeatmapHandler::new().into()),
+            "uinput" => keyboard.add_handler(UinputHandler::new().into()),
+            "flash" => keyboard.add_handler(FlashHandler::new().into()),
+            "snake" => keyboard.add_handler(Snake::new().into()),
+            _ => unreachable!(),
+        }
+    }
+    keyboard.start_handle_loop().unwrap_or_else(|e| fail(format!("{:?}", e)));
+}
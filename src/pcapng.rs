@@ -0,0 +1,458 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use byteorder::{ByteOrder, BigEndian, LittleEndian, NativeEndian};
+use usb::{Packet, LinkType};
+
+/// First four bytes of every pcapng file
+pub const MAGIC: [u8; 4] = [0x0a, 0x0d, 0x0d, 0x0a];
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_PACKET: u32 = 0x00000002;
+const BLOCK_SIMPLE_PACKET: u32 = 0x00000003;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_TSRESOL: u16 = 9;
+
+#[derive(Debug)]
+pub enum PcapngError {
+    Io(io::Error),
+    /// The file violates the pcapng block structure at the given offset
+    Format { offset: usize, reason: &'static str },
+}
+
+impl fmt::Display for PcapngError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PcapngError::Io(ref e) => write!(f, "io error: {}", e),
+            PcapngError::Format { offset, reason } =>
+                write!(f, "malformed pcapng at offset {}: {}", offset, reason),
+        }
+    }
+}
+
+impl StdError for PcapngError {
+    fn description(&self) -> &str {
+        match *self {
+            PcapngError::Io(_) => "io error",
+            PcapngError::Format { .. } => "malformed pcapng",
+        }
+    }
+}
+
+impl From<io::Error> for PcapngError {
+    fn from(e: io::Error) -> Self {
+        PcapngError::Io(e)
+    }
+}
+
+/// Byte order of a pcapng section
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    fn u16(&self, buf: &[u8]) -> u16 {
+        match *self {
+            Endianness::Little => LittleEndian::read_u16(buf),
+            Endianness::Big => BigEndian::read_u16(buf),
+        }
+    }
+
+    fn u32(&self, buf: &[u8]) -> u32 {
+        match *self {
+            Endianness::Little => LittleEndian::read_u32(buf),
+            Endianness::Big => BigEndian::read_u32(buf),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    link_type: i32,
+    /// Timestamp units per second
+    ts_units: u64,
+}
+
+/// A packet read from a pcapng file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record<'a> {
+    /// Index of the interface across all sections
+    pub interface: usize,
+    pub link_type: i32,
+    /// Byte order of the section, which is also the byte order of
+    /// the usbmon header of the capturing host
+    pub endianness: Endianness,
+    pub sec: u64,
+    pub usec: u32,
+    pub data: &'a [u8],
+    pub comment: Option<String>,
+}
+
+fn format_error<T>(offset: usize, reason: &'static str) -> Result<T, PcapngError> {
+    Err(PcapngError::Format { offset: offset, reason: reason })
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Microseconds in `rem` timestamp units, which are less than a second of
+/// `units`, a power of 10 or 2
+fn micros(rem: u64, units: u64) -> u32 {
+    let micros = if units % 1_000_000 == 0 {
+        rem / (units / 1_000_000)
+    } else if units <= u64::max_value() / 1_000_000 {
+        rem * 1_000_000 / units
+    } else {
+        // 2^44 < units <= 2^63, divide the low bits first to not overflow
+        let k = units.trailing_zeros() - 44;
+        let (high, low) = (rem >> k, rem & ((1 << k) - 1));
+        (high * 1_000_000 + (low * 1_000_000 >> k)) >> 44
+    };
+    micros as u32
+}
+
+/// Iterates over the options of a block, calling `f` with code and value.
+fn read_options<F>(mut options: &[u8], endianness: Endianness, mut f: F)
+        where F: FnMut(u16, &[u8]) {
+    while options.len() >= 4 {
+        let code = endianness.u16(&options[0..2]);
+        let len = endianness.u16(&options[2..4]) as usize;
+        if code == OPT_END || options.len() < 4 + len {
+            return;
+        }
+        f(code, &options[4..4 + len]);
+        options = &options[::std::cmp::min(options.len(), 4 + padded(len))..];
+    }
+}
+
+fn read_comment(options: &[u8], endianness: Endianness) -> Option<String> {
+    let mut comment = None;
+    read_options(options, endianness, |code, value| {
+        if code == OPT_COMMENT && comment.is_none() {
+            comment = Some(String::from_utf8_lossy(value).into_owned());
+        }
+    });
+    comment
+}
+
+/// Reads all packets of all sections of a pcapng file.
+pub fn read(bytes: &[u8]) -> Result<Vec<Record>, PcapngError> {
+    let mut records = Vec::new();
+    // interfaces of previous sections stay addressable by their global index
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut section_start = 0;
+    let mut endianness = Endianness::Little;
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        if bytes.len() - offset < 12 {
+            return format_error(offset, "truncated block header");
+        }
+        let block = &bytes[offset..];
+        if LittleEndian::read_u32(&block[0..4]) == BLOCK_SECTION_HEADER {
+            endianness = match LittleEndian::read_u32(&block[8..12]) {
+                BYTE_ORDER_MAGIC => Endianness::Little,
+                m if m.swap_bytes() == BYTE_ORDER_MAGIC => Endianness::Big,
+                _ => return format_error(offset, "invalid byte order magic"),
+            };
+            section_start = interfaces.len();
+        } else if offset == 0 {
+            return format_error(offset, "missing section header block");
+        }
+        let block_type = endianness.u32(&block[0..4]);
+        let total_len = endianness.u32(&block[4..8]) as usize;
+        if total_len < 12 || total_len % 4 != 0 || total_len > block.len() {
+            return format_error(offset, "invalid block length");
+        }
+        let body = &block[8..total_len - 4];
+
+        match block_type {
+            BLOCK_INTERFACE_DESCRIPTION => {
+                if body.len() < 8 {
+                    return format_error(offset, "truncated interface description block");
+                }
+                let mut ts_units = Some(1_000_000);
+                read_options(&body[8..], endianness, |code, value| {
+                    if code == OPT_IF_TSRESOL && value.len() == 1 {
+                        let exp = (value[0] & 0x7f) as u32;
+                        // units per second must fit into a u64
+                        ts_units = match (value[0] & 0x80 == 0, exp) {
+                            (true, 0...19) => Some(10u64.pow(exp)),
+                            (false, 0...63) => Some(1 << exp),
+                            _ => None,
+                        };
+                    }
+                });
+                let ts_units = match ts_units {
+                    Some(units) => units,
+                    None => return format_error(offset, "invalid timestamp resolution"),
+                };
+                interfaces.push(Interface {
+                    link_type: endianness.u16(&body[0..2]) as i32,
+                    ts_units: ts_units,
+                });
+            },
+            BLOCK_ENHANCED_PACKET | BLOCK_PACKET => {
+                if body.len() < 20 {
+                    return format_error(offset, "truncated packet block");
+                }
+                let interface = if block_type == BLOCK_ENHANCED_PACKET {
+                    endianness.u32(&body[0..4]) as usize
+                } else {
+                    endianness.u16(&body[0..2]) as usize
+                };
+                let index = section_start + interface;
+                let iface = match interfaces.get(index) {
+                    Some(&iface) => iface,
+                    _ => return format_error(offset, "packet of unknown interface"),
+                };
+                let ts = (endianness.u32(&body[4..8]) as u64) << 32
+                    | endianness.u32(&body[8..12]) as u64;
+                let caplen = endianness.u32(&body[12..16]) as usize;
+                if body.len() < 20 + caplen {
+                    return format_error(offset, "packet data exceeds block");
+                }
+                let options = &body[::std::cmp::min(body.len(), 20 + padded(caplen))..];
+                records.push(Record {
+                    interface: index,
+                    link_type: iface.link_type,
+                    endianness: endianness,
+                    sec: ts / iface.ts_units,
+                    usec: micros(ts % iface.ts_units, iface.ts_units),
+                    data: &body[20..20 + caplen],
+                    comment: read_comment(options, endianness),
+                });
+            },
+            BLOCK_SIMPLE_PACKET => {
+                let iface = match interfaces.get(section_start) {
+                    Some(&iface) => iface,
+                    _ => return format_error(offset, "packet of unknown interface"),
+                };
+                if body.len() < 4 {
+                    return format_error(offset, "truncated simple packet block");
+                }
+                let len = endianness.u32(&body[0..4]) as usize;
+                let caplen = ::std::cmp::min(len, body.len() - 4);
+                records.push(Record {
+                    interface: section_start,
+                    link_type: iface.link_type,
+                    endianness: endianness,
+                    sec: 0,
+                    usec: 0,
+                    data: &body[4..4 + caplen],
+                    comment: None,
+                });
+            },
+            // statistics, name resolution and custom blocks
+            _ => {},
+        }
+        offset += total_len;
+    }
+    Ok(records)
+}
+
+/// Writes usbmon packets into a pcapng file with a single
+/// `DLT_USB_LINUX_MMAPPED` interface in host byte order.
+pub struct Writer<W: Write> {
+    w: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut w: W) -> io::Result<Writer<W>> {
+        let mut shb = Vec::new();
+        put_u32(&mut shb, BYTE_ORDER_MAGIC);
+        put_u16(&mut shb, 1);
+        put_u16(&mut shb, 0);
+        // section length unknown
+        put_u32(&mut shb, 0xffffffff);
+        put_u32(&mut shb, 0xffffffff);
+        try!(write_block(&mut w, BLOCK_SECTION_HEADER, &shb));
+
+        let mut idb = Vec::new();
+        put_u16(&mut idb, LinkType::UsbLinuxMmapped.dlt() as u16);
+        put_u16(&mut idb, 0);
+        // no snap length limit
+        put_u32(&mut idb, 0);
+        try!(write_block(&mut w, BLOCK_INTERFACE_DESCRIPTION, &idb));
+        Ok(Writer { w: w })
+    }
+
+    /// Writes a packet, optionally annotated with a comment like a replay
+    /// verdict which Wireshark shows as `frame.comment`.
+    pub fn write_packet(&mut self, packet: &Packet, comment: Option<&str>) -> io::Result<()> {
+        let data = packet.to_bytes();
+        let ts = packet.get_sec() * 1_000_000 + packet.get_usec() as u64;
+        let mut epb = Vec::with_capacity(32 + data.len());
+        put_u32(&mut epb, 0);
+        put_u32(&mut epb, (ts >> 32) as u32);
+        put_u32(&mut epb, ts as u32);
+        put_u32(&mut epb, data.len() as u32);
+        put_u32(&mut epb, data.len() as u32);
+        epb.extend_from_slice(&data);
+        pad(&mut epb);
+        if let Some(comment) = comment {
+            if comment.len() > u16::max_value() as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "comment longer than 65535 bytes"));
+            }
+            put_u16(&mut epb, OPT_COMMENT);
+            put_u16(&mut epb, comment.len() as u16);
+            epb.extend_from_slice(comment.as_bytes());
+            pad(&mut epb);
+            put_u16(&mut epb, OPT_END);
+            put_u16(&mut epb, 0);
+        }
+        write_block(&mut self.w, BLOCK_ENHANCED_PACKET, &epb)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, n: u16) {
+    let mut b = [0u8; 2];
+    NativeEndian::write_u16(&mut b, n);
+    buf.extend_from_slice(&b);
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    let mut b = [0u8; 4];
+    NativeEndian::write_u32(&mut b, n);
+    buf.extend_from_slice(&b);
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    let len = padded(buf.len());
+    buf.resize(len, 0);
+}
+
+fn write_block<W: Write>(w: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let mut block = Vec::with_capacity(body.len() + 12);
+    let total_len = (padded(body.len()) + 12) as u32;
+    put_u32(&mut block, block_type);
+    put_u32(&mut block, total_len);
+    block.extend_from_slice(body);
+    pad(&mut block);
+    put_u32(&mut block, total_len);
+    w.write_all(&block)
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, BigEndian, LittleEndian};
+    use synth::Transfer;
+    use super::{read, Writer, Endianness, PcapngError};
+
+    fn put(buf: &mut Vec<u8>, big: bool, n: u32, len: usize) {
+        let mut b = [0u8; 4];
+        if big { BigEndian::write_u32(&mut b, n) } else { LittleEndian::write_u32(&mut b, n) }
+        let b = if big { &b[4 - len..] } else { &b[..len] };
+        buf.extend_from_slice(b);
+    }
+
+    fn block(big: bool, block_type: u32, body: &[u8]) -> Vec<u8> {
+        let mut block = Vec::new();
+        put(&mut block, big, block_type, 4);
+        put(&mut block, big, body.len() as u32 + 12, 4);
+        block.extend_from_slice(body);
+        put(&mut block, big, body.len() as u32 + 12, 4);
+        block
+    }
+
+    /// A section with one interface of resolution `tsresol` and a 4 byte
+    /// packet at `ts` on interface `iface`
+    fn section(big: bool, tsresol: Option<u8>, iface: u32, ts: u64) -> Vec<u8> {
+        let mut shb = Vec::new();
+        put(&mut shb, big, 0x1a2b3c4d, 4);
+        put(&mut shb, big, 1, 2);
+        put(&mut shb, big, 0, 2);
+        shb.extend_from_slice(&[0xff; 8]);
+        let mut idb = Vec::new();
+        put(&mut idb, big, 220, 2);
+        put(&mut idb, big, 0, 2);
+        put(&mut idb, big, 0, 4);
+        if let Some(tsresol) = tsresol {
+            put(&mut idb, big, 9, 2);
+            put(&mut idb, big, 1, 2);
+            idb.extend_from_slice(&[tsresol, 0, 0, 0]);
+        }
+        let mut epb = Vec::new();
+        put(&mut epb, big, iface, 4);
+        put(&mut epb, big, (ts >> 32) as u32, 4);
+        put(&mut epb, big, ts as u32, 4);
+        put(&mut epb, big, 4, 4);
+        put(&mut epb, big, 4, 4);
+        epb.extend_from_slice(&[1, 2, 3, 4]);
+        let mut bytes = block(big, 0x0a0d0d0a, &shb);
+        bytes.extend(block(big, 1, &idb));
+        bytes.extend(block(big, 6, &epb));
+        bytes
+    }
+
+    fn time(bytes: &[u8]) -> (u64, u32) {
+        let records = read(bytes).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].data, &[1, 2, 3, 4]);
+        (records[0].sec, records[0].usec)
+    }
+
+    #[test]
+    fn writes_and_reads_packets_with_comments() {
+        let t = Transfer::control(0x80, 0x06, 0x0100, 0, vec![18, 1]).to_transaction(7, 1_500_000);
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        writer.write_packet(t.submit().unwrap(), Some("Incorrect")).unwrap();
+        writer.write_packet(t.complete().unwrap(), None).unwrap();
+        let bytes = writer.into_inner();
+        let records = read(&bytes).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].comment, Some("Incorrect".to_string()));
+        assert_eq!(records[1].comment, None);
+        assert_eq!((records[0].sec, records[0].usec), (1, 500_000));
+        assert_eq!(records[0].data, &t.submit().unwrap().to_bytes()[..]);
+        assert_eq!(records[1].data, &t.complete().unwrap().to_bytes()[..]);
+    }
+
+    #[test]
+    fn converts_timestamp_resolutions() {
+        assert_eq!(time(&section(false, None, 0, 1_250_000)), (1, 250_000));
+        assert_eq!(time(&section(false, Some(9), 0, 2_000_000_500)), (2, 0));
+        assert_eq!(time(&section(false, Some(3), 0, 1_250)), (1, 250_000));
+        assert_eq!(time(&section(false, Some(19), 0, u64::max_value())), (1, 844_674));
+        assert_eq!(time(&section(false, Some(0x80 | 10), 0, 1024 + 512)), (1, 500_000));
+        assert_eq!(time(&section(false, Some(0x80 | 63), 0, 3 << 61)), (0, 750_000));
+    }
+
+    #[test]
+    fn rejects_too_fine_resolutions() {
+        for &tsresol in &[20, 0x80 | 64] {
+            match read(&section(false, Some(tsresol), 0, 0)) {
+                Err(PcapngError::Format { reason, .. }) => assert_eq!(reason, "invalid timestamp resolution"),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_big_endian_sections() {
+        let bytes = section(true, Some(6), 0, 3_000_001);
+        assert_eq!(time(&bytes), (3, 1));
+        assert_eq!(read(&bytes).unwrap()[0].endianness, Endianness::Big);
+    }
+
+    #[test]
+    fn rejects_packets_of_unknown_interfaces() {
+        match read(&section(false, None, 1, 0)) {
+            Err(PcapngError::Format { reason, .. }) => assert_eq!(reason, "packet of unknown interface"),
+            other => panic!("{:?}", other),
+        }
+    }
+}
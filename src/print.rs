@@ -1,18 +1,20 @@
+use std::time::Duration;
 use libusb;
 use libusb::{
     Context,
     Device,
+    DeviceHandle,
     DeviceDescriptor,
     ConfigDescriptor,
     Version,
     Result,
-    Interfaces,
+    Language,
     Interface,
     InterfaceDescriptor,
     EndpointDescriptor,
 };
-use pcap::{Capture, Offline};
-use usb::Packet;
+use rustc_serialize::json;
+use capture::Capture;
 
 trait PrintPrefix {
     fn to_str(&self) -> &str;
@@ -41,122 +43,359 @@ pub fn print_context(context: &Context) {
     println!("supports detach kernel driver? {}", context.supports_detach_kernel_driver());
 }
 
+const STRING_TIMEOUT_MS: u64 = 1000;
+
+/// Output format of descriptor dumps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Indented text
+    Text,
+    Json,
+}
+
+/// Descriptors of a device, serializable to JSON to keep snapshots of
+/// firmware revisions
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct DeviceTree {
+    /// u16 like the bus id of usbmon packets
+    pub bus: u16,
+    pub address: u8,
+    pub speed: String,
+    pub descriptor: Descriptor,
+    pub configs: Vec<Config>,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct Descriptor {
+    pub usb_version: String,
+    pub device_version: String,
+    pub class_code: u8,
+    pub sub_class_code: u8,
+    pub protocol_code: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub max_packet_size: u8,
+    pub num_configurations: u8,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct Config {
+    pub number: u8,
+    pub max_power: u16,
+    pub self_powered: bool,
+    pub remote_wakeup: bool,
+    pub num_interfaces: u8,
+    pub description: Option<String>,
+    pub interfaces: Vec<InterfaceTree>,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct InterfaceTree {
+    pub number: u8,
+    pub alt_settings: Vec<AltSetting>,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct AltSetting {
+    pub number: u8,
+    pub setting_number: u8,
+    pub class_code: u8,
+    pub sub_class_code: u8,
+    pub protocol_code: u8,
+    pub num_endpoints: u8,
+    pub description: Option<String>,
+    /// Only known for descriptors read from a capture
+    pub hid: Option<Hid>,
+    pub endpoints: Vec<Endpoint>,
+}
+
+/// HID class descriptor following the interface descriptor
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct Hid {
+    pub hid_version: String,
+    pub country_code: u8,
+    pub report_descriptor_length: u16,
+    pub report_descriptor: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct Endpoint {
+    pub address: u8,
+    pub number: u8,
+    pub direction: String,
+    pub transfer_type: String,
+    pub sync_type: String,
+    pub usage_type: String,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+/// Reads string descriptors if the device can be opened
+struct Strings<'a> {
+    handle: Option<(DeviceHandle<'a>, Language)>,
+}
+
+impl<'a> Strings<'a> {
+    fn new(device: &Device<'a>) -> Strings<'a> {
+        let timeout = Duration::from_millis(STRING_TIMEOUT_MS);
+        let handle = device.open().ok().and_then(|handle| {
+            let lang = handle.read_languages(timeout).ok().and_then(|l| l.first().cloned());
+            lang.map(|lang| (handle, lang))
+        });
+        Strings { handle: handle }
+    }
+
+    fn read(&self, index: Option<u8>) -> Option<String> {
+        let timeout = Duration::from_millis(STRING_TIMEOUT_MS);
+        match (&self.handle, index) {
+            (&Some((ref handle, lang)), Some(index)) => handle.read_string_descriptor(lang, index, timeout).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl DeviceTree {
+    /// Reads all descriptors of a device. Strings are left out if the device
+    /// can't be opened.
+    pub fn from_device(device: &mut Device) -> Result<DeviceTree> {
+        let desc = try!(device.device_descriptor());
+        let strings = Strings::new(device);
+        let mut configs = Vec::new();
+        for i in 0..desc.num_configurations() {
+            let config = try!(device.config_descriptor(i));
+            configs.push(Config::new(&config, &strings));
+        }
+        Ok(DeviceTree {
+            bus: device.bus_number() as u16,
+            address: device.address(),
+            speed: format!("{:?}", device.speed()),
+            descriptor: Descriptor::new(&desc, &strings),
+            configs: configs,
+        })
+    }
+
+    pub fn print(&self, format: Format) {
+        match format {
+            Format::Text => {
+                println!("Device:");
+                print_device(self, Some("    "));
+                println!("Descriptor:");
+                print_descriptor(&self.descriptor, Some("    "));
+                println!("Config:");
+                print_configs(&self.configs, Some("    "));
+                println!("");
+            },
+            Format::Json => println!("{}", json::as_pretty_json(self)),
+        }
+    }
+}
+
+impl Descriptor {
+    fn new(desc: &DeviceDescriptor, strings: &Strings) -> Descriptor {
+        Descriptor {
+            usb_version: version_to_string(&desc.usb_version()),
+            device_version: version_to_string(&desc.device_version()),
+            class_code: desc.class_code(),
+            sub_class_code: desc.sub_class_code(),
+            protocol_code: desc.protocol_code(),
+            vendor_id: desc.vendor_id(),
+            product_id: desc.product_id(),
+            max_packet_size: desc.max_packet_size(),
+            num_configurations: desc.num_configurations(),
+            manufacturer: strings.read(desc.manufacturer_string_index()),
+            product: strings.read(desc.product_string_index()),
+            serial_number: strings.read(desc.serial_number_string_index()),
+        }
+    }
+}
+
+impl Config {
+    fn new(config: &ConfigDescriptor, strings: &Strings) -> Config {
+        Config {
+            number: config.number(),
+            max_power: config.max_power(),
+            self_powered: config.self_powered(),
+            remote_wakeup: config.remote_wakeup(),
+            num_interfaces: config.num_interfaces(),
+            description: strings.read(config.description_string_index()),
+            interfaces: config.interfaces().map(|i| InterfaceTree::new(&i, strings)).collect(),
+        }
+    }
+}
+
+impl InterfaceTree {
+    fn new(interface: &Interface, strings: &Strings) -> InterfaceTree {
+        InterfaceTree {
+            number: interface.number(),
+            alt_settings: interface.descriptors().map(|d| AltSetting::new(&d, strings)).collect(),
+        }
+    }
+}
+
+impl AltSetting {
+    fn new(if_desc: &InterfaceDescriptor, strings: &Strings) -> AltSetting {
+        AltSetting {
+            number: if_desc.interface_number(),
+            setting_number: if_desc.setting_number(),
+            class_code: if_desc.class_code(),
+            sub_class_code: if_desc.sub_class_code(),
+            protocol_code: if_desc.protocol_code(),
+            num_endpoints: if_desc.num_endpoints(),
+            description: strings.read(if_desc.description_string_index()),
+            hid: None,
+            endpoints: if_desc.endpoint_descriptors().map(|e| Endpoint::new(&e)).collect(),
+        }
+    }
+}
+
+impl Endpoint {
+    fn new(endpoint: &EndpointDescriptor) -> Endpoint {
+        Endpoint {
+            address: endpoint.address(),
+            number: endpoint.number(),
+            direction: format!("{:?}", endpoint.direction()),
+            transfer_type: format!("{:?}", endpoint.transfer_type()),
+            sync_type: format!("{:?}", endpoint.sync_type()),
+            usage_type: format!("{:?}", endpoint.usage_type()),
+            max_packet_size: endpoint.max_packet_size(),
+            interval: endpoint.interval(),
+        }
+    }
+}
+
 #[allow(unused)]
-pub fn print_everything(device: &mut Device) {
-    println!("Device:");
-    print_device(device, Some("    "));
-    match device.device_descriptor() {
-        Ok(desc) => {
-            println!("Descriptor:");
-            print_descriptor(&desc, Some("    "));
+/// Prints the trees one after another, or as a single JSON array
+pub fn print_trees(trees: &[DeviceTree], format: Format) {
+    match format {
+        Format::Text => for tree in trees {
+            tree.print(format);
         },
-        Err(e) => println!("Error accessing descriptor: {:?}", e),
+        Format::Json => println!("{}", json::as_pretty_json(&trees)),
     }
-    println!("Config:");
-    print_configs(device, Some("    ")).unwrap();
-    println!("");
 }
 
 #[allow(unused)]
-pub fn print_device(device: &mut Device, prefix: Option<&str>) {
-    println!("{}Bus: {}", prefix.to_str(), device.bus_number());
-    println!("{}Address: {}", prefix.to_str(), device.address());
-    println!("{}Speed: {:?}", prefix.to_str(), device.speed());
+pub fn print_device(device: &DeviceTree, prefix: Option<&str>) {
+    println!("{}Bus: {}", prefix.to_str(), device.bus);
+    println!("{}Address: {}", prefix.to_str(), device.address);
+    println!("{}Speed: {}", prefix.to_str(), device.speed);
 }
 
 #[allow(unused)]
-pub fn print_descriptor(desc: &DeviceDescriptor, prefix: Option<&str>) {
-    println!("{}UsbVersion: {}", prefix.to_str(), version_to_string(&desc.usb_version()));
-    println!("{}DeviceVersion: {}", prefix.to_str(), version_to_string(&desc.device_version()));
-    println!("{}ClassCode: {}", prefix.to_str(), desc.class_code());
-    println!("{}SubClassCode: {}", prefix.to_str(), desc.sub_class_code());
-    println!("{}ProtocolCode: {}", prefix.to_str(), desc.protocol_code());
-    println!("{}VendorId: {}", prefix.to_str(), desc.vendor_id());
-    println!("{}ProductId: {}", prefix.to_str(), desc.product_id());
-    println!("{}    {:04x}:{:04x}", prefix.to_str(), desc.vendor_id(), desc.product_id());
-    println!("{}MaxPacketSize: {}", prefix.to_str(), desc.max_packet_size());
-    println!("{}NumConfigurations: {}", prefix.to_str(), desc.num_configurations());
+pub fn print_descriptor(desc: &Descriptor, prefix: Option<&str>) {
+    println!("{}UsbVersion: {}", prefix.to_str(), desc.usb_version);
+    println!("{}DeviceVersion: {}", prefix.to_str(), desc.device_version);
+    println!("{}ClassCode: {}", prefix.to_str(), desc.class_code);
+    println!("{}SubClassCode: {}", prefix.to_str(), desc.sub_class_code);
+    println!("{}ProtocolCode: {}", prefix.to_str(), desc.protocol_code);
+    println!("{}VendorId: {}", prefix.to_str(), desc.vendor_id);
+    println!("{}ProductId: {}", prefix.to_str(), desc.product_id);
+    println!("{}    {:04x}:{:04x}", prefix.to_str(), desc.vendor_id, desc.product_id);
+    println!("{}MaxPacketSize: {}", prefix.to_str(), desc.max_packet_size);
+    println!("{}NumConfigurations: {}", prefix.to_str(), desc.num_configurations);
+    print_string("Manufacturer", &desc.manufacturer, prefix);
+    print_string("Product", &desc.product, prefix);
+    print_string("SerialNumber", &desc.serial_number, prefix);
 }
 
 #[allow(unused)]
-pub fn print_configs(device: &mut Device, prefix: Option<&str>) -> Result<()> {
-    let desc = try!(device.device_descriptor());
-    let num_config = desc.num_configurations();
-    for i in 0..num_config {
-        let config = try!(device.config_descriptor(i));
-        print_config(&config, prefix);
+pub fn print_configs(configs: &[Config], prefix: Option<&str>) {
+    for config in configs {
+        print_config(config, prefix);
     }
-    return Ok(());
 }
 
 #[allow(unused)]
-pub fn print_config(config: &ConfigDescriptor, prefix: Option<&str>) {
-    println!("{}Num: {}", prefix.to_str(), config.number());
-    println!("{}MaxPower: {}", prefix.to_str(), config.max_power());
-    println!("{}SelfPowered: {}", prefix.to_str(), config.self_powered());
-    println!("{}RemoteWakeup: {}", prefix.to_str(), config.remote_wakeup());
-    println!("{}NumInterfaces: {}", prefix.to_str(), config.num_interfaces());
+pub fn print_config(config: &Config, prefix: Option<&str>) {
+    println!("{}Num: {}", prefix.to_str(), config.number);
+    println!("{}MaxPower: {}", prefix.to_str(), config.max_power);
+    println!("{}SelfPowered: {}", prefix.to_str(), config.self_powered);
+    println!("{}RemoteWakeup: {}", prefix.to_str(), config.remote_wakeup);
+    println!("{}NumInterfaces: {}", prefix.to_str(), config.num_interfaces);
+    print_string("Description", &config.description, prefix);
     println!("{}Interfaces:", prefix.to_str());
-    print_interfaces(&mut config.interfaces(), Some(&(prefix.to_str().to_string() + "    ")));
+    print_interfaces(&config.interfaces, Some(&(prefix.to_str().to_string() + "    ")));
 }
 
 #[allow(unused)]
-pub fn print_interfaces(interfaces: &mut Interfaces, prefix: Option<&str>) {
+pub fn print_interfaces(interfaces: &[InterfaceTree], prefix: Option<&str>) {
     for interface in interfaces {
-        print_interface(&interface, prefix);
+        print_interface(interface, prefix);
     }
 }
 
 #[allow(unused)]
-pub fn print_interface(interface: &Interface, prefix: Option<&str>) {
-    println!("{}Number: {}", prefix.to_str(), interface.number());
-    for if_desc in interface.descriptors() {
-        print_interface_descriptor(&if_desc, prefix);
+pub fn print_interface(interface: &InterfaceTree, prefix: Option<&str>) {
+    println!("{}Number: {}", prefix.to_str(), interface.number);
+    for if_desc in &interface.alt_settings {
+        print_interface_descriptor(if_desc, prefix);
     }
 }
 
 #[allow(unused)]
-pub fn print_interface_descriptor(if_desc: &InterfaceDescriptor, prefix: Option<&str>) {
-    println!("{}Number: {}", prefix.to_str(), if_desc.interface_number());
-    println!("{}SettingNumber: {}", prefix.to_str(), if_desc.setting_number());
-    println!("{}ClassCode: {}", prefix.to_str(), if_desc.class_code());
-    println!("{}SubClassCode: {}", prefix.to_str(), if_desc.sub_class_code());
-    println!("{}ProtocolCode: {}", prefix.to_str(), if_desc.protocol_code());
-    println!("{}NumEndpoints: {}", prefix.to_str(), if_desc.num_endpoints());
-    for endpoint in if_desc.endpoint_descriptors() {
+pub fn print_interface_descriptor(if_desc: &AltSetting, prefix: Option<&str>) {
+    println!("{}Number: {}", prefix.to_str(), if_desc.number);
+    println!("{}SettingNumber: {}", prefix.to_str(), if_desc.setting_number);
+    println!("{}ClassCode: {}", prefix.to_str(), if_desc.class_code);
+    println!("{}SubClassCode: {}", prefix.to_str(), if_desc.sub_class_code);
+    println!("{}ProtocolCode: {}", prefix.to_str(), if_desc.protocol_code);
+    println!("{}NumEndpoints: {}", prefix.to_str(), if_desc.num_endpoints);
+    print_string("Description", &if_desc.description, prefix);
+    if let Some(ref hid) = if_desc.hid {
+        println!("{}Hid:", prefix.to_str());
+        print_hid(hid, Some(&(prefix.to_str().to_string() + "    ")));
+    }
+    for endpoint in &if_desc.endpoints {
         println!("{}Endpoint:", prefix.to_str());
-        print_endpoint(&endpoint, Some(&(prefix.to_str().to_string() + "    ")));
+        print_endpoint(endpoint, Some(&(prefix.to_str().to_string() + "    ")));
     }
 }
 
 #[allow(unused)]
-pub fn print_endpoint(endpoint: &EndpointDescriptor, prefix: Option<&str>) {
-    println!("{}Address: {}", prefix.to_str(), endpoint.address());
-    println!("{}Number: {}", prefix.to_str(), endpoint.number());
-    println!("{}Direction: {:?}", prefix.to_str(), endpoint.direction());
-    println!("{}TransferType: {:?}", prefix.to_str(), endpoint.transfer_type());
-    println!("{}SyncType: {:?}", prefix.to_str(), endpoint.sync_type());
-    println!("{}UsageType: {:?}", prefix.to_str(), endpoint.usage_type());
-    println!("{}MaxPacketSize: {}", prefix.to_str(), endpoint.max_packet_size());
-    println!("{}Interval: {}", prefix.to_str(), endpoint.interval());
+pub fn print_endpoint(endpoint: &Endpoint, prefix: Option<&str>) {
+    println!("{}Address: {}", prefix.to_str(), endpoint.address);
+    println!("{}Number: {}", prefix.to_str(), endpoint.number);
+    println!("{}Direction: {}", prefix.to_str(), endpoint.direction);
+    println!("{}TransferType: {}", prefix.to_str(), endpoint.transfer_type);
+    println!("{}SyncType: {}", prefix.to_str(), endpoint.sync_type);
+    println!("{}UsageType: {}", prefix.to_str(), endpoint.usage_type);
+    println!("{}MaxPacketSize: {}", prefix.to_str(), endpoint.max_packet_size);
+    println!("{}Interval: {}", prefix.to_str(), endpoint.interval);
 }
 
 #[allow(unused)]
-fn version_to_string(v: &Version) -> String {
+pub fn print_hid(hid: &Hid, prefix: Option<&str>) {
+    println!("{}HidVersion: {}", prefix.to_str(), hid.hid_version);
+    println!("{}CountryCode: {}", prefix.to_str(), hid.country_code);
+    println!("{}ReportDescriptorLength: {}", prefix.to_str(), hid.report_descriptor_length);
+    if let Some(ref report) = hid.report_descriptor {
+        let bytes: Vec<_> = report.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("{}ReportDescriptor: {}", prefix.to_str(), bytes.join(" "));
+    }
+}
+
+fn print_string(name: &str, string: &Option<String>, prefix: Option<&str>) {
+    if let Some(ref s) = *string {
+        println!("{}{}: {}", prefix.to_str(), name, s);
+    }
+}
+
+#[allow(unused)]
+pub fn version_to_string(v: &Version) -> String {
     let &Version(j, m, n) = v;
     return format!("v{}.{}.{}", j, m, n);
 }
 
 #[allow(unused)]
-pub fn print_cap(cap: &mut Capture<Offline>) {
-    while let Ok(packet) = cap.next() {
-        println!("Packet: {:?}", packet);
-        if packet.header.len < 64 {
-            unreachable!();
+pub fn print_cap(cap: &mut Capture) {
+    while let Some(packet) = cap.next() {
+        match packet {
+            Ok(p) => println!("{:?}", p),
+            Err(e) => println!("Error: {}", e),
         }
-        let p = Packet::from_bytes(&packet.data).unwrap();
-        println!("{:?}", p);
-
     }
 }
 
@@ -0,0 +1,244 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{ByteOrder, NativeEndian};
+use libusb::{Result as UsbResult, Error as UsbError};
+use transport::{self, Transport};
+use usb::{Packet, LinkType, TransferType, Direction, UrbStatus};
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+/// Snap length usbmon captures are written with
+const SNAPLEN: u32 = 0x40000;
+
+/// Writes usbmon packets into a classic pcap file with a
+/// `DLT_USB_LINUX_MMAPPED` link type in host byte order, like libpcap does
+/// when capturing from usbmon.
+pub struct Writer<W: Write> {
+    w: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut w: W) -> io::Result<Writer<W>> {
+        let mut header = [0u8; 24];
+        NativeEndian::write_u32(&mut header[0..4], PCAP_MAGIC);
+        NativeEndian::write_u16(&mut header[4..6], 2);
+        NativeEndian::write_u16(&mut header[6..8], 4);
+        // timezone offset and accuracy are 0
+        NativeEndian::write_u32(&mut header[16..20], SNAPLEN);
+        NativeEndian::write_u32(&mut header[20..24], LinkType::UsbLinuxMmapped.dlt() as u32);
+        try!(w.write_all(&header));
+        Ok(Writer { w: w })
+    }
+
+    pub fn write_packet(&mut self, packet: &Packet) -> io::Result<()> {
+        let data = packet.to_bytes();
+        let mut record = vec![0u8; 16];
+        NativeEndian::write_u32(&mut record[0..4], packet.get_sec() as u32);
+        NativeEndian::write_u32(&mut record[4..8], packet.get_usec());
+        NativeEndian::write_u32(&mut record[8..12], data.len() as u32);
+        NativeEndian::write_u32(&mut record[12..16], data.len() as u32);
+        record.extend_from_slice(&data);
+        self.w.write_all(&record)
+    }
+
+    #[allow(unused)]
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+/// Transport writing every transfer of the wrapped transport into a capture
+/// as Submit and Complete packets. Once a packet couldn't be written, all
+/// transfers fail with `Io`.
+///
+/// The transfers of the g910 crate's event loop don't go through a
+/// `Transport` and can't be recorded.
+pub struct Recorder<T: Transport, W: Write> {
+    transport: T,
+    writer: Writer<W>,
+    bus_id: u16,
+    device: u8,
+    next_id: u64,
+    /// Submits waiting for their completion
+    in_flight: Vec<Packet<'static>>,
+    /// First error writing the capture
+    error: Option<io::Error>,
+}
+
+impl<T: Transport> Recorder<T, File> {
+    /// Records into a new pcap file at `path`
+    pub fn create(transport: T, path: &Path) -> io::Result<Recorder<T, File>> {
+        Recorder::new(transport, try!(File::create(path)))
+    }
+}
+
+impl<T: Transport, W: Write> Recorder<T, W> {
+    pub fn new(transport: T, w: W) -> io::Result<Recorder<T, W>> {
+        Ok(Recorder {
+            transport: transport,
+            writer: try!(Writer::new(w)),
+            bus_id: 0,
+            device: 0,
+            next_id: 1,
+            in_flight: Vec::new(),
+            error: None,
+        })
+    }
+
+    /// Bus and device number written into the packets, 0 by default
+    pub fn set_address(&mut self, bus_id: u16, device: u8) {
+        self.bus_id = bus_id;
+        self.device = device;
+    }
+
+    /// Error which stopped the recording
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    #[allow(unused)]
+    pub fn into_inner(self) -> (T, W) {
+        (self.transport, self.writer.into_inner())
+    }
+
+    fn check(&self) -> UsbResult<()> {
+        match self.error {
+            Some(_) => Err(UsbError::Io),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, packet: &mut Packet) -> UsbResult<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+        packet.set_time(now.as_secs(), now.subsec_nanos() / 1000);
+        if let Err(e) = self.writer.write_packet(packet) {
+            self.error = Some(e);
+        }
+        self.check()
+    }
+
+    fn submit(&mut self, mut packet: Packet<'static>, res: UsbResult<()>) -> UsbResult<()> {
+        packet.set_address(self.bus_id, self.device);
+        self.next_id += 1;
+        try!(self.write(&mut packet));
+        match res {
+            Ok(()) => self.in_flight.push(packet),
+            Err(e) => {
+                let mut complete = packet.complete(UrbStatus::from_usb_error(e), 0, Vec::new());
+                try!(self.write(&mut complete));
+            },
+        }
+        res
+    }
+}
+
+impl<T: Transport, W: Write> Transport for Recorder<T, W> {
+    fn send_control(&mut self, endpoint_direction: u8, buf: Vec<u8>, request_type: u8,
+                    request: u8, value: u16, index: u16) -> UsbResult<()> {
+        try!(self.check());
+        let data = if endpoint_direction & 0x80 == 0 { buf.clone() } else { Vec::new() };
+        let mut packet = Packet::submit(self.next_id, TransferType::Control, endpoint_direction,
+                                        buf.len() as u32, data);
+        packet.set_setup(request_type, request, value, index, buf.len() as u16);
+        let res = self.transport.send_control(endpoint_direction, buf, request_type, request, value, index);
+        self.submit(packet, res)
+    }
+
+    fn send_interrupt(&mut self, endpoint_direction: u8, buf: Vec<u8>) -> UsbResult<()> {
+        try!(self.check());
+        let data = if endpoint_direction & 0x80 == 0 { buf.clone() } else { Vec::new() };
+        let packet = Packet::submit(self.next_id, TransferType::Interrupt, endpoint_direction,
+                                    buf.len() as u32, data);
+        let res = self.transport.send_interrupt(endpoint_direction, buf);
+        self.submit(packet, res)
+    }
+
+    fn recv(&mut self, endpoint_direction: u8) -> UsbResult<Vec<u8>> {
+        try!(self.check());
+        let res = self.transport.recv(endpoint_direction);
+        let pipe = transport::pipe(endpoint_direction);
+        let pos = self.in_flight.iter().position(|p| transport::pipe(p.get_endpoint_direction()) == pipe);
+        if let Some(pos) = pos {
+            let submit = self.in_flight.remove(pos);
+            let mut complete = match (&res, submit.get_direction()) {
+                (&Ok(ref data), Direction::In) =>
+                    submit.complete(UrbStatus::Success, data.len() as u32, data.clone()),
+                (&Ok(_), Direction::Out) => submit.complete(UrbStatus::Success, submit.get_length(), Vec::new()),
+                (&Err(e), _) => submit.complete(UrbStatus::from_usb_error(e), 0, Vec::new()),
+            };
+            try!(self.write(&mut complete));
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+    use libusb::Error as UsbError;
+    use capture::Capture;
+    use replay::{Control, Until, Stop};
+    use synth::Transfer;
+    use transaction;
+    use transport::{Transport, MockTransport};
+    use usb::UrbStatus;
+    use super::Recorder;
+
+    /// Accepts the pcap header and nothing else
+    struct Full(usize);
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 + buf.len() > 24 {
+                return Err(io::Error::new(io::ErrorKind::Other, "disk full"));
+            }
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_errors_fail_transfers() {
+        let mut recorder = Recorder::new(MockTransport::new(Vec::new()), Full(0)).unwrap();
+        assert_eq!(recorder.send_interrupt(0x81, vec![0; 8]), Err(UsbError::Io));
+        assert!(recorder.error().is_some());
+        assert_eq!(recorder.recv(0x81), Err(UsbError::Io));
+    }
+
+    #[test]
+    fn records_a_replay() {
+        let stalled = Transfer { status: UrbStatus::Pipe, ..Transfer::control(0xc0, 0xff, 0, 0, vec![0; 4]) };
+        let transfers = [Transfer::control(0x80, 0x06, 0x0100, 0, vec![18, 1, 0x00, 0x02]),
+                         Transfer::control(0x21, 0x09, 0x0211, 2, vec![0x11, 0xff, 0x0f, 0x3b]),
+                         stalled,
+                         Transfer::interrupt(0x02, vec![0x11, 0xff]),
+                         Transfer::interrupt(0x82, vec![0x11, 0xff, 0x0f])];
+        let transactions: Vec<_> = transfers.iter().enumerate()
+            .map(|(i, t)| t.to_transaction(i as u64 + 100, i as u64 * 1000))
+            .collect();
+        let path = env::temp_dir().join("usbtest-record-replay.pcap");
+        {
+            let recorder = Recorder::create(MockTransport::new(transactions.clone()), &path).unwrap();
+            let mut ctrl = Control::from_transactions(transactions.clone(), recorder);
+            assert_eq!(ctrl.replay_until(&Until::End, &[]), Stop::End);
+            assert_eq!(ctrl.report().totals.error_expected, 1);
+            assert!(ctrl.transport().error().is_none());
+        }
+        let packets = Capture::from_file(&path).unwrap().read_all().unwrap();
+        fs::remove_file(&path).unwrap();
+        let recorded = transaction::pair(packets);
+        assert_eq!(recorded.len(), transactions.len());
+        for (i, (r, t)) in recorded.iter().zip(&transactions).enumerate() {
+            // the recorder numbers the URBs itself
+            assert_eq!(r.id(), i as u64 + 1);
+            assert!(r.same(t), "transaction {}: {} != {}", i, r, t);
+        }
+    }
+}
@@ -1,10 +1,17 @@
-use pcap::{Capture, Offline};
+use capture::{Capture, CaptureError};
+use transaction::{self, Transaction};
+use std::collections::VecDeque;
 use std::path::Path;
-use libusb::{DeviceHandle, Result as UsbResult, Error as UsbError, Context, AsyncGroup, Transfer};
-use usb::{Packet, TransferType, UrbType, Direction};
-use std::time::Duration;
+use libusb::{DeviceHandle, Result as UsbResult, Error as UsbError, Context};
+use usb::{Packet, TransferType, UrbType, Direction, SetupRequest};
+use filter::Filter;
+use report::{Entry, Report};
+use rules::Rules;
+use transport::{self, Transport, LibusbTransport, MockTransport};
+use std::fmt;
 use std::io;
-use std::io::BufRead;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::u8;
 use std::str::FromStr;
 use g910::*;
@@ -14,15 +21,25 @@ type RecvResult = UsbResult<Vec<u8>>;
 
 #[derive(Debug, PartialEq)]
 struct PacketInfo {
+    /// Index of the transaction in the capture
+    index: usize,
+    submit: Packet<'static>,
     req_len: usize,
-    b_request: u8,
+    request: Option<SetupRequest>,
+    /// Recorded completion of the sent packet
+    expected: Option<Packet<'static>>,
+    sent: Instant,
 }
 
 impl PacketInfo {
-    fn new(req_len: usize, b_request: u8) -> PacketInfo {
+    fn new(index: usize, submit: Packet<'static>, req_len: usize, expected: Option<Packet<'static>>) -> PacketInfo {
         PacketInfo {
+            index: index,
+            request: submit.get_request(),
+            submit: submit,
             req_len: req_len,
-            b_request: b_request,
+            expected: expected,
+            sent: Instant::now(),
         }
     }
 }
@@ -36,45 +53,26 @@ enum SendResponse {
 enum SendResponseError {
     Error { packet_info: PacketInfo, err: UsbError },
     InvalidParam,
+    /// `Transport` has no Bulk and Isochronous transfers
+    Unsupported(TransferType),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ReplayCompare {
-    Correct(u8),
-    ErrorExpected(u8),
+    Correct(Option<SetupRequest>),
+    ErrorExpected(Option<SetupRequest>),
     Dropped,
     Incorrect,
 }
 
-struct Replay<'a> {
-    handle: &'a DeviceHandle<'a>,
-    async_group: AsyncGroup<'a>,
+struct Replay<T: Transport> {
+    transport: T,
     // TODO: use this flag
     handshake_done: bool,
-    timeout: Duration,
 }
 
-impl<'a> Replay<'a> {
-    pub fn send_control(&mut self, endpoint_direction: u8, buf: Vec<u8>, request_type: u8,
-                        request: u8, value:u16, index: u16) ->  UsbResult<()> {
-        println!("Initiating control packet...");
-        self.async_group.submit(Transfer::control(
-                self.handle,
-                endpoint_direction,
-                buf,
-                request_type,
-                request,
-                value,
-                index,
-                self.timeout
-        ))
-    }
-
-    pub fn send_interrupt(&mut self, endpoint_direction: u8, buf: Vec<u8>) -> UsbResult<()> {
-        self.async_group.submit(Transfer::interrupt(self.handle, endpoint_direction, buf, self.timeout))
-    }
-
-    pub fn send_packet(&mut self, req: Packet) -> SendResult {
+impl<T: Transport> Replay<T> {
+    pub fn send_packet(&mut self, index: usize, req: Packet<'static>, expected: Option<Packet<'static>>) -> SendResult {
         if req.get_urb_type() != UrbType::Submit {
             return Err(SendResponseError::InvalidParam);
         }
@@ -89,212 +87,559 @@ impl<'a> Replay<'a> {
                 }
                 len = buf.len();
                 println!("Initiating control packet...");
-                self.send_control(
+                self.transport.send_control(
                         req.get_endpoint_direction(),
                         buf,
                         req.get_bm_request_type(),
                         req.get_b_request(),
                         req.get_value(),
-                        req.get_language_id(),
+                        req.get_index(),
                 )
             },
             TransferType::Interrupt => {
                 println!("Initiating interrupt packet on iface {}...",  req.get_endpoint());
-                len = req.get_length() as usize;
-                buf.resize(len, 0u8);
+                if req.get_direction() == Direction::In {
+                    buf.resize(req.get_length() as usize, 0u8);
+                } else {
+                    buf.extend_from_slice(req.get_data());
+                }
+                len = buf.len();
                 let endpoint_direction = req.get_endpoint_direction();
-                self.send_interrupt(endpoint_direction, buf)
+                self.transport.send_interrupt(endpoint_direction, buf)
             }
-            _ => unimplemented!()
+            transfer_type => return Err(SendResponseError::Unsupported(transfer_type)),
         };
+        let packet_info = PacketInfo::new(index, req, len, expected);
         match res {
-            Ok(_) => Ok(SendResponse::Success { packet_info: PacketInfo::new(len, req.get_b_request()) }),
-            Err(err) => Err(SendResponseError::Error { packet_info:
-                PacketInfo::new(len, req.get_b_request()), err: err })
+            Ok(_) => Ok(SendResponse::Success { packet_info: packet_info }),
+            Err(err) => Err(SendResponseError::Error { packet_info: packet_info, err: err })
+        }
+    }
+
+    fn recv(&mut self, endpoint_direction: u8) -> RecvResult {
+        self.transport.recv(endpoint_direction)
+    }
+}
+
+/// `bRequest` of HID SET_REPORT
+pub const SET_REPORT: u8 = 0x09;
+/// `bRequest` of HID SET_IDLE
+pub const SET_IDLE: u8 = 0x0a;
+
+/// Where `Control::replay_until` stops
+#[derive(Debug, Clone, PartialEq)]
+pub enum Until {
+    /// Before the transaction with this index
+    Index(usize),
+    /// After this many transactions
+    Count(usize),
+    /// After a control transfer with this `bRequest`
+    Request(u8),
+    /// After a transaction whose Submit matches
+    Filter(Filter),
+    /// After the last transaction
+    End,
+}
+
+/// Why `Control::replay_until` stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    Reached,
+    /// Before a control transfer with this `bRequest`
+    Breakpoint(u8),
+    /// The response differs from the recorded one
+    Mismatch,
+    Error(UsbError),
+    /// All transactions were replayed
+    End,
+}
+
+/// How far a timed replay lagged behind the recorded timing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Drift {
+    /// Number of transactions sent on schedule
+    pub count: usize,
+    /// Sum of how late the transactions were sent, in microseconds
+    pub total: u64,
+    /// The transaction sent latest relative to its schedule and how late it
+    /// was, in microseconds
+    pub max: (usize, u64),
+    /// How late the last transaction was sent, in microseconds
+    pub last: u64,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.count == 0 {
+            return write!(f, "Drift: no transactions were timed");
         }
+        write!(f, "Drift over {} transactions: mean {}µs, max {}µs at transaction {}, last {}µs",
+               self.count, self.total / self.count as u64, self.max.1, self.max.0, self.last)
     }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_nanos() as u64 / 1000
+}
+
+/// Capture timestamp of a packet in microseconds
+fn recorded_at(p: &Packet) -> u64 {
+    p.get_sec() * 1_000_000 + p.get_usec() as u64
+}
 
-    fn recv(&mut self) -> RecvResult {
-        Ok(try!(self.async_group.wait_any()).actual().iter().cloned().collect())
+/// Keeps the recorded gaps between Submits, multiplied by `scale`
+struct Timing {
+    scale: f64,
+    /// Start of the schedule and the capture timestamp it corresponds to
+    start: Option<(Instant, u64)>,
+    drift: Drift,
+}
+
+impl Timing {
+    /// Sleeps until the transaction `index` recorded at `timestamp` is due.
+    /// The first transaction after a resync starts the schedule.
+    fn wait(&mut self, index: usize, timestamp: u64) {
+        let (start, recorded) = match self.start {
+            Some(start) => start,
+            None => {
+                self.start = Some((Instant::now(), timestamp));
+                return;
+            }
+        };
+        let due = (timestamp.saturating_sub(recorded) as f64 * self.scale) as u64;
+        let elapsed = micros(start.elapsed());
+        if due > elapsed {
+            let wait = due - elapsed;
+            thread::sleep(Duration::new(wait / 1_000_000, (wait % 1_000_000) as u32 * 1000));
+        }
+        let late = micros(start.elapsed()).saturating_sub(due);
+        self.drift.count += 1;
+        self.drift.total += late;
+        self.drift.last = late;
+        if late >= self.drift.max.1 {
+            self.drift.max = (index, late);
+        }
+    }
+}
+
+pub struct Control<T: Transport> {
+    transactions: VecDeque<Transaction>,
+    replay: Replay<T>,
+    /// Index of the next transaction in the capture
+    index: usize,
+    /// Last sent transaction
+    last: Option<Transaction>,
+    /// Sent transfers waiting to be received, in the order they were sent
+    in_flight: Vec<PacketInfo>,
+    /// Index, transaction and result of the last received transfer
+    compared: Option<(usize, Transaction, RecvResult)>,
+    timing: Option<Timing>,
+    /// Decide which responses are correct
+    rules: Rules,
+    report: Report,
+}
+
+impl<'a> Control<LibusbTransport<'a>> {
+    #[allow(unused)]
+    pub fn new(path: &Path, context: &'a Context, handle: &'a DeviceHandle<'a>)
+            -> Result<Control<LibusbTransport<'a>>, CaptureError> {
+        let transport = LibusbTransport::new(context, handle, Duration::from_secs(10));
+        Control::with_transport(path, transport)
     }
 }
 
-pub struct Control<'a> {
-    cap: Capture<Offline>,
-    replay: Replay<'a>,
+impl Control<MockTransport> {
+    /// Replays `path` against a device simulated from the capture `device`
+    #[allow(unused)]
+    pub fn mock(path: &Path, device: &Path) -> Result<Control<MockTransport>, CaptureError> {
+        Control::with_transport(path, try!(MockTransport::from_file(device)))
+    }
 }
 
-impl<'a> Control<'a> {
-    pub fn new(path: &Path, context: &'a Context, handle: &'a DeviceHandle<'a>) -> Control<'a> {
+impl<T: Transport> Control<T> {
+    pub fn with_transport(path: &Path, transport: T) -> Result<Control<T>, CaptureError> {
+        let packets = try!(try!(Capture::from_file(path)).read_all());
+        Ok(Control::from_transactions(transaction::pair(packets), transport))
+    }
+
+    /// Replays transactions which weren't read from a capture, e.g.
+    /// synthesized ones
+    pub fn from_transactions(transactions: Vec<Transaction>, transport: T) -> Control<T> {
         Control {
-            cap: Capture::from_file(path).unwrap(),
-            replay: Replay{
-                handle: handle,
-                async_group: AsyncGroup::new(context),
+            transactions: transactions.into_iter().collect(),
+            replay: Replay {
+                transport: transport,
                 handshake_done: false,
-                timeout: Duration::from_secs(10),
-            }
+            },
+            index: 0,
+            last: None,
+            in_flight: Vec::new(),
+            compared: None,
+            timing: None,
+            rules: Rules::default(),
+            report: Report::default(),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn transport(&self) -> &T {
+        &self.replay.transport
+    }
+
+    /// Returns true if all transactions were replayed and received
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty() && self.in_flight.is_empty()
+    }
+
+    /// Number of transactions left to replay
+    pub fn remaining(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Index of the next transaction in the capture
+    pub fn position(&self) -> usize {
+        self.index
+    }
+
+    /// The next transaction to be replayed
+    pub fn peek(&self) -> Option<&Transaction> {
+        self.transactions.front()
+    }
+
+    /// Number of sent transactions which weren't received yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// The last received transaction, its index and the data or error
+    /// received for it
+    pub fn last(&self) -> Option<(usize, &Transaction, &RecvResult)> {
+        self.compared.as_ref().map(|&(i, ref t, ref r)| (i, t, r))
+    }
+
+    /// Results of all transactions replayed so far
+    pub fn report(&self) -> &Report {
+        &self.report
+    }
+
+    /// Compares responses with `rules` instead of byte for byte
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.rules = rules;
+    }
+
+    /// Sends transactions with the gaps between their recorded Submits
+    /// multiplied by `scale` instead of as fast as possible
+    pub fn set_timing(&mut self, scale: f64) {
+        self.timing = Some(Timing { scale: scale, start: None, drift: Drift::default() });
+    }
+
+    /// Restarts the schedule of a timed replay at the next transaction,
+    /// e.g. after pausing
+    pub fn resync(&mut self) {
+        if let Some(ref mut timing) = self.timing {
+            timing.start = None;
         }
     }
 
+    /// How far a timed replay drifted from the recorded timing
+    pub fn drift(&self) -> Option<Drift> {
+        self.timing.as_ref().map(|t| t.drift)
+    }
+
+    /// Skips the next `count` transactions, at most the remaining ones.
     pub fn skip(&mut self, count: u8) {
         for _ in 0..count {
-            self.cap.next().unwrap();
+            if self.transactions.pop_front().is_none() {
+                return;
+            }
+            self.index += 1;
         }
     }
 
+    /// Puts the last replayed transaction back to be sent again. Fails if
+    /// it wasn't received yet.
+    pub fn rewind(&mut self) -> Result<(), String> {
+        if self.last.is_none() {
+            return Err("nothing was sent yet".to_string());
+        }
+        if self.in_flight.iter().any(|p| p.index + 1 == self.index) {
+            return Err("the last transaction is still in flight".to_string());
+        }
+        self.transactions.push_front(self.last.take().unwrap());
+        self.index -= 1;
+        Ok(())
+    }
+
+    /// Overwrites the payload of the next transaction starting at `offset`
+    pub fn edit_next(&mut self, offset: usize, bytes: &[u8]) -> Result<(), String> {
+        let submit = match self.transactions.front_mut().and_then(|t| t.submit_mut()) {
+            Some(submit) => submit,
+            None => return Err("the next transaction has no Submit".to_string()),
+        };
+        if submit.get_direction() != Direction::Out {
+            return Err("only OUT transfers carry a payload".to_string());
+        }
+        let mut data = submit.get_data().to_vec();
+        if offset + bytes.len() > data.len() {
+            return Err(format!("the payload is only {} bytes long", data.len()));
+        }
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        submit.set_data(data);
+        Ok(())
+    }
+
     fn send_next(&mut self) -> SendResult {
-        let &mut Control { ref mut cap, ref mut replay } = self;
-        let req = Packet::from_bytes(cap.next().unwrap().data).unwrap();
-        if req.get_urb_type() != UrbType::Submit {
-            println!("dropped (incorrect?) packet: {:?}", req);
-            return Ok(SendResponse::Dropped);
+        let (req, expected) = match self.transactions.pop_front() {
+            Some(t) => {
+                if let (Some(timing), true) = (self.timing.as_mut(), t.submit().is_some()) {
+                    timing.wait(self.index, t.timestamp());
+                }
+                self.index += 1;
+                self.last = Some(t.clone());
+                t.into_parts()
+            },
+            None => return Err(SendResponseError::InvalidParam),
+        };
+        match req {
+            Some(req) => self.replay.send_packet(self.index - 1, req, expected),
+            None => {
+                println!("dropped completion without submit: {:?}", expected);
+                Ok(SendResponse::Dropped)
+            }
         }
-        replay.send_packet(req)
     }
 
+    /// Prints `entry` as a row of the report and adds it
+    fn push_entry(&mut self, entry: Entry) {
+        let _ = entry.write_row(&mut io::stdout(), 0);
+        self.report.push(entry);
+    }
+
+    /// Compares the response with the recorded one and adds the result to
+    /// the report
     fn compare_next(&mut self, send: SendResult, recv: RecvResult) -> UsbResult<ReplayCompare> {
-        let buf = try!(recv);
+        let info = match send {
+            Ok(SendResponse::Success { ref packet_info }) | Err(SendResponseError::Error { ref packet_info, .. }) =>
+                Some((packet_info.index, Transaction::new(Some(packet_info.submit.clone()), packet_info.expected.clone()),
+                      packet_info.sent.elapsed())),
+            _ => None,
+        };
+        let res = self.compare(send, recv.clone());
+        if let Some((index, t, latency)) = info {
+            self.push_entry(Entry::new(index, &t, Some(&recv), Some(latency), &res));
+            self.compared = Some((index, t, recv));
+        }
+        res
+    }
+
+    fn compare(&mut self, send: SendResult, recv: RecvResult) -> UsbResult<ReplayCompare> {
+        let buf = match (recv, &send) {
+            (Ok(buf), _) => buf,
+            // asynchronous transfers report stalls on completion
+            (Err(err), &Ok(SendResponse::Success { ref packet_info })) => {
+                let expected = match packet_info.expected {
+                    Some(ref e) => e,
+                    None => return Err(err),
+                };
+                let correct = expected.get_status() == err;
+                return if correct { Ok(ReplayCompare::ErrorExpected(packet_info.request)) } else { Err(err) };
+            },
+            // the submit itself failed
+            (Err(_), &Err(_)) => Vec::new(),
+            (Err(err), _) => return Err(err),
+        };
         match send {
             Ok(send_response) => {
-                let expected = Packet::from_bytes(self.cap.next().unwrap().data).unwrap();
                 match send_response {
                     SendResponse::Success { packet_info } => {
-                        let PacketInfo { req_len, b_request } = packet_info;
-                        let correct = expected.get_data() == &buf[..];
-                        if req_len != buf.len() {
-                            println!("Requested {} but only received {}", req_len, buf.len());
-                        }
-                        println!("Result correct: {}", correct);
-                        if correct {
-                            Ok(ReplayCompare::Correct(b_request))
+                        let PacketInfo { index, submit, request, expected, .. } = packet_info;
+                        let expected = match expected {
+                            Some(e) => e,
+                            None => return Ok(ReplayCompare::Incorrect),
+                        };
+                        if self.rules.matches(index, Some(&submit), expected.get_data(), &buf) {
+                            Ok(ReplayCompare::Correct(request))
                         } else {
-                            println!("{:?}", expected.get_data());
-                            println!("{:?}", buf);
                             Ok(ReplayCompare::Incorrect)
                         }
                     },
-                    SendResponse::Dropped => {
-                        println!("Packet dropped");
-                        Ok(ReplayCompare::Dropped)
-                    }
+                    SendResponse::Dropped => Ok(ReplayCompare::Dropped),
                 }
             }
             Err(SendResponseError::Error { packet_info, err }) => {
-                let expected = Packet::from_bytes(self.cap.next().unwrap().data).unwrap();
-                let correct = expected.get_status() == err;
-                println!("{}, expected {:?}, correct: {}", err, expected.get_status(), correct);
-                if correct {
-                    Ok(ReplayCompare::ErrorExpected(packet_info.b_request))
+                let expected = match packet_info.expected {
+                    Some(e) => e,
+                    None => return Err(err),
+                };
+                if expected.get_status() == err {
+                    Ok(ReplayCompare::ErrorExpected(packet_info.request))
                 } else {
                     Err(err)
                 }
             },
-            Err(SendResponseError::InvalidParam) => {
-                println!("got invalid param");
-                Err(UsbError::InvalidParam)
+            Err(SendResponseError::InvalidParam) => Err(UsbError::InvalidParam),
+            Err(SendResponseError::Unsupported(_)) => Err(UsbError::NotSupported),
+        }
+    }
+
+    /// Sends the next transaction and receives the transfers in flight
+    /// which completed before the one after it was submitted in the
+    /// capture. Once all transactions were sent, the remaining transfers in
+    /// flight are received.
+    pub fn replay_compare_next(&mut self) -> UsbResult<Vec<ReplayCompare>> {
+        let mut results = Vec::new();
+        if !self.transactions.is_empty() || self.in_flight.is_empty() {
+            if let Some(res) = self.submit_next() {
+                results.push(try!(res));
             }
         }
+        while let Some(res) = self.receive_due(false) {
+            results.push(try!(res));
+        }
+        Ok(results)
+    }
+
+    /// Sends the next transaction, keeping it in flight if it was submitted
+    fn submit_next(&mut self) -> Option<UsbResult<ReplayCompare>> {
+        match self.send_next() {
+            Ok(SendResponse::Success { packet_info }) => {
+                self.in_flight.push(packet_info);
+                None
+            },
+            // nothing is in flight to be received
+            Ok(SendResponse::Dropped) => {
+                let res = Ok(ReplayCompare::Dropped);
+                if let Some(entry) = self.last.as_ref().map(|t| Entry::new(self.index - 1, t, None, None, &res)) {
+                    self.push_entry(entry);
+                }
+                Some(res)
+            },
+            send => {
+                let err = match send {
+                    Err(SendResponseError::Error { err, .. }) => err,
+                    _ => UsbError::InvalidParam,
+                };
+                Some(self.compare_next(send, Err(err)))
+            },
+        }
     }
 
-    pub fn replay_compare_next(&mut self) -> UsbResult<ReplayCompare> {
-        let send = self.send_next();
-        let res = self.replay.recv();
-        self.compare_next(send, res)
+    /// Receives the transfer which completed first in the capture if it
+    /// completed before the next transaction was submitted, or any transfer
+    /// if all were sent or `all` is set. Each pipe is received in the order
+    /// it was sent on, so completions are matched to their own Submits.
+    fn receive_due(&mut self, all: bool) -> Option<UsbResult<ReplayCompare>> {
+        let next = if all { None } else { self.peek().map(|t| t.timestamp()) };
+        let pipe = match self.in_flight.iter()
+                .map(|p| (p.expected.as_ref().map_or(u64::max_value(), |e| recorded_at(e)), p))
+                .filter(|&(at, _)| next.map_or(true, |next| at <= next))
+                .min_by_key(|&(at, _)| at) {
+            Some((_, p)) => transport::pipe(p.submit.get_endpoint_direction()),
+            None => return None,
+        };
+        let pos = self.in_flight.iter().position(|p| transport::pipe(p.submit.get_endpoint_direction()) == pipe).unwrap();
+        let packet_info = self.in_flight.remove(pos);
+        let recv = self.replay.recv(packet_info.submit.get_endpoint_direction());
+        Some(self.compare_next(Ok(SendResponse::Success { packet_info: packet_info }), recv))
     }
 
     fn listen_iface2(&mut self) -> UsbResult<()> {
         let mut vec = Vec::new();
         vec.resize(64, 0u8);
-        self.replay.send_interrupt(0x82, vec)
+        self.replay.transport.send_interrupt(0x82, vec)
     }
     
-    pub fn replay_stop<F>(&mut self, mut stop: F) -> UsbResult<()>
-            where F: FnMut(&ReplayCompare) -> bool {
-        let stdin = io::stdin();
-        let mut halt = false;
-        //for _ in stdin.lock().lines() {
-        for i in 0.. {
-            if halt {
-                match stdin.lock().read_line(&mut String::new()) {
-                    Ok(_) => {},
-                    Err(_) => break
+    /// Replays transactions until `until` is reached, a response differs
+    /// or a control transfer with a `bRequest` of `breakpoints` is next.
+    /// The first transaction never stops at a breakpoint, so replay can be
+    /// continued from one. Transfers in flight are received before stopping
+    /// at `until`.
+    pub fn replay_until(&mut self, until: &Until, breakpoints: &[u8]) -> Stop {
+        let mut count = 0;
+        loop {
+            let submit = match self.peek() {
+                Some(t) => t.submit().cloned(),
+                None if self.in_flight.is_empty() => return Stop::End,
+                None => None,
+            };
+            if let Until::Index(i) = *until {
+                if self.index >= i {
+                    return self.drain();
                 }
-                halt = false;
             }
-            println!("{}:", i);
-            match self.replay_compare_next() {
-                Ok(ok) => {
-                    if stop(&ok) {
-                        return Ok(());
-                    }
-                    match ok {
-                        ReplayCompare::Correct(_) => {},
-                        ReplayCompare::ErrorExpected(_) => {},
-                        ReplayCompare::Dropped => {},
-                        ReplayCompare::Incorrect => {
-                            println!("Maybe incorrect???");
-                            halt = true;
-                        },
-                    }
-                }
-                // real error during execution
-                Err(e) => {
-                    println!("Error replaying packet: {}", e);
-                    halt = true;
+            let request = submit.as_ref().and_then(|s| match s.get_transfer_type() {
+                TransferType::Control => Some(s.get_b_request()),
+                _ => None,
+            });
+            if let Some(b) = request {
+                if count > 0 && breakpoints.contains(&b) {
+                    return Stop::Breakpoint(b);
                 }
             }
+            println!("{}:", self.index);
+            match self.replay_compare_next() {
+                Ok(ref results) if results.contains(&ReplayCompare::Incorrect) => return Stop::Mismatch,
+                Ok(_) => {},
+                Err(e) => return Stop::Error(e),
+            }
+            count += 1;
+            let reached = match *until {
+                Until::Count(n) => count >= n,
+                Until::Request(r) => request == Some(r),
+                Until::Filter(ref f) => submit.map_or(false, |s| f.matches(&s)),
+                Until::Index(_) | Until::End => false,
+            };
+            if reached {
+                return self.drain();
+            }
         }
-        Ok(())
     }
 
+    /// Receives all transfers in flight, so everything sent before stopping
+    /// is compared
+    fn drain(&mut self) -> Stop {
+        while let Some(res) = self.receive_due(true) {
+            match res {
+                Ok(ReplayCompare::Incorrect) => return Stop::Mismatch,
+                Ok(_) => {},
+                Err(e) => return Stop::Error(e),
+            }
+        }
+        Stop::Reached
+    }
+
+    /// Replays everything, continuing after differing responses
     #[allow(unused)]
     pub fn replay_all(&mut self) -> UsbResult<()> {
-        self.replay_stop(|_| false)
+        loop {
+            match self.replay_until(&Until::End, &[]) {
+                Stop::End => return Ok(()),
+                Stop::Error(e) => return Err(e),
+                _ => {},
+            }
+        }
     }
 
-    pub fn replay_basic_handshake(&mut self) -> UsbResult<()> {
+    /// Where the basic handshake ends: after the SET_IDLE of both
+    /// interfaces. Fails if the handshake was replayed already.
+    pub fn basic_handshake(&mut self) -> UsbResult<Vec<Until>> {
         if self.replay.handshake_done {
             return Err(UsbError::InvalidParam);
         }
         self.replay.handshake_done = true;
-        let mut i = 0;
-        try!(self.replay_stop(move |p| {
-            println!("{:?}", p);
-            println!("i: {}", i);
-            if *p != ReplayCompare::Correct(0x0a) {
-                i += 1;
-                if i >= 2 {
-                    return true;
-                }
-            }
-            false
-        }));
-        Ok(())
+        Ok(vec![Until::Request(SET_IDLE), Until::Request(SET_IDLE)])
     }
 
-    pub fn replay_handshake(&mut self) -> UsbResult<()> {
-        try!(self.replay_basic_handshake());
-        // GET DESCRIPTOR HID Report
-        try!(self.replay_compare_next());
-        // INTERRUP in iface 2
-        let send2 = self.send_next();
-
-        // continue replay until after the first SET_REPORT packet
-        try!(self.replay_stop(|p| {
-            println!("in fn: {:?}", p);
-            *p == ReplayCompare::Correct(0x09)
-        }));
-        // next one should be the response on iface 2
-        let recv = self.replay.recv();
-        self.compare_next(send2, recv).unwrap();
-
-        Ok(())
+    /// Where the handshake ends: after the basic handshake and the first
+    /// SET_REPORT. The interrupt IN on iface 2 is received before stopping.
+    pub fn handshake(&mut self) -> UsbResult<Vec<Until>> {
+        let mut until = try!(self.basic_handshake());
+        until.push(Until::Request(SET_REPORT));
+        Ok(until)
     }
 
     pub fn test(&mut self) -> UsbResult<()> {
         ////try!(self.replay_basic_handshake());
-        //self.replay.timeout = Duration::from_secs(1);
         //try!(self.set_all_colors(Color::new(0,0x65,0xbd)));
         ////let streams = [
             ////"11ff0f4b00040000000000000000000000000000",
@@ -330,5 +675,162 @@ impl<'a> Control<'a> {
 
         Ok(())
     }
-} 
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+    use capture::Capture;
+    use report::{Report, Verdict};
+    use synth::Transfer;
+    use libusb::{Result as UsbResult, Error as UsbError};
+    use transport::{MockTransport, Script};
+    use transaction;
+    use usb::TransferType;
+    use super::{Control, Until, Stop};
+
+    /// Replays the capture `path` against a mock answering from `device`
+    fn replay(path: &str, device: &str) -> Report {
+        let mut ctrl = Control::mock(Path::new(path), Path::new(device)).unwrap();
+        while !ctrl.is_empty() {
+            let _ = ctrl.replay_compare_next();
+        }
+        ctrl.report().clone()
+    }
+
+    #[test]
+    fn handshakes_replay_correctly() {
+        for name in &["handshake", "handshake2", "handshake3", "handshake4", "handshake5"] {
+            let path = format!("pcap/g910/handshake/{}.pcap", name);
+            let report = replay(&path, &path);
+            assert!(report.totals.correct > 0, "{}: {}", path, report.summary());
+            // only transfers whose completion wasn't captured can't be answered
+            for e in report.entries.iter().filter(|e| e.verdict == Verdict::Incorrect) {
+                assert_eq!(e.expected_status, None, "{}: transaction {} is incorrect", path, e.index);
+            }
+        }
+    }
+
+    #[test]
+    fn other_device_is_incorrect() {
+        let report = replay("pcap/g910/handshake/handshake2.pcap", "pcap/g602/handshake/handshake.pcap");
+        assert!(report.totals.incorrect > 0, "{}", report.summary());
+    }
+
+    /// An interrupt IN which completes after the following control transfer
+    fn slow_interrupt() -> Control<MockTransport> {
+        let mut interrupt = Transfer::interrupt(0x81, vec![1, 2]);
+        interrupt.latency = 5000;
+        let control = Transfer::control(0x80, 0x06, 0x0100, 0, vec![18, 1]);
+        let transactions = vec![interrupt.to_transaction(1, 1000), control.to_transaction(2, 2000)];
+        Control::from_transactions(transactions.clone(), MockTransport::new(transactions))
+    }
+
+    #[test]
+    fn reached_receives_transfers_in_flight() {
+        let mut ctrl = slow_interrupt();
+        assert_eq!(ctrl.replay_until(&Until::Count(1), &[]), Stop::Reached);
+        assert_eq!(ctrl.in_flight(), 0);
+        assert_eq!(ctrl.report().totals.correct, 1);
+    }
+
+    #[test]
+    fn rewind_refuses_transfers_in_flight() {
+        let mut ctrl = slow_interrupt();
+        ctrl.replay_compare_next().unwrap();
+        assert_eq!(ctrl.in_flight(), 1);
+        assert!(ctrl.rewind().is_err());
+        assert_eq!(ctrl.replay_until(&Until::Count(1), &[]), Stop::Reached);
+        assert!(ctrl.rewind().is_ok());
+        assert_eq!(ctrl.position(), 1);
+    }
+
+    /// Remembers the data of interrupt transfers
+    #[derive(Default)]
+    struct Sent(Vec<Vec<u8>>);
 
+    impl Script for Sent {
+        fn control(&mut self, _: u8, _: &[u8], _: u8, _: u8, _: u16, _: u16) -> UsbResult<Vec<u8>> {
+            Err(UsbError::Pipe)
+        }
+
+        fn interrupt(&mut self, _: u8, buf: &[u8]) -> UsbResult<Vec<u8>> {
+            self.0.push(buf.to_vec());
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn out_interrupts_send_their_payload() {
+        let transactions = vec![Transfer::interrupt(0x02, vec![0x11, 0xff, 0x0f]).to_transaction(1, 0)];
+        let mut ctrl = Control::from_transactions(transactions, MockTransport::with_script(Sent::default()));
+        assert_eq!(ctrl.replay_until(&Until::End, &[]), Stop::End);
+        assert_eq!(ctrl.transport().script().0, vec![vec![0x11, 0xff, 0x0f]]);
+    }
+
+    #[test]
+    fn bulk_transfers_are_not_supported() {
+        let bulk = Transfer { transfer_type: TransferType::Bulk, ..Transfer::interrupt(0x81, vec![1]) };
+        let transactions = vec![bulk.to_transaction(1, 0)];
+        let mut ctrl = Control::from_transactions(transactions.clone(), MockTransport::new(transactions));
+        assert_eq!(ctrl.replay_until(&Until::End, &[]), Stop::Error(UsbError::NotSupported));
+        assert_eq!(ctrl.replay_until(&Until::End, &[]), Stop::End);
+    }
+
+    /// Interrupts recorded `gap` microseconds apart
+    fn timed_interrupts(count: u64, gap: u64) -> Control<MockTransport> {
+        let transactions: Vec<_> = (0..count)
+            .map(|i| Transfer::interrupt(0x81, vec![i as u8]).to_transaction(i + 1, i * gap))
+            .collect();
+        Control::from_transactions(transactions.clone(), MockTransport::new(transactions))
+    }
+
+    #[test]
+    fn unscaled_timing_measures_drift() {
+        let path = "pcap/g910/handshake/handshake2.pcap";
+        let packets = Capture::from_file(path).unwrap().read_all().unwrap();
+        let timed = transaction::pair(packets).iter().filter(|t| t.submit().is_some()).count();
+        let mut ctrl = Control::mock(Path::new(path), Path::new(path)).unwrap();
+        ctrl.set_timing(0.0);
+        let start = Instant::now();
+        while !ctrl.is_empty() {
+            let _ = ctrl.replay_compare_next();
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let drift = ctrl.drift().unwrap();
+        // the first transaction starts the schedule
+        assert_eq!(drift.count, timed - 1);
+        assert!(drift.max.0 > 0 && drift.max.0 < ctrl.position());
+        assert!(drift.max.1 >= drift.last && drift.max.1 <= drift.total);
+    }
+
+    #[test]
+    fn timing_scales_the_recorded_gaps() {
+        let mut ctrl = timed_interrupts(3, 100_000);
+        ctrl.set_timing(0.5);
+        let start = Instant::now();
+        assert_eq!(ctrl.replay_until(&Until::End, &[]), Stop::End);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(ctrl.drift().unwrap().count, 2);
+        assert_eq!(ctrl.report().totals.correct, 3);
+    }
+
+    #[test]
+    fn resync_restarts_the_schedule() {
+        let mut ctrl = timed_interrupts(2, 60_000_000);
+        assert!(ctrl.drift().is_none());
+        ctrl.set_timing(1.0);
+        let start = Instant::now();
+        assert_eq!(ctrl.replay_until(&Until::Count(1), &[]), Stop::Reached);
+        ctrl.resync();
+        assert_eq!(ctrl.replay_until(&Until::End, &[]), Stop::End);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(ctrl.drift().unwrap().count, 0);
+    }
+
+    #[test]
+    fn missing_capture_fails() {
+        assert!(Control::mock(Path::new("pcap/missing.pcap"), Path::new("pcap/missing.pcap")).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::cmp;
+use std::io::{self, Write};
+use std::time::Duration;
+use libusb::Result as UsbResult;
+use rustc_serialize::json;
+use print::Format;
+use replay::ReplayCompare;
+use transaction::Transaction;
+use usb::UrbStatus;
+
+/// Outcome of replaying one transaction
+#[derive(Debug, Clone, Copy, PartialEq, RustcEncodable)]
+pub enum Verdict {
+    Correct,
+    /// Failed like the recorded transaction
+    ErrorExpected,
+    /// Completion without Submit, nothing was sent
+    Dropped,
+    Incorrect,
+}
+
+/// A replayed transaction, payloads are hex strings
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct Entry {
+    /// Index of the transaction in the capture
+    pub index: usize,
+    /// Transfer type and endpoint, e.g. `Interrupt 0x82`
+    pub transfer: String,
+    pub request: Option<String>,
+    pub expected_status: Option<String>,
+    pub actual_status: Option<String>,
+    pub expected_data: String,
+    pub actual_data: String,
+    pub recorded_latency_us: Option<u64>,
+    pub latency_us: Option<u64>,
+    pub verdict: Verdict,
+}
+
+fn hex(data: &[u8]) -> String {
+    let bytes: Vec<_> = data.iter().map(|b| format!("{:02x}", b)).collect();
+    bytes.join(" ")
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_nanos() as u64 / 1000
+}
+
+impl Entry {
+    /// Entry of the transaction `index` which received `received` after
+    /// `latency` and was judged `res`. Unexpected errors are `Incorrect`.
+    pub fn new(index: usize, t: &Transaction, received: Option<&UsbResult<Vec<u8>>>,
+               latency: Option<Duration>, res: &UsbResult<ReplayCompare>) -> Entry {
+        let verdict = match *res {
+            Ok(ReplayCompare::Correct(_)) => Verdict::Correct,
+            Ok(ReplayCompare::ErrorExpected(_)) => Verdict::ErrorExpected,
+            Ok(ReplayCompare::Dropped) => Verdict::Dropped,
+            Ok(ReplayCompare::Incorrect) | Err(_) => Verdict::Incorrect,
+        };
+        let (actual_status, actual_data) = match received {
+            Some(&Ok(ref data)) => (Some("Success".to_string()), hex(data)),
+            Some(&Err(e)) => (Some(format!("{:?}", UrbStatus::from_usb_error(e))), String::new()),
+            None => match *res {
+                Err(e) => (Some(format!("{:?}", UrbStatus::from_usb_error(e))), String::new()),
+                Ok(_) => (None, String::new()),
+            },
+        };
+        Entry {
+            index: index,
+            transfer: format!("{:?} 0x{:02x}", t.transfer_type(), t.endpoint()),
+            request: t.request().map(|r| format!("{}", r)),
+            expected_status: t.status().map(|s| format!("{:?}", s)),
+            actual_status: actual_status,
+            expected_data: hex(t.response_data()),
+            actual_data: actual_data,
+            recorded_latency_us: t.latency().map(micros),
+            latency_us: latency.map(micros),
+            verdict: verdict,
+        }
+    }
+
+    /// Writes the entry as a row of the table with a `width` wide request
+    /// column, followed by the payloads if it is incorrect
+    pub fn write_row<W: Write>(&self, w: &mut W, width: usize) -> io::Result<()> {
+        let latency = |l: Option<u64>| l.map_or("-".to_string(), |l| l.to_string());
+        try!(writeln!(w, "{:>5}  {:<16} {:<width$}  {:<10} {:<10} {:>9} {:>9}  {:?}", self.index, self.transfer,
+                      self.request.as_ref().map_or("", |r| r), self.expected_status.as_ref().map_or("-", |s| s),
+                      self.actual_status.as_ref().map_or("-", |s| s), latency(self.recorded_latency_us),
+                      latency(self.latency_us), self.verdict, width = width));
+        if self.verdict == Verdict::Incorrect && self.expected_data != self.actual_data {
+            try!(writeln!(w, "       expected: {}", self.expected_data));
+            try!(writeln!(w, "       actual:   {}", self.actual_data));
+        }
+        Ok(())
+    }
+}
+
+/// Number of transactions with each verdict
+#[derive(Debug, Clone, Copy, Default, PartialEq, RustcEncodable)]
+pub struct Totals {
+    pub total: usize,
+    pub correct: usize,
+    pub error_expected: usize,
+    pub dropped: usize,
+    pub incorrect: usize,
+}
+
+/// Results of a replay, serializable to JSON to attach them to bug reports
+#[derive(Debug, Clone, Default, PartialEq, RustcEncodable)]
+pub struct Report {
+    pub entries: Vec<Entry>,
+    pub totals: Totals,
+}
+
+impl Report {
+    pub fn push(&mut self, entry: Entry) {
+        self.totals.total += 1;
+        match entry.verdict {
+            Verdict::Correct => self.totals.correct += 1,
+            Verdict::ErrorExpected => self.totals.error_expected += 1,
+            Verdict::Dropped => self.totals.dropped += 1,
+            Verdict::Incorrect => self.totals.incorrect += 1,
+        }
+        self.entries.push(entry);
+    }
+
+    pub fn summary(&self) -> String {
+        let t = &self.totals;
+        format!("{} transactions: {} correct, {} expected errors, {} dropped, {} incorrect",
+                t.total, t.correct, t.error_expected, t.dropped, t.incorrect)
+    }
+
+    /// Writes the report as table, showing the payloads of incorrect
+    /// transactions, or as JSON
+    pub fn write<W: Write>(&self, w: &mut W, format: Format) -> io::Result<()> {
+        if format == Format::Json {
+            return writeln!(w, "{}", json::as_pretty_json(self));
+        }
+        let width = self.entries.iter().filter_map(|e| e.request.as_ref()).map(|r| r.len()).max().unwrap_or(0);
+        let width = cmp::max(width, "request".len());
+        try!(writeln!(w, "{:>5}  {:<16} {:<width$}  {:<10} {:<10} {:>9} {:>9}  {}", "index", "transfer", "request",
+                      "expected", "actual", "rec. µs", "µs", "verdict", width = width));
+        for e in &self.entries {
+            try!(e.write_row(w, width));
+        }
+        writeln!(w, "{}", self.summary())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+    use rustc_serialize::json::Json;
+    use print::Format;
+    use super::{Report, Entry, Verdict, Totals};
+
+    fn entry(index: usize, request: Option<&str>, verdict: Verdict) -> Entry {
+        Entry {
+            index: index,
+            transfer: "Control 0x80".to_string(),
+            request: request.map(|r| r.to_string()),
+            expected_status: Some("Success".to_string()),
+            actual_status: Some("Success".to_string()),
+            expected_data: "12 01".to_string(),
+            actual_data: "12 01".to_string(),
+            recorded_latency_us: Some(105),
+            latency_us: None,
+            verdict: verdict,
+        }
+    }
+
+    fn report() -> Report {
+        let mut report = Report::default();
+        report.push(entry(0, Some("GET_DESCRIPTOR Device"), Verdict::Correct));
+        report.push(Entry { actual_data: "12 02".to_string(), ..entry(1, None, Verdict::Incorrect) });
+        report
+    }
+
+    #[test]
+    fn counts_verdicts() {
+        let mut report = Report::default();
+        for &verdict in &[Verdict::Correct, Verdict::Incorrect, Verdict::ErrorExpected, Verdict::Dropped, Verdict::Correct] {
+            report.push(entry(report.entries.len(), None, verdict));
+        }
+        let totals = Totals { total: 5, correct: 2, error_expected: 1, dropped: 1, incorrect: 1 };
+        assert_eq!(report.totals, totals);
+        assert_eq!(report.summary(), "5 transactions: 2 correct, 1 expected errors, 1 dropped, 1 incorrect");
+    }
+
+    #[test]
+    fn writes_a_table() {
+        let mut out = Vec::new();
+        report().write(&mut out, Format::Text).unwrap();
+        let lines: Vec<_> = str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines, vec![
+            "index  transfer         request                expected   actual       rec. µs        µs  verdict",
+            "    0  Control 0x80     GET_DESCRIPTOR Device  Success    Success          105         -  Correct",
+            "    1  Control 0x80                            Success    Success          105         -  Incorrect",
+            "       expected: 12 01",
+            "       actual:   12 02",
+            "2 transactions: 1 correct, 0 expected errors, 0 dropped, 1 incorrect",
+        ]);
+    }
+
+    #[test]
+    fn writes_json() {
+        let mut out = Vec::new();
+        report().write(&mut out, Format::Json).unwrap();
+        let json = Json::from_str(str::from_utf8(&out).unwrap()).unwrap();
+        let totals = json.find("totals").unwrap();
+        assert_eq!(totals.find("total").and_then(|t| t.as_u64()), Some(2));
+        assert_eq!(totals.find("incorrect").and_then(|t| t.as_u64()), Some(1));
+        let entries = json.find("entries").and_then(|e| e.as_array()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].find("request").and_then(|r| r.as_string()), Some("GET_DESCRIPTOR Device"));
+        assert_eq!(entries[0].find("recorded_latency_us").and_then(|l| l.as_u64()), Some(105));
+        assert_eq!(entries[0].find("verdict").and_then(|v| v.as_string()), Some("Correct"));
+        assert!(entries[1].find("request").unwrap().is_null());
+        assert!(entries[1].find("latency_us").unwrap().is_null());
+        assert_eq!(entries[1].find("actual_data").and_then(|d| d.as_string()), Some("12 02"));
+    }
+}
@@ -0,0 +1,296 @@
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use regex::Regex;
+use filter::Filter;
+use usb::Packet;
+
+/// Transactions a rule applies to
+#[derive(Debug, Clone)]
+pub enum Selector {
+    All,
+    /// Indices of transactions in the capture, end exclusive
+    Range(usize, usize),
+    /// Transactions whose Submit matches
+    Filter(Filter),
+}
+
+impl Selector {
+    fn matches(&self, index: usize, submit: Option<&Packet>) -> bool {
+        match *self {
+            Selector::All => true,
+            Selector::Range(start, end) => start <= index && index < end,
+            Selector::Filter(ref f) => submit.map_or(false, |s| f.matches(s)),
+        }
+    }
+}
+
+/// How a response may differ from the recorded one
+#[derive(Debug, Clone)]
+pub enum Rule {
+    /// Only bits set in the mask are compared, bytes beyond it are compared
+    /// completely
+    Mask(Vec<u8>),
+    /// Bytes in these ranges may differ
+    Ignore(Vec<Range<usize>>),
+    /// Only the length is compared
+    Length,
+    /// The lowercase hex string of the response must match, the recorded
+    /// response is ignored
+    Regex(Regex),
+}
+
+/// Rules deciding which responses count as correct during replay.
+///
+/// A response is correct if it has the recorded length and equals the
+/// recorded response in all bits not masked by the `mask`, `ignore` and
+/// `length` rules of its transaction. If `regex` rules apply, the response
+/// must match all of them instead.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    rules: Vec<(Selector, Rule)>,
+}
+
+#[derive(Debug)]
+pub enum RulesError {
+    Io(io::Error),
+    /// Line `line` (starting at 1) is invalid
+    Parse { line: usize, msg: String },
+}
+
+impl fmt::Display for RulesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RulesError::Io(ref e) => write!(f, "io error: {}", e),
+            RulesError::Parse { line, ref msg } => write!(f, "line {}: {}", line, msg),
+        }
+    }
+}
+
+impl StdError for RulesError {
+    fn description(&self) -> &str {
+        match *self {
+            RulesError::Io(_) => "io error",
+            RulesError::Parse { .. } => "invalid rule",
+        }
+    }
+}
+
+impl From<io::Error> for RulesError {
+    fn from(e: io::Error) -> Self {
+        RulesError::Io(e)
+    }
+}
+
+fn parse_index(s: &str) -> Result<usize, String> {
+    s.trim().parse().map_err(|_| format!("invalid index '{}'", s.trim()))
+}
+
+/// `N` or `N..M`, end exclusive
+fn parse_range(s: &str) -> Result<Range<usize>, String> {
+    let mut split = s.splitn(2, "..");
+    let start = try!(parse_index(split.next().unwrap()));
+    let end = match split.next() {
+        Some(end) => try!(parse_index(end)),
+        None => start + 1,
+    };
+    if end <= start {
+        return Err(format!("empty range '{}'", s.trim()));
+    }
+    Ok(start..end)
+}
+
+fn parse_selector(s: &str) -> Result<Selector, String> {
+    if s == "*" {
+        return Ok(Selector::All);
+    }
+    if s.chars().all(|c| c.is_digit(10) || c == '.') {
+        let range = try!(parse_range(s));
+        return Ok(Selector::Range(range.start, range.end));
+    }
+    Filter::parse(s).map(Selector::Filter).map_err(|e| e.highlight(s))
+}
+
+fn parse_rule(s: &str) -> Result<Rule, String> {
+    let (name, args) = match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim()),
+        None => (s, ""),
+    };
+    Ok(match name {
+        "mask" => {
+            let hex: String = args.chars().filter(|c| !c.is_whitespace()).collect();
+            if hex.is_empty() || hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_digit(16)) {
+                return Err(format!("invalid mask '{}'", args));
+            }
+            Rule::Mask((0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap()).collect())
+        },
+        "ignore" => {
+            let mut ranges = Vec::new();
+            for range in args.split(',') {
+                ranges.push(try!(parse_range(range)));
+            }
+            Rule::Ignore(ranges)
+        },
+        "length" => Rule::Length,
+        "regex" => match Regex::new(args) {
+            Ok(regex) => Rule::Regex(regex),
+            Err(e) => return Err(format!("invalid regex: {}", e)),
+        },
+        _ => return Err(format!("unknown rule '{}'", name)),
+    })
+}
+
+impl Rules {
+    /// Parses one rule per line written as `SELECTOR => RULE`, skipping
+    /// empty lines and `#` comments.
+    ///
+    /// SELECTOR is `*`, the index of a transaction, a range of indices like
+    /// `3..10` or a filter expression on the Submit. RULE is one of
+    ///
+    /// ```text
+    /// mask ff ff 00 ff        compare only the bits set in the mask
+    /// ignore 4..6, 10         ignore bytes 4, 5 and 10
+    /// length                  compare only the length
+    /// regex ^11ff0f..         match the response's hex string
+    /// ```
+    pub fn parse(text: &str) -> Result<Rules, RulesError> {
+        let mut rules = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.splitn(2, '#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parsed = match line.find("=>") {
+                Some(pos) => parse_selector(line[..pos].trim())
+                    .and_then(|s| parse_rule(line[pos + 2..].trim()).map(|r| (s, r))),
+                None => Err("expected SELECTOR => RULE".to_string()),
+            };
+            match parsed {
+                Ok(rule) => rules.push(rule),
+                Err(msg) => return Err(RulesError::Parse { line: i + 1, msg: msg }),
+            }
+        }
+        Ok(Rules { rules: rules })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Rules, RulesError> {
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text)));
+        Rules::parse(&text)
+    }
+
+    /// Sidecar file with the rules of a capture, `<capture>.rules`
+    pub fn sidecar(capture: &Path) -> PathBuf {
+        let mut name = capture.file_name().map_or_else(OsString::new, |n| n.to_os_string());
+        name.push(".rules");
+        capture.with_file_name(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Whether `actual` is a correct response to the transaction `index`
+    /// which was recorded with the response `expected`
+    pub fn matches(&self, index: usize, submit: Option<&Packet>, expected: &[u8], actual: &[u8]) -> bool {
+        let rules: Vec<_> = self.rules.iter()
+            .filter(|&&(ref s, _)| s.matches(index, submit))
+            .map(|&(_, ref r)| r)
+            .collect();
+        let regexes: Vec<_> = rules.iter().filter_map(|r| match **r {
+            Rule::Regex(ref regex) => Some(regex),
+            _ => None,
+        }).collect();
+        if !regexes.is_empty() {
+            let hex: String = actual.iter().map(|b| format!("{:02x}", b)).collect();
+            return regexes.iter().all(|r| r.is_match(&hex));
+        }
+        if expected.len() != actual.len() {
+            return false;
+        }
+        let mut mask = vec![0xffu8; expected.len()];
+        for rule in rules {
+            match *rule {
+                Rule::Mask(ref m) => for (a, b) in mask.iter_mut().zip(m) {
+                    *a &= *b;
+                },
+                Rule::Ignore(ref ranges) => for range in ranges {
+                    for m in mask.iter_mut().skip(range.start).take(range.end - range.start) {
+                        *m = 0;
+                    }
+                },
+                Rule::Length => for m in &mut mask {
+                    *m = 0;
+                },
+                Rule::Regex(_) => {},
+            }
+        }
+        expected.iter().zip(actual).zip(&mask).all(|((e, a), m)| e & m == a & m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use synth::Transfer;
+    use super::{Rules, RulesError};
+
+    #[test]
+    fn masks_only_the_leading_bytes() {
+        let rules = Rules::parse("* => mask ff 0f").unwrap();
+        assert!(rules.matches(0, None, &[1, 0x12, 3], &[1, 0x22, 3]));
+        assert!(!rules.matches(0, None, &[1, 0x12, 3], &[1, 0x13, 3]));
+        // bytes beyond the mask are compared completely
+        assert!(!rules.matches(0, None, &[1, 0x12, 3], &[1, 0x12, 4]));
+        assert!(!rules.matches(0, None, &[1, 0x12, 3], &[1, 0x12]));
+    }
+
+    #[test]
+    fn ignores_ranges_past_the_end() {
+        let rules = Rules::parse("2..6 => ignore 1, 3..100").unwrap();
+        assert!(rules.matches(2, None, &[1, 2, 3, 4], &[1, 0, 3, 0]));
+        assert!(!rules.matches(2, None, &[1, 2, 3, 4], &[0, 2, 3, 4]));
+        assert!(!rules.matches(6, None, &[1, 2, 3, 4], &[1, 0, 3, 0]));
+        assert!(!rules.matches(2, None, &[1, 2, 3, 4], &[1, 2, 3]));
+    }
+
+    #[test]
+    fn regexes_override_the_length() {
+        let rules = Rules::parse("* => length\n5 => regex ^11ff..\n5 => regex 00$").unwrap();
+        assert!(rules.matches(4, None, &[1, 2], &[3, 4]));
+        assert!(!rules.matches(4, None, &[1, 2], &[3]));
+        assert!(rules.matches(5, None, &[1, 2], &[0x11, 0xff, 0x0f, 0x00]));
+        assert!(!rules.matches(5, None, &[1, 2], &[0x11, 0xff, 0x0f, 0x01]));
+        assert!(!rules.matches(5, None, &[0x11, 0xff, 0x00], &[0x11, 0xfe, 0x00]));
+    }
+
+    #[test]
+    fn selects_by_filter() {
+        let rules = Rules::parse("request == 0x09 && data[0] == 0x11 => length").unwrap();
+        let set_report = Transfer::control(0x21, 0x09, 0x0211, 1, vec![0x11, 0xff]).to_transaction(1, 0);
+        let get_descriptor = Transfer::control(0x80, 0x06, 0x0100, 0, vec![0; 2]).to_transaction(2, 0);
+        assert!(rules.matches(0, set_report.submit(), &[1, 2], &[3, 4]));
+        assert!(!rules.matches(0, get_descriptor.submit(), &[1, 2], &[3, 4]));
+        // transactions without a Submit are never selected by filters
+        assert!(!rules.matches(0, None, &[1, 2], &[3, 4]));
+    }
+
+    #[test]
+    fn reports_lines_of_errors() {
+        let line = |text| match Rules::parse(text) {
+            Err(RulesError::Parse { line, .. }) => line,
+            res => panic!("{:?}", res),
+        };
+        assert_eq!(line("# comment\n\n* => mask ff\n3 => mask f"), 4);
+        assert_eq!(line("* => length\nlength"), 2);
+        assert_eq!(line("5..5 => length"), 1);
+        assert_eq!(line("\n* => ignore 4, x"), 2);
+        assert_eq!(line("* => regex ("), 1);
+        assert_eq!(line("* => crc"), 1);
+        assert_eq!(line("\n\nlen >> 3 => length"), 3);
+        assert_eq!(Rules::parse("* => length # all\n1..3 => ignore 0").unwrap().len(), 2);
+    }
+}
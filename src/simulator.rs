@@ -0,0 +1,265 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::time::Duration;
+use libusb::{Result as UsbResult, Error as UsbError};
+use capture::{Capture, CaptureError};
+use transport::Script;
+use usb::{Packet, UrbType, UrbStatus, TransferType, Direction};
+
+/// Control request as seen by the device, compared like `Packet::same`
+/// compares the setup fields
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Request {
+    endpoint_direction: u8,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    /// Data of OUT requests
+    data: Vec<u8>,
+}
+
+impl Request {
+    fn from_packet(packet: &Packet) -> Request {
+        Request {
+            endpoint_direction: packet.get_endpoint_direction(),
+            request_type: packet.get_bm_request_type(),
+            request: packet.get_b_request(),
+            value: packet.get_value(),
+            index: packet.get_index(),
+            length: packet.get_w_length(),
+            data: packet.get_data().to_vec(),
+        }
+    }
+
+    fn same_ignoring_length(&self, other: &Request) -> bool {
+        Request { length: other.length, ..self.clone() } == *other
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Response {
+    status: UrbStatus,
+    data: Vec<u8>,
+    /// Interrupt transfers completed after the request, e.g. HID++ answers
+    interrupts: Vec<(u8, Vec<u8>)>,
+}
+
+/// A device answering control requests with the responses recorded in
+/// captures of the real device.
+///
+/// Interrupt IN data completed after a recorded request is queued when the
+/// request is answered and returned by following interrupt reads. If a
+/// request was recorded several times, the responses are given in recorded
+/// order, repeating the last one.
+pub struct Simulator {
+    responses: Vec<(Request, Vec<Response>)>,
+    served: HashMap<Request, usize>,
+    interrupts: VecDeque<(u8, Vec<u8>)>,
+}
+
+impl Simulator {
+    pub fn new() -> Simulator {
+        Simulator {
+            responses: Vec::new(),
+            served: HashMap::new(),
+            interrupts: VecDeque::new(),
+        }
+    }
+
+    pub fn from_files<P: AsRef<Path>>(paths: &[P]) -> Result<Simulator, CaptureError> {
+        let mut sim = Simulator::new();
+        for path in paths {
+            sim.learn(try!(try!(Capture::from_file(path)).read_all()));
+        }
+        Ok(sim)
+    }
+
+    /// Adds the control requests recorded in `packets`
+    pub fn learn(&mut self, packets: Vec<Packet<'static>>) {
+        // submitted control requests by URB id
+        let mut pending: HashMap<u64, (Request, Response)> = HashMap::new();
+        let mut recorded = Vec::new();
+        let mut last: Option<u64> = None;
+        for packet in packets {
+            match (packet.get_urb_type(), packet.get_transfer_type()) {
+                (UrbType::Submit, TransferType::Control) => {
+                    let response = Response { status: UrbStatus::Success, data: Vec::new(), interrupts: Vec::new() };
+                    pending.insert(packet.get_id(), (Request::from_packet(&packet), response));
+                    last = Some(packet.get_id());
+                },
+                (UrbType::Complete, TransferType::Control) => {
+                    if let Some((request, mut response)) = pending.remove(&packet.get_id()) {
+                        response.status = packet.get_status();
+                        response.data = packet.get_data().to_vec();
+                        recorded.push((packet.get_id(), request, response));
+                    }
+                },
+                (UrbType::Complete, TransferType::Interrupt) => {
+                    if packet.get_direction() != Direction::In || packet.get_data().is_empty() {
+                        continue;
+                    }
+                    let interrupt = (packet.get_endpoint_direction(), packet.get_data().to_vec());
+                    let id = match last {
+                        Some(id) => id,
+                        // e.g. key presses before the first request
+                        None => continue,
+                    };
+                    match pending.get_mut(&id) {
+                        Some(&mut (_, ref mut response)) => response.interrupts.push(interrupt),
+                        None => match recorded.iter_mut().rev().find(|&&mut (i, _, _)| i == id) {
+                            Some(&mut (_, _, ref mut response)) => response.interrupts.push(interrupt),
+                            None => {},
+                        },
+                    }
+                },
+                _ => {},
+            }
+        }
+        for (_, request, response) in recorded {
+            match self.responses.iter().position(|&(ref r, _)| *r == request) {
+                Some(i) => self.responses[i].1.push(response),
+                None => self.responses.push((request, vec![response])),
+            }
+        }
+    }
+
+    fn lookup(&mut self, request: &Request) -> Option<Response> {
+        let pos = self.responses.iter().position(|&(ref r, _)| r == request)
+            .or_else(|| self.responses.iter().position(|&(ref r, _)| r.same_ignoring_length(request)));
+        let &(ref recorded, ref responses) = match pos {
+            Some(pos) => &self.responses[pos],
+            None => return None,
+        };
+        let served = self.served.entry(recorded.clone()).or_insert(0);
+        let response = responses[cmp::min(*served, responses.len() - 1)].clone();
+        *served += 1;
+        Some(response)
+    }
+
+    /// Answers a control request. Unknown requests are stalled.
+    pub fn control(&mut self, request_type: u8, request: u8, value: u16, index: u16,
+                   length: u16, data: &[u8]) -> UsbResult<Vec<u8>> {
+        let endpoint_direction = request_type & 0x80;
+        let request = Request {
+            endpoint_direction: endpoint_direction,
+            request_type: request_type,
+            request: request,
+            value: value,
+            index: index,
+            length: length,
+            data: data.to_vec(),
+        };
+        let response = match self.lookup(&request) {
+            Some(response) => response,
+            None => return Err(UsbError::Pipe),
+        };
+        self.interrupts.extend(response.interrupts);
+        match response.status {
+            UrbStatus::Success => {
+                let mut data = response.data;
+                data.truncate(length as usize);
+                Ok(data)
+            },
+            status => Err(status.to_usb_error().unwrap_or(UsbError::Other)),
+        }
+    }
+
+    /// Returns queued interrupt data of `endpoint`, timing out if there is none
+    pub fn interrupt(&mut self, endpoint: u8) -> UsbResult<Vec<u8>> {
+        match self.interrupts.iter().position(|&(e, _)| e == endpoint) {
+            Some(i) => Ok(self.interrupts.remove(i).unwrap().1),
+            None => Err(UsbError::Timeout),
+        }
+    }
+
+    // synchronous API of libusb::DeviceHandle
+
+    #[allow(unused)]
+    pub fn read_control(&mut self, request_type: u8, request: u8, value: u16, index: u16,
+                        buf: &mut [u8], _timeout: Duration) -> UsbResult<usize> {
+        let data = try!(self.control(request_type, request, value, index, buf.len() as u16, &[]));
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    #[allow(unused)]
+    pub fn write_control(&mut self, request_type: u8, request: u8, value: u16, index: u16,
+                         buf: &[u8], _timeout: Duration) -> UsbResult<usize> {
+        try!(self.control(request_type, request, value, index, buf.len() as u16, buf));
+        Ok(buf.len())
+    }
+
+    #[allow(unused)]
+    pub fn read_interrupt(&mut self, endpoint: u8, buf: &mut [u8], _timeout: Duration) -> UsbResult<usize> {
+        let data = try!(self.interrupt(endpoint));
+        let len = cmp::min(data.len(), buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+}
+
+/// Answers transfers sent through `MockTransport`
+impl Script for Simulator {
+    fn control(&mut self, _endpoint_direction: u8, buf: &[u8], request_type: u8,
+               request: u8, value: u16, index: u16) -> UsbResult<Vec<u8>> {
+        let data = if request_type & 0x80 == 0 { buf } else { &[] };
+        Simulator::control(self, request_type, request, value, index, buf.len() as u16, data)
+    }
+
+    fn interrupt(&mut self, endpoint_direction: u8, _buf: &[u8]) -> UsbResult<Vec<u8>> {
+        Simulator::interrupt(self, endpoint_direction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use libusb::Error as UsbError;
+    use replay::Control;
+    use report::{Totals, Verdict};
+    use transport::MockTransport;
+    use super::Simulator;
+
+    const HANDSHAKES: [&'static str; 5] = ["pcap/g910/handshake/handshake.pcap",
+        "pcap/g910/handshake/handshake2.pcap", "pcap/g910/handshake/handshake3.pcap",
+        "pcap/g910/handshake/handshake4.pcap", "pcap/g910/handshake/handshake5.pcap"];
+
+    #[test]
+    fn answers_standard_requests() {
+        let mut sim = Simulator::from_files(&HANDSHAKES).unwrap();
+        let device = sim.control(0x80, 0x06, 0x0100, 0, 18, &[]).unwrap();
+        assert_eq!(&device[..4], &[18, 1, 0x00, 0x02]);
+        assert_eq!(sim.control(0xc0, 0xff, 0, 0, 8, &[]), Err(UsbError::Pipe));
+        assert_eq!(sim.interrupt(0x83), Err(UsbError::Timeout));
+    }
+
+    /// Replays the recorded initializations to a G910 simulated from them.
+    /// Incorrect are the interrupts still in flight when a capture ended and
+    /// HID++ answers which differ between the captures.
+    #[test]
+    fn answers_handshakes() {
+        let expected = [(682, 675, 3, 2, 2), (252, 245, 2, 2, 3), (252, 246, 2, 2, 2),
+                        (252, 246, 2, 2, 2), (252, 244, 2, 2, 4)];
+        for (path, &(total, correct, error_expected, dropped, incorrect)) in HANDSHAKES.iter().zip(&expected) {
+            let sim = Simulator::from_files(&HANDSHAKES).unwrap();
+            let mut ctrl = Control::with_transport(Path::new(path), MockTransport::with_script(sim)).unwrap();
+            while !ctrl.is_empty() {
+                let _ = ctrl.replay_compare_next();
+            }
+            let totals = Totals {
+                total: total,
+                correct: correct,
+                error_expected: error_expected,
+                dropped: dropped,
+                incorrect: incorrect,
+            };
+            assert_eq!(ctrl.report().totals, totals, "{}", path);
+            for e in ctrl.report().entries.iter().filter(|e| e.verdict == Verdict::Incorrect) {
+                assert!(e.transfer.starts_with("Interrupt"), "{}: transaction {} is incorrect", path, e.index);
+            }
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use record::Writer;
+use transaction::Transaction;
+use usb::{Packet, TransferType, UrbStatus};
+
+/// Setup packet of a control transfer, without `wLength`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Setup {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+}
+
+/// A transfer described by its fields, turned into a Submit and a Complete
+/// by `Transfer::to_transaction`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    pub transfer_type: TransferType,
+    /// Endpoint address including the direction bit
+    pub endpoint: u8,
+    pub setup: Option<Setup>,
+    /// Sent for OUT transfers, returned for IN transfers
+    pub data: Vec<u8>,
+    /// Requested length of IN transfers, the length of `data` if `None`
+    pub length: Option<u32>,
+    pub status: UrbStatus,
+    /// Microseconds between the previous Complete and the Submit
+    pub delay: u64,
+    /// Microseconds between Submit and Complete
+    pub latency: u64,
+}
+
+impl Transfer {
+    /// Control transfer on endpoint 0, whose direction is the one of
+    /// `request_type`
+    pub fn control(request_type: u8, request: u8, value: u16, index: u16, data: Vec<u8>) -> Transfer {
+        let setup = Setup { request_type: request_type, request: request, value: value, index: index };
+        Transfer::new(TransferType::Control, request_type & 0x80, Some(setup), data)
+    }
+
+    pub fn interrupt(endpoint: u8, data: Vec<u8>) -> Transfer {
+        Transfer::new(TransferType::Interrupt, endpoint, None, data)
+    }
+
+    fn new(transfer_type: TransferType, endpoint: u8, setup: Option<Setup>, data: Vec<u8>) -> Transfer {
+        Transfer {
+            transfer_type: transfer_type,
+            endpoint: endpoint,
+            setup: setup,
+            data: data,
+            length: None,
+            status: UrbStatus::Success,
+            delay: 1000,
+            latency: 100,
+        }
+    }
+
+    fn is_in(&self) -> bool {
+        self.endpoint & 0x80 == 0x80
+    }
+
+    /// Submit at `submitted` and Complete `latency` later, in microseconds
+    pub fn to_transaction(&self, id: u64, submitted: u64) -> Transaction {
+        let length = self.length.unwrap_or(self.data.len() as u32);
+        let request = if self.is_in() { Vec::new() } else { self.data.clone() };
+        let mut submit = Packet::submit(id, self.transfer_type, self.endpoint, length, request);
+        if let Some(s) = self.setup {
+            submit.set_setup(s.request_type, s.request, s.value, s.index, length as u16);
+        }
+        submit.set_time(submitted / 1_000_000, (submitted % 1_000_000) as u32);
+        let mut complete = match (self.status, self.is_in()) {
+            (UrbStatus::Success, true) => submit.complete(self.status, self.data.len() as u32, self.data.clone()),
+            (UrbStatus::Success, false) => submit.complete(self.status, length, Vec::new()),
+            (status, _) => submit.complete(status, 0, Vec::new()),
+        };
+        let completed = submitted.saturating_add(self.latency);
+        complete.set_time(completed / 1_000_000, (completed % 1_000_000) as u32);
+        Transaction::new(Some(submit), Some(complete))
+    }
+}
+
+/// Lays out transfers one after another with URB ids counting from 1,
+/// starting at time 0
+pub fn synthesize(transfers: &[Transfer]) -> Result<Vec<Transaction>, SynthError> {
+    let mut time = 0u64;
+    let mut transactions = Vec::new();
+    for (i, t) in transfers.iter().enumerate() {
+        let submitted = match time.checked_add(t.delay) {
+            Some(submitted) => submitted,
+            None => return Err(SynthError::TooLate { transfer: i + 1 }),
+        };
+        time = match submitted.checked_add(t.latency) {
+            Some(completed) => completed,
+            None => return Err(SynthError::TooLate { transfer: i + 1 }),
+        };
+        transactions.push(t.to_transaction(i as u64 + 1, submitted));
+    }
+    Ok(transactions)
+}
+
+/// Writes transactions into a usbmon pcap file, Submit before Complete
+pub fn write(path: &Path, transactions: &[Transaction]) -> io::Result<()> {
+    let mut writer = try!(Writer::new(try!(File::create(path))));
+    for t in transactions {
+        for packet in t.submit().into_iter().chain(t.complete()) {
+            try!(writer.write_packet(packet));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum SynthError {
+    Io(io::Error),
+    /// Line `line` (starting at 1) is invalid
+    Parse { line: usize, msg: String },
+    /// Transfer `transfer` (starting at 1) completes after the largest
+    /// timestamp
+    TooLate { transfer: usize },
+}
+
+impl fmt::Display for SynthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SynthError::Io(ref e) => write!(f, "io error: {}", e),
+            SynthError::Parse { line, ref msg } => write!(f, "line {}: {}", line, msg),
+            SynthError::TooLate { transfer } => write!(f, "transfer {}: delays and latencies are too large", transfer),
+        }
+    }
+}
+
+impl StdError for SynthError {
+    fn description(&self) -> &str {
+        match *self {
+            SynthError::Io(_) => "io error",
+            SynthError::Parse { .. } => "invalid transfer",
+            SynthError::TooLate { .. } => "timestamp overflow",
+        }
+    }
+}
+
+impl From<io::Error> for SynthError {
+    fn from(e: io::Error) -> Self {
+        SynthError::Io(e)
+    }
+}
+
+fn parse_number(s: &str, max: u64) -> Result<u64, String> {
+    let res = if s.starts_with("0x") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    };
+    match res {
+        Some(n) if n <= max => Ok(n),
+        _ => Err(format!("invalid number '{}'", s)),
+    }
+}
+
+/// Hex bytes, optionally separated by whitespace like `11 ff 0f3b`
+fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let hex: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if let Some(c) = hex.chars().find(|c| !c.is_digit(16)) {
+        return Err(format!("invalid hex digit '{}'", c));
+    }
+    if hex.len() % 2 != 0 {
+        return Err(format!("odd number of hex digits in '{}'", s.trim()));
+    }
+    Ok((0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap()).collect())
+}
+
+/// Parses a transfer written as
+///
+/// ```text
+/// control REQUEST_TYPE REQUEST VALUE INDEX [OPTION..] [: DATA]
+/// interrupt ENDPOINT [OPTION..] [: DATA]
+/// ```
+///
+/// with the options `len=N` for the requested length of IN transfers,
+/// `status=ERRNO` like `status=-32` for a stall, `delay=US` and `latency=US`.
+/// Numbers are decimal or hex with `0x`, DATA are hex bytes, e.g.
+/// `control 0x21 0x09 0x0211 1 : 11 ff 0f 3b`.
+pub fn parse_transfer(line: &str) -> Result<Transfer, String> {
+    let (fields, data) = match line.find(':') {
+        Some(i) => (&line[..i], try!(parse_bytes(&line[i + 1..]))),
+        None => (line, Vec::new()),
+    };
+    let words: Vec<_> = fields.split_whitespace().collect();
+    let (positional, options): (Vec<&str>, Vec<&str>) = words.iter().skip(1).cloned().partition(|w| !w.contains('='));
+    let expect = |n: usize| if positional.len() == n {
+        Ok(())
+    } else {
+        Err(format!("{} expects {} arguments but got {}", words[0], n, positional.len()))
+    };
+    let mut transfer = match words.get(0).cloned() {
+        Some("control") => {
+            try!(expect(4));
+            Transfer::control(try!(parse_number(positional[0], 0xff)) as u8,
+                              try!(parse_number(positional[1], 0xff)) as u8,
+                              try!(parse_number(positional[2], 0xffff)) as u16,
+                              try!(parse_number(positional[3], 0xffff)) as u16, data)
+        },
+        Some("interrupt") => {
+            try!(expect(1));
+            Transfer::interrupt(try!(parse_number(positional[0], 0xff)) as u8, data)
+        },
+        Some(kind) => return Err(format!("unknown transfer type '{}'", kind)),
+        None => return Err("empty transfer".to_string()),
+    };
+    for option in options {
+        let mut split = option.splitn(2, '=');
+        let (key, value) = (split.next().unwrap(), split.next().unwrap());
+        match key {
+            "len" => transfer.length = Some(try!(parse_number(value, 0xffff)) as u32),
+            "status" => {
+                let errno = try!(value.parse::<i32>().map_err(|_| format!("invalid errno '{}'", value)));
+                transfer.status = UrbStatus::from(errno as u32);
+            },
+            "delay" => transfer.delay = try!(parse_number(value, u64::max_value())),
+            "latency" => transfer.latency = try!(parse_number(value, u64::max_value())),
+            _ => return Err(format!("unknown option '{}'", key)),
+        }
+    }
+    // wLength is 16 bit
+    if transfer.transfer_type == TransferType::Control && transfer.data.len() > 0xffff {
+        return Err(format!("control transfers carry at most 65535 bytes but got {}", transfer.data.len()));
+    }
+    if transfer.is_in() && transfer.length.map_or(false, |l| (l as usize) < transfer.data.len()) {
+        return Err("returns more data than requested".to_string());
+    }
+    Ok(transfer)
+}
+
+/// Parses one transfer per line, skipping empty lines and `#` comments
+pub fn parse(text: &str) -> Result<Vec<Transfer>, SynthError> {
+    let mut transfers = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.splitn(2, '#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match parse_transfer(line) {
+            Ok(t) => transfers.push(t),
+            Err(msg) => return Err(SynthError::Parse { line: i + 1, msg: msg }),
+        }
+    }
+    Ok(transfers)
+}
+
+/// Reads and synthesizes the transfers in the text file `source`
+pub fn from_file(source: &Path) -> Result<Vec<Transaction>, SynthError> {
+    let mut text = String::new();
+    try!(File::open(source).and_then(|mut f| f.read_to_string(&mut text)));
+    synthesize(&try!(parse(&text)))
+}
+
+/// Compiles the transfers in the text file `source` into the pcap `out`
+pub fn compile(source: &Path, out: &Path) -> Result<usize, SynthError> {
+    let transactions = try!(from_file(source));
+    try!(write(out, &transactions));
+    Ok(transactions.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use capture::Capture;
+    use transaction;
+    use usb::{TransferType, UrbStatus, SetupRequest, ReportType};
+    use super::{parse, parse_transfer, synthesize, write, Setup, Transfer, SynthError};
+
+    #[test]
+    fn parses_transfers() {
+        let t = parse_transfer("control 0x21 0x09 0x0211 1 delay=5 latency=7 : 11 ff 0f3b").unwrap();
+        assert_eq!(t.transfer_type, TransferType::Control);
+        assert_eq!(t.endpoint, 0x00);
+        assert_eq!(t.setup, Some(Setup { request_type: 0x21, request: 0x09, value: 0x0211, index: 1 }));
+        assert_eq!(t.data, vec![0x11, 0xff, 0x0f, 0x3b]);
+        assert_eq!((t.delay, t.latency), (5, 7));
+
+        let transaction = t.to_transaction(1, 5);
+        assert_eq!(transaction.request(),
+                   Some(SetupRequest::SetReport { report_type: ReportType::Output, report_id: 0x11, interface: 1 }));
+        assert_eq!(transaction.request_data(), &[0x11, 0xff, 0x0f, 0x3b]);
+        assert_eq!(transaction.status(), Some(UrbStatus::Success));
+        assert_eq!(transaction.latency().unwrap().subsec_nanos(), 7000);
+    }
+
+    #[test]
+    fn parses_length_and_status() {
+        let t = parse_transfer("interrupt 0x81 len=8 status=-32 : 01 02").unwrap();
+        assert_eq!(t.length, Some(8));
+        assert_eq!(t.status, UrbStatus::Pipe);
+        let transaction = t.to_transaction(1, 0);
+        assert_eq!(transaction.submit().unwrap().get_length(), 8);
+        // failed transfers return no data
+        assert_eq!(transaction.response_data(), &[] as &[u8]);
+
+        assert!(parse_transfer("interrupt 0x81 len=1 : 01 02").is_err());
+        assert!(parse_transfer("interrupt 0x81 status=x").is_err());
+        assert!(parse_transfer("interrupt 0x81 speed=1").is_err());
+        assert!(parse_transfer("control 0x80 6 0x100").is_err());
+    }
+
+    #[test]
+    fn reports_lines_of_errors() {
+        match parse("# comment\n\ninterrupt 0x81\nbulk 0x02\n") {
+            Err(SynthError::Parse { line, .. }) => assert_eq!(line, 4),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_timestamps() {
+        let mut late = Transfer::interrupt(0x81, vec![1]);
+        late.delay = u64::max_value() - 50;
+        match synthesize(&[Transfer::interrupt(0x81, vec![1]), late]) {
+            Err(SynthError::TooLate { transfer }) => assert_eq!(transfer, 2),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn writes_readable_captures() {
+        let transfers = parse("control 0x80 0x06 0x0100 0 : 12 01\ninterrupt 0x02 : 11 ff\n").unwrap();
+        let transactions = synthesize(&transfers).unwrap();
+        let path = env::temp_dir().join("usbtest-synth-test.pcap");
+        write(&path, &transactions).unwrap();
+        let read = transaction::pair(Capture::from_file(&path).unwrap().read_all().unwrap());
+        assert_eq!(read, transactions);
+    }
+}
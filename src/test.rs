@@ -12,7 +12,17 @@ use libusb::{
 use std::time::Duration;
 use std::fmt::Display;
 use std::path::Path;
-use pcap;
+use rustc_serialize::json;
+use capture::{Capture, CaptureError};
+use descriptor;
+use diff::{self, Edit, ByteDiff};
+use infer::{self, Sample};
+use transaction;
+use features::FeatureTable;
+use filter::Filter;
+use hid::ReportDescriptor;
+use hidpp;
+use print::Format;
 use usb;
 use g910::*;
 
@@ -197,47 +207,145 @@ fn read_endpoint(handle: &mut DeviceHandle, endpoint: &Endpoint) -> Result<()>{
     return Ok(());
 }
 
-pub fn print_all_data(p: &Path) {
-    let mut c = pcap::Capture::from_file(p).unwrap();
-    while let Ok(pa) = c.next() {
-        let packet = usb::Packet::from_bytes(pa.data).unwrap();
-        if packet.get_data_length() != 0
-                //&& packet.get_direction() == usb::Direction::Out
-                //&& packet.get_endpoint() == 0
-                //&& packet.get_data()[0] == 0x12
-        {
+type CaptureResult = ::std::result::Result<(), CaptureError>;
+
+/// Opens a capture only yielding packets matching `filter`
+fn open(p: &Path, filter: &Filter) -> ::std::result::Result<Capture, CaptureError> {
+    let mut c = try!(Capture::from_file(p));
+    c.set_filter(filter.clone());
+    Ok(c)
+}
+
+/// Prints the data of all packets matching `filter`, e.g.
+/// `dir == out && ep == 0 && data[0] == 0x12`
+pub fn print_all_data(p: &Path, filter: &Filter) -> CaptureResult {
+    let mut c = try!(open(p, filter));
+    while let Some(packet) = c.next() {
+        let packet = try!(packet);
+        if packet.get_data_length() != 0 {
+            if let Some(request) = packet.get_request() {
+                println!("{}", request);
+            }
+            let data = match hidpp::Message::parse(packet.get_data()) {
+                Some(msg) => msg.to_string(),
+                None => format!("{:?}", packet.get_data().iter()
+                                .map(|b| format!("{:02x}", b)).fold(String::new(), |o,n| o+&n)),
+            };
             if packet.get_direction() == usb::Direction::Out {
-                println!("IN:  {:?}", packet.get_data().iter()
-                         .map(|b| format!("{:02x}", b)).fold(String::new(), |o,n| o+&n));
+                println!("IN:  {}", data);
             }
             if packet.get_direction() == usb::Direction::In {
-                println!("OUT: {:?}", packet.get_data().iter()
-                         .map(|b| format!("{:02x}", b)).fold(String::new(), |o,n| o+&n));
+                println!("OUT: {}", data);
             }
         }
     }
+    Ok(())
 }
 
+/// Learns the feature table from `handshake` and prints all HID++ messages
+/// of `p` with their feature names.
 #[allow(unused)]
-pub fn compare(p1: &Path, p2: &Path) {
-    let mut c1 = pcap::Capture::from_file(&p1).unwrap();
-    let mut c2 = pcap::Capture::from_file(&p2).unwrap();
-    for i in 0.. {
-        let (packet1, packet2) = match (c1.next(), c2.next()) {
-            (Ok(p1), Ok(p2)) => (p1, p2),
-            (Err(e), Ok(p2)) => return println!("capture1 is empty, capture2 still has sth ({:?})", e),
-            (Ok(p1), Err(e)) => return println!("capture1 still has sth, capture2 is empty ({:?})", e),
-            (Err(e1), Err(e2)) => return println!("success ({:?}, {:?})", e1, e2),
+pub fn print_features(handshake: &Path, p: &Path, filter: &Filter) -> CaptureResult {
+    let table = try!(FeatureTable::from_capture(handshake));
+    table.print();
+    println!("");
+    let mut c = try!(open(p, filter));
+    while let Some(packet) = c.next() {
+        let packet = try!(packet);
+        if let Some(msg) = hidpp::Message::parse(packet.get_data()) {
+            let dir = match packet.get_direction() {
+                usb::Direction::Out => "->",
+                usb::Direction::In => "<-",
+            };
+            println!("{} {}", dir, table.describe(&msg));
+        }
+    }
+    Ok(())
+}
 
-        };
-        let p1 = usb::Packet::from_bytes(packet1.data).unwrap();
-        let p2 = usb::Packet::from_bytes(packet2.data).unwrap();
-        if !p1.same(&p2) {
-            println!("Packet {} incorrect", i+1);
-            println!("{}", format!("{:?}", p1).replace(", ", ",\n").replace("{ ", "{\n"));
-            println!("{}", format!("{:?}", p2).replace(", ", ",\n").replace("{ ", "{\n"));
+/// Decodes the HID report descriptors returned in a capture
+#[allow(unused)]
+pub fn print_report_descriptors(p: &Path, filter: &Filter) -> CaptureResult {
+    for device in try!(descriptor::from_capture(p, filter)) {
+        let interfaces = device.configs.iter().flat_map(|c| c.interfaces.iter());
+        for alt in interfaces.flat_map(|i| i.alt_settings.iter()) {
+            let report = match alt.hid.as_ref().and_then(|h| h.report_descriptor.as_ref()) {
+                Some(report) => report,
+                None => continue,
+            };
+            println!("{:04x}:{:04x} interface {}:", device.descriptor.vendor_id,
+                     device.descriptor.product_id, alt.number);
+            match ReportDescriptor::parse(report) {
+                Ok(desc) => desc.print(),
+                Err(e) => println!("Invalid report descriptor: {}", e),
+            }
+            println!("");
         }
     }
+    Ok(())
+}
+
+/// Prints the differences between two captures, aligning their
+/// transactions
+#[allow(unused)]
+pub fn compare(p1: &Path, p2: &Path, filter: &Filter) -> CaptureResult {
+    let t1 = try!(transaction::from_capture(p1, filter));
+    let t2 = try!(transaction::from_capture(p2, filter));
+    let (mut i1, mut i2) = (0, 0);
+    let mut same = true;
+    for edit in diff::diff(&t1, &t2) {
+        match edit {
+            Edit::Same(..) => {},
+            Edit::Deleted(t) => println!("- {}: {}", i1 + 1, t),
+            Edit::Inserted(t) => println!("+ {}: {}", i2 + 1, t),
+            Edit::Modified(a, b) => {
+                println!("~ {}/{}: {}", i1 + 1, i2 + 1, a);
+                if a.status() != b.status() {
+                    println!("    status: {:?} -> {:?}", a.status(), b.status());
+                }
+                if a.request_data() != b.request_data() {
+                    println!("  request:\n{}", ByteDiff::new(a.request_data(), b.request_data()));
+                }
+                if a.response_data() != b.response_data() {
+                    println!("  response:\n{}", ByteDiff::new(a.response_data(), b.response_data()));
+                }
+            },
+        }
+        match edit {
+            Edit::Same(..) => { i1 += 1; i2 += 1; },
+            Edit::Modified(..) => { i1 += 1; i2 += 1; same = false; },
+            Edit::Deleted(_) => { i1 += 1; same = false; },
+            Edit::Inserted(_) => { i2 += 1; same = false; },
+        }
+    }
+    if same {
+        println!("success");
+    }
+    Ok(())
+}
+
+/// Prints the payload fields varying between the color captures in `dir`
+/// and where each key's color is set
+#[allow(unused)]
+pub fn print_color_fields(dir: &Path, filter: &Filter, format: Format) -> CaptureResult {
+    let samples = try!(Sample::from_color_dir(dir, filter));
+    let fields = infer::infer(&samples);
+    let locations = infer::color_locations(&fields, "key");
+    if format == Format::Json {
+        println!("{}", json::as_pretty_json(&locations));
+        return Ok(());
+    }
+    for field in &fields {
+        println!("{}", field);
+    }
+    println!("");
+    println!("{:<8} {:>11}  {:>4}  {:>4}  {:>4}  {:>5}  {:>4}", "key", "transaction", "id", "at", "red", "green",
+             "blue");
+    for l in &locations {
+        println!("{:<8} {:>11}  0x{:02x}  0x{:02x}  0x{:02x}   0x{:02x}  0x{:02x}", l.label, l.transaction + 1, l.id,
+                 l.id_offset, l.red, l.green, l.blue);
+    }
+    Ok(())
 }
 
 #[allow(unused)]
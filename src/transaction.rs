@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+use capture::{Capture, CaptureError};
+use filter::Filter;
+use usb::{Packet, UrbType, UrbStatus, TransferType, Direction, SetupRequest};
+
+/// A Submit and its Complete packet, matched by URB id.
+///
+/// Either half may be missing if the capture started after the submission
+/// or ended before the completion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    submit: Option<Packet<'static>>,
+    complete: Option<Packet<'static>>,
+}
+
+impl Transaction {
+    pub fn new(submit: Option<Packet<'static>>, complete: Option<Packet<'static>>) -> Transaction {
+        Transaction {
+            submit: submit,
+            complete: complete,
+        }
+    }
+
+    pub fn submit(&self) -> Option<&Packet<'static>> {
+        self.submit.as_ref()
+    }
+
+    pub fn submit_mut(&mut self) -> Option<&mut Packet<'static>> {
+        self.submit.as_mut()
+    }
+
+    pub fn complete(&self) -> Option<&Packet<'static>> {
+        self.complete.as_ref()
+    }
+
+    pub fn into_parts(self) -> (Option<Packet<'static>>, Option<Packet<'static>>) {
+        (self.submit, self.complete)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.submit.is_some() && self.complete.is_some()
+    }
+
+    /// Any of the two halves, preferring the Submit
+    fn packet(&self) -> &Packet<'static> {
+        self.submit.as_ref().or(self.complete.as_ref()).unwrap()
+    }
+
+    pub fn id(&self) -> u64 {
+        self.packet().get_id()
+    }
+
+    pub fn transfer_type(&self) -> TransferType {
+        self.packet().get_transfer_type()
+    }
+
+    /// Endpoint address including the direction bit
+    pub fn endpoint(&self) -> u8 {
+        self.packet().get_endpoint_direction()
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.packet().get_direction()
+    }
+
+    pub fn request(&self) -> Option<SetupRequest> {
+        self.submit.as_ref().and_then(|s| s.get_request())
+    }
+
+    /// Data sent to the device
+    pub fn request_data(&self) -> &[u8] {
+        self.submit.as_ref().map(|s| s.get_data()).unwrap_or(&[])
+    }
+
+    /// Data returned by the device
+    pub fn response_data(&self) -> &[u8] {
+        self.complete.as_ref().map(|c| c.get_data()).unwrap_or(&[])
+    }
+
+    pub fn status(&self) -> Option<UrbStatus> {
+        self.complete.as_ref().map(|c| c.get_status())
+    }
+
+    /// Compares both halves with `Packet::same`
+    pub fn same(&self, other: &Transaction) -> bool {
+        let same = |a: Option<&Packet>, b: Option<&Packet>| match (a, b) {
+            (Some(a), Some(b)) => a.same(b),
+            (None, None) => true,
+            _ => false,
+        };
+        same(self.submit(), other.submit()) && same(self.complete(), other.complete())
+    }
+
+    /// Time between Submit and Complete
+    pub fn latency(&self) -> Option<Duration> {
+        let (s, c) = match (self.submit.as_ref(), self.complete.as_ref()) {
+            (Some(s), Some(c)) => (s, c),
+            _ => return None,
+        };
+        let micros = micros(c).saturating_sub(micros(s));
+        Some(Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000))
+    }
+
+    /// Capture timestamp of the first packet in microseconds
+    pub fn timestamp(&self) -> u64 {
+        micros(self.packet())
+    }
+}
+
+fn micros(p: &Packet) -> u64 {
+    p.get_sec() * 1_000_000 + p.get_usec() as u64
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:?} 0x{:02x}", self.transfer_type(), self.endpoint()));
+        if let Some(request) = self.request() {
+            try!(write!(f, " {}", request));
+        }
+        match self.status() {
+            Some(status) => write!(f, " {:?}", status),
+            None => write!(f, " incomplete"),
+        }
+    }
+}
+
+/// Groups packets into transactions, ordered by the position of their
+/// first packet in the capture.
+///
+/// Completions are matched to the latest outstanding Submit with the same
+/// URB id, regardless of how many packets lie in between. URB ids are
+/// reused by the kernel once a transfer completed.
+pub fn pair<I>(packets: I) -> Vec<Transaction>
+        where I: IntoIterator<Item=Packet<'static>> {
+    let mut transactions = Vec::new();
+    let mut pending: HashMap<u64, usize> = HashMap::new();
+    for packet in packets {
+        match packet.get_urb_type() {
+            UrbType::Submit => {
+                pending.insert(packet.get_id(), transactions.len());
+                transactions.push(Transaction { submit: Some(packet), complete: None });
+            },
+            // Complete, submission Error or garbage
+            _ => {
+                match pending.remove(&packet.get_id()) {
+                    Some(i) => transactions[i].complete = Some(packet),
+                    None => transactions.push(Transaction { submit: None, complete: Some(packet) }),
+                }
+            },
+        }
+    }
+    transactions
+}
+
+/// Reads the transactions of a capture of which a packet matches `filter`.
+/// Packets are paired before filtering, so both halves are kept.
+pub fn from_capture(path: &Path, filter: &Filter) -> Result<Vec<Transaction>, CaptureError> {
+    let packets = try!(try!(Capture::from_file(path)).read_all());
+    Ok(pair(packets).into_iter().filter(|t| filter.matches_transaction(t)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use capture::Capture;
+    use usb::{Packet, UrbType, UrbStatus, TransferType};
+    use super::pair;
+
+    #[test]
+    fn pairs_captured_packets_by_id() {
+        let packets = Capture::from_file("pcap/g910/handshake/handshake.pcap").unwrap().read_all().unwrap();
+        assert_eq!(packets.len(), 1360);
+        let transactions = pair(packets);
+        assert_eq!(transactions.len(), 682);
+        assert_eq!(transactions.iter().filter(|t| t.is_complete()).count(), 678);
+        // completions of transfers submitted before the capture started
+        assert_eq!(transactions.iter().filter(|t| t.submit().is_none()).count(), 2);
+        // the interrupt transfers pending when it ended
+        let pending: Vec<_> = transactions.iter().enumerate()
+            .filter(|&(_, t)| t.complete().is_none())
+            .map(|(i, t)| (i, t.transfer_type(), t.endpoint()))
+            .collect();
+        assert_eq!(pending, vec![(675, TransferType::Interrupt, 0x81), (681, TransferType::Interrupt, 0x82)]);
+        for t in transactions.iter().filter(|t| t.is_complete()) {
+            let (s, c) = (t.submit().unwrap(), t.complete().unwrap());
+            assert_eq!((s.get_urb_type(), c.get_urb_type()), (UrbType::Submit, UrbType::Complete));
+            assert_eq!(s.get_id(), c.get_id());
+            assert!(t.latency().is_some());
+        }
+        // URB ids are reused once their transfer completed
+        let ids: HashSet<_> = transactions.iter().map(|t| t.id()).collect();
+        assert_eq!(ids.len(), 218);
+        assert!(transactions.windows(2).all(|w| w[0].timestamp() <= w[1].timestamp()));
+    }
+
+    #[test]
+    fn pairs_interleaved_and_reused_ids() {
+        let submit = |id| Packet::submit(id, TransferType::Interrupt, 0x81, 8, vec![]);
+        let (s1, s2) = (submit(1), submit(2));
+        let (c1, c2) = (s1.complete(UrbStatus::Success, 1, vec![1]), s2.complete(UrbStatus::Success, 1, vec![2]));
+        let transactions = pair(vec![s1.clone(), s2.clone(), c2.clone(), s1.clone(), c1.clone(), c1.clone()]);
+        assert_eq!(transactions.len(), 4);
+        assert_eq!((transactions[0].submit(), transactions[0].complete()), (Some(&s1), None));
+        assert_eq!((transactions[1].submit(), transactions[1].complete()), (Some(&s2), Some(&c2)));
+        assert_eq!((transactions[2].submit(), transactions[2].complete()), (Some(&s1), Some(&c1)));
+        assert_eq!((transactions[3].submit(), transactions[3].complete()), (None, Some(&c1)));
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+use libusb::{DeviceHandle, Result as UsbResult, Error as UsbError, Context, AsyncGroup, Transfer};
+use capture::{Capture, CaptureError};
+use transaction::{self, Transaction};
+use usb::{Packet, TransferType, Direction, UrbStatus};
+
+/// Asynchronous access to a device. Transfers are submitted with the
+/// `send_*` functions and their results are collected with `recv`.
+///
+/// Each pipe is an independent stream: transfers of a pipe complete in the
+/// order they were submitted, regardless of the other pipes.
+pub trait Transport {
+    fn send_control(&mut self, endpoint_direction: u8, buf: Vec<u8>, request_type: u8,
+                    request: u8, value: u16, index: u16) -> UsbResult<()>;
+
+    fn send_interrupt(&mut self, endpoint_direction: u8, buf: Vec<u8>) -> UsbResult<()>;
+
+    /// Waits for the oldest transfer submitted to the pipe of
+    /// `endpoint_direction` to finish and returns its data
+    fn recv(&mut self, endpoint_direction: u8) -> UsbResult<Vec<u8>>;
+}
+
+/// Pipe of an endpoint address. Control transfers of both directions share
+/// the default pipe 0.
+pub fn pipe(endpoint_direction: u8) -> u8 {
+    if endpoint_direction & 0x7f == 0 { 0 } else { endpoint_direction }
+}
+
+/// Submits transfers with libusb, using a group of transfers per pipe so
+/// they can be waited for separately
+pub struct LibusbTransport<'a> {
+    context: &'a Context,
+    handle: &'a DeviceHandle<'a>,
+    groups: HashMap<u8, AsyncGroup<'a>>,
+    timeout: Duration,
+}
+
+impl<'a> LibusbTransport<'a> {
+    pub fn new(context: &'a Context, handle: &'a DeviceHandle<'a>, timeout: Duration) -> LibusbTransport<'a> {
+        LibusbTransport {
+            context: context,
+            handle: handle,
+            groups: HashMap::new(),
+            timeout: timeout,
+        }
+    }
+
+    fn submit(&mut self, endpoint_direction: u8, transfer: Transfer<'a>) -> UsbResult<()> {
+        let context = self.context;
+        self.groups.entry(pipe(endpoint_direction)).or_insert_with(|| AsyncGroup::new(context)).submit(transfer)
+    }
+}
+
+impl<'a> Transport for LibusbTransport<'a> {
+    fn send_control(&mut self, endpoint_direction: u8, buf: Vec<u8>, request_type: u8,
+                    request: u8, value: u16, index: u16) -> UsbResult<()> {
+        let transfer = Transfer::control(
+                self.handle,
+                endpoint_direction,
+                buf,
+                request_type,
+                request,
+                value,
+                index,
+                self.timeout
+        );
+        self.submit(endpoint_direction, transfer)
+    }
+
+    fn send_interrupt(&mut self, endpoint_direction: u8, buf: Vec<u8>) -> UsbResult<()> {
+        let transfer = Transfer::interrupt(self.handle, endpoint_direction, buf, self.timeout);
+        self.submit(endpoint_direction, transfer)
+    }
+
+    fn recv(&mut self, endpoint_direction: u8) -> UsbResult<Vec<u8>> {
+        let group = match self.groups.get_mut(&pipe(endpoint_direction)) {
+            Some(group) => group,
+            None => return Err(UsbError::NotFound),
+        };
+        Ok(try!(group.wait_any()).actual().iter().cloned().collect())
+    }
+}
+
+/// Answers the transfers of a device which isn't there, e.g. with recorded
+/// completions
+pub trait Script {
+    fn control(&mut self, endpoint_direction: u8, buf: &[u8], request_type: u8,
+               request: u8, value: u16, index: u16) -> UsbResult<Vec<u8>>;
+
+    /// Called when the transfer is received, so IN transfers can return data
+    /// caused by transfers sent after them
+    fn interrupt(&mut self, endpoint_direction: u8, buf: &[u8]) -> UsbResult<Vec<u8>>;
+}
+
+/// A transfer waiting to be received
+struct InFlight {
+    endpoint_direction: u8,
+    buf: Vec<u8>,
+    /// Answer of control transfers, interrupt transfers are answered when
+    /// received
+    result: Option<UsbResult<Vec<u8>>>,
+}
+
+/// In-memory device answered by a `Script`, by default with the completions
+/// recorded in a capture
+pub struct MockTransport<S: Script = Recording> {
+    script: S,
+    in_flight: Vec<InFlight>,
+}
+
+impl MockTransport<Recording> {
+    pub fn new(transactions: Vec<Transaction>) -> MockTransport {
+        MockTransport::with_script(Recording::new(transactions))
+    }
+
+    pub fn from_file(path: &Path) -> Result<MockTransport, CaptureError> {
+        let packets = try!(try!(Capture::from_file(path)).read_all());
+        Ok(MockTransport::new(transaction::pair(packets)))
+    }
+
+    /// Number of recorded transactions which were not requested
+    #[allow(unused)]
+    pub fn remaining(&self) -> usize {
+        self.script.remaining()
+    }
+}
+
+impl<S: Script> MockTransport<S> {
+    pub fn with_script(script: S) -> MockTransport<S> {
+        MockTransport {
+            script: script,
+            in_flight: Vec::new(),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn script(&self) -> &S {
+        &self.script
+    }
+}
+
+impl<S: Script> Transport for MockTransport<S> {
+    fn send_control(&mut self, endpoint_direction: u8, buf: Vec<u8>, request_type: u8,
+                    request: u8, value: u16, index: u16) -> UsbResult<()> {
+        let result = self.script.control(endpoint_direction, &buf, request_type, request, value, index);
+        self.in_flight.push(InFlight { endpoint_direction: endpoint_direction, buf: Vec::new(), result: Some(result) });
+        Ok(())
+    }
+
+    fn send_interrupt(&mut self, endpoint_direction: u8, buf: Vec<u8>) -> UsbResult<()> {
+        self.in_flight.push(InFlight { endpoint_direction: endpoint_direction, buf: buf, result: None });
+        Ok(())
+    }
+
+    fn recv(&mut self, endpoint_direction: u8) -> UsbResult<Vec<u8>> {
+        let next = match self.in_flight.iter().position(|f| pipe(f.endpoint_direction) == pipe(endpoint_direction)) {
+            Some(i) => i,
+            None => return Err(UsbError::NotFound),
+        };
+        let f = self.in_flight.remove(next);
+        match f.result {
+            Some(result) => result,
+            None => self.script.interrupt(f.endpoint_direction, &f.buf),
+        }
+    }
+}
+
+/// Completions recorded in a capture.
+///
+/// A transfer is matched to the first not yet used recorded Submit with the
+/// same endpoint and, for control transfers, the same setup packet and
+/// data. Unknown control requests are stalled, unknown interrupt transfers
+/// time out.
+pub struct Recording {
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl Recording {
+    pub fn new(transactions: Vec<Transaction>) -> Recording {
+        Recording { transactions: transactions.into_iter().map(Some).collect() }
+    }
+
+    /// Number of recorded transactions which were not requested
+    pub fn remaining(&self) -> usize {
+        self.transactions.iter().filter(|t| t.as_ref().map_or(false, |t| t.is_complete())).count()
+    }
+
+    fn respond<F>(&mut self, matches: F, unmatched: UsbError) -> UsbResult<Vec<u8>>
+            where F: Fn(&Packet) -> bool {
+        let pos = self.transactions.iter().position(|t| match *t {
+            Some(ref t) => t.is_complete() && matches(t.submit().unwrap()),
+            None => false,
+        });
+        let pos = match pos {
+            Some(pos) => pos,
+            None => return Err(unmatched),
+        };
+        let (_, complete) = self.transactions[pos].take().unwrap().into_parts();
+        let complete = complete.unwrap();
+        match complete.get_status() {
+            UrbStatus::Success => Ok(complete.get_data().to_vec()),
+            status => Err(status.to_usb_error().unwrap_or(UsbError::Other)),
+        }
+    }
+}
+
+impl Script for Recording {
+    fn control(&mut self, endpoint_direction: u8, buf: &[u8], request_type: u8,
+               request: u8, value: u16, index: u16) -> UsbResult<Vec<u8>> {
+        self.respond(|s| {
+            s.get_transfer_type() == TransferType::Control
+                && s.get_endpoint_direction() == endpoint_direction
+                && s.get_bm_request_type() == request_type
+                && s.get_b_request() == request
+                && s.get_value() == value
+                && s.get_index() == index
+                && (s.get_direction() == Direction::In || s.get_data() == buf)
+        }, UsbError::Pipe)
+    }
+
+    fn interrupt(&mut self, endpoint_direction: u8, buf: &[u8]) -> UsbResult<Vec<u8>> {
+        self.respond(|s| {
+            s.get_transfer_type() == TransferType::Interrupt
+                && s.get_endpoint_direction() == endpoint_direction
+                && (s.get_direction() == Direction::In || s.get_data() == buf)
+        }, UsbError::Timeout)
+    }
+}
@@ -0,0 +1,828 @@
+use std::borrow::Cow;
+use std::error::Error as StdError;
+use std::fmt;
+use byteorder::{ByteOrder, NativeEndian};
+use libusb::Error;
+
+mod setup;
+mod usbpcap;
+
+pub use self::setup::{SetupRequest, RequestKind, Recipient, DescriptorType, ReportType};
+pub use self::usbpcap::UsbPcapDecoder;
+
+/// Length of the usbmon header in `DLT_USB_LINUX` captures
+pub const LEGACY_HEADER_LEN: usize = 48;
+/// Length of the usbmon header in `DLT_USB_LINUX_MMAPPED` captures
+pub const MMAPPED_HEADER_LEN: usize = 64;
+/// Length of one isochronous descriptor following the mmapped header
+const ISO_DESC_LEN: usize = 16;
+
+/// pcap link types whose packets can be decoded into a `Packet`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkType {
+    /// `DLT_USB_LINUX` (189), 48 byte usbmon header
+    UsbLinux,
+    /// `DLT_USB_LINUX_MMAPPED` (220), 64 byte usbmon header
+    UsbLinuxMmapped,
+    /// `DLT_USBPCAP` (249), variable length USBPcap header written on Windows
+    UsbPcap,
+}
+
+impl LinkType {
+    pub fn from_dlt(dlt: i32) -> Option<LinkType> {
+        match dlt {
+            189 => Some(LinkType::UsbLinux),
+            220 => Some(LinkType::UsbLinuxMmapped),
+            249 => Some(LinkType::UsbPcap),
+            _ => None,
+        }
+    }
+
+    pub fn dlt(&self) -> i32 {
+        match *self {
+            LinkType::UsbLinux => 189,
+            LinkType::UsbLinuxMmapped => 220,
+            LinkType::UsbPcap => 249,
+        }
+    }
+
+    /// Length of the pseudo header. USBPcap stores the actual length in
+    /// each packet, for it this is only the minimum.
+    pub fn header_len(&self) -> usize {
+        match *self {
+            LinkType::UsbLinux => LEGACY_HEADER_LEN,
+            LinkType::UsbLinuxMmapped => MMAPPED_HEADER_LEN,
+            LinkType::UsbPcap => usbpcap::MIN_HEADER_LEN,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PacketHead {
+    id: u64,
+    urb_type: u8,
+    transfer_type: u8,
+    endpoint_direction: u8,
+    device: u8,
+    bus_id: u16,
+    setup_request: u8,
+    data_present: u8,
+    sec: u64,
+    usec: u32,
+    status: u32,
+    length: u32,
+    data_length: u32,
+    bm_request_type: u8,
+    b_request: u8,
+    descriptor_index: u8,
+    descriptor_type: u8,
+    language_id: u16,
+    w_length: u16,
+    interval: u32,
+    start_frame: u32,
+    transfer_flags: u32,
+    num_iso_desc: u32,
+}
+
+impl PacketHead {
+    /// Decodes a usbmon header field by field.
+    ///
+    /// `bytes` must contain at least `link_type.header_len()` bytes. The
+    /// legacy header lacks the trailing fields, they are reported as 0.
+    fn parse<B: ByteOrder>(link_type: LinkType, bytes: &[u8]) -> PacketHead {
+        let mut head = PacketHead {
+            id: B::read_u64(&bytes[0..8]),
+            urb_type: bytes[8],
+            transfer_type: bytes[9],
+            endpoint_direction: bytes[10],
+            device: bytes[11],
+            bus_id: B::read_u16(&bytes[12..14]),
+            setup_request: bytes[14],
+            data_present: bytes[15],
+            sec: B::read_u64(&bytes[16..24]),
+            usec: B::read_u32(&bytes[24..28]),
+            status: B::read_u32(&bytes[28..32]),
+            length: B::read_u32(&bytes[32..36]),
+            data_length: B::read_u32(&bytes[36..40]),
+            bm_request_type: bytes[40],
+            b_request: bytes[41],
+            descriptor_index: bytes[42],
+            descriptor_type: bytes[43],
+            language_id: B::read_u16(&bytes[44..46]),
+            w_length: B::read_u16(&bytes[46..48]),
+            interval: 0,
+            start_frame: 0,
+            transfer_flags: 0,
+            num_iso_desc: 0,
+        };
+        if link_type == LinkType::UsbLinuxMmapped {
+            head.interval = B::read_u32(&bytes[48..52]);
+            head.start_frame = B::read_u32(&bytes[52..56]);
+            head.transfer_flags = B::read_u32(&bytes[56..60]);
+            head.num_iso_desc = B::read_u32(&bytes[60..64]);
+        }
+        head
+    }
+
+    /// Encodes this header as `DLT_USB_LINUX_MMAPPED` header in byte order `B`.
+    fn write<B: ByteOrder>(&self, bytes: &mut [u8]) {
+        B::write_u64(&mut bytes[0..8], self.id);
+        bytes[8] = self.urb_type;
+        bytes[9] = self.transfer_type;
+        bytes[10] = self.endpoint_direction;
+        bytes[11] = self.device;
+        B::write_u16(&mut bytes[12..14], self.bus_id);
+        bytes[14] = self.setup_request;
+        bytes[15] = self.data_present;
+        B::write_u64(&mut bytes[16..24], self.sec);
+        B::write_u32(&mut bytes[24..28], self.usec);
+        B::write_u32(&mut bytes[28..32], self.status);
+        B::write_u32(&mut bytes[32..36], self.length);
+        B::write_u32(&mut bytes[36..40], self.data_length);
+        bytes[40] = self.bm_request_type;
+        bytes[41] = self.b_request;
+        bytes[42] = self.descriptor_index;
+        bytes[43] = self.descriptor_type;
+        B::write_u16(&mut bytes[44..46], self.language_id);
+        B::write_u16(&mut bytes[46..48], self.w_length);
+        B::write_u32(&mut bytes[48..52], self.interval);
+        B::write_u32(&mut bytes[52..56], self.start_frame);
+        B::write_u32(&mut bytes[56..60], self.transfer_flags);
+        // isochronous descriptors are not retained
+        B::write_u32(&mut bytes[60..64], 0);
+    }
+
+    fn validate(&self) -> Result<(), ParseError> {
+        try!(UrbType::try_from(self.urb_type));
+        try!(TransferType::try_from(self.transfer_type));
+        if self.usec >= 1_000_000 {
+            return Err(ParseError::InvalidField { field: "usec", value: self.usec as u64 });
+        }
+        Ok(())
+    }
+}
+
+/// Reason why a captured packet could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseError {
+    /// The packet is shorter than the header and data it announces
+    Truncated { field: &'static str, needed: usize, available: usize },
+    /// A header field contains a value usbmon never writes
+    InvalidField { field: &'static str, value: u64 },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Truncated { field, needed, available } =>
+                write!(f, "truncated {}: needed {} bytes but only {} are available",
+                       field, needed, available),
+            ParseError::InvalidField { field, value } =>
+                write!(f, "invalid value 0x{:x} in field {}", value, field),
+        }
+    }
+}
+
+impl StdError for ParseError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseError::Truncated { .. } => "packet is truncated",
+            ParseError::InvalidField { .. } => "packet header contains an invalid field",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet<'a> {
+    head: PacketHead,
+    data: Cow<'a, [u8]>,
+}
+
+#[allow(unused)]
+impl<'a> Packet<'a> {
+    /// Parses a `DLT_USB_LINUX_MMAPPED` packet as handed out by libpcap,
+    /// which converts usbmon headers to host byte order while reading.
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Packet<'a>, ParseError> {
+        Packet::parse::<NativeEndian>(LinkType::UsbLinuxMmapped, bytes)
+    }
+
+    /// Parses a packet of the given link type whose usbmon header is stored
+    /// in byte order `B`.
+    ///
+    /// USBPcap packets are always little endian and carry neither timestamps
+    /// nor complete control transfers, they are decoded stage by stage. Use
+    /// `UsbPcapDecoder` to get usbmon-like Submit/Complete packets instead.
+    pub fn parse<B: ByteOrder>(link_type: LinkType, bytes: &'a [u8]) -> Result<Packet<'a>, ParseError> {
+        if link_type == LinkType::UsbPcap {
+            return usbpcap::Record::parse(bytes).map(|r| r.into_packet(0, 0));
+        }
+        let header_len = link_type.header_len();
+        if bytes.len() < header_len {
+            return Err(ParseError::Truncated {
+                field: "header",
+                needed: header_len,
+                available: bytes.len()
+            });
+        }
+        let head = PacketHead::parse::<B>(link_type, bytes);
+        try!(head.validate());
+
+        let mut offset = header_len;
+        // isochronous descriptors are stored between header and data
+        if head.transfer_type == 0 {
+            offset += head.num_iso_desc as usize * ISO_DESC_LEN;
+        }
+        let end = offset + head.data_length as usize;
+        if bytes.len() < end {
+            return Err(ParseError::Truncated {
+                field: "data",
+                needed: end,
+                available: bytes.len()
+            });
+        }
+        Ok(Packet { head: head, data: Cow::Borrowed(&bytes[offset..end]) })
+    }
+
+    /// Encodes this packet as `DLT_USB_LINUX_MMAPPED` packet in host byte
+    /// order, regardless of the format it was read from.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; MMAPPED_HEADER_LEN];
+        let mut head = self.head;
+        head.data_length = self.data.len() as u32;
+        head.write::<NativeEndian>(&mut bytes);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Submit of a transfer as usbmon records it. IN transfers carry no
+    /// data, `length` is the length of the transfer buffer.
+    pub fn submit(id: u64, transfer_type: TransferType, endpoint_direction: u8, length: u32,
+                  data: Vec<u8>) -> Packet<'static> {
+        let dir_in = endpoint_direction & 0x80 == 0x80;
+        let head = PacketHead {
+            id: id,
+            urb_type: UrbType::Submit.into(),
+            transfer_type: transfer_type.into(),
+            endpoint_direction: endpoint_direction,
+            device: 0,
+            bus_id: 0,
+            setup_request: b'-',
+            data_present: if dir_in { b'<' } else { 0 },
+            sec: 0,
+            usec: 0,
+            status: UrbStatus::InProgress.into(),
+            length: length,
+            data_length: data.len() as u32,
+            bm_request_type: 0,
+            b_request: 0,
+            descriptor_index: 0,
+            descriptor_type: 0,
+            language_id: 0,
+            w_length: 0,
+            interval: 0,
+            start_frame: 0,
+            // URB_DIR_IN
+            transfer_flags: if dir_in { 0x200 } else { 0 },
+            num_iso_desc: 0,
+        };
+        Packet { head: head, data: Cow::Owned(data) }
+    }
+
+    /// Completion of this Submit. OUT transfers carry no data, `length` is
+    /// the number of bytes transferred.
+    pub fn complete(&self, status: UrbStatus, length: u32, data: Vec<u8>) -> Packet<'static> {
+        let mut head = self.head;
+        head.urb_type = UrbType::Complete.into();
+        head.setup_request = b'-';
+        head.data_present = if self.get_direction() == Direction::Out { b'>' } else { 0 };
+        head.status = status.into();
+        head.length = length;
+        head.data_length = data.len() as u32;
+        head.bm_request_type = 0;
+        head.b_request = 0;
+        head.descriptor_index = 0;
+        head.descriptor_type = 0;
+        head.language_id = 0;
+        head.w_length = 0;
+        Packet { head: head, data: Cow::Owned(data) }
+    }
+
+    /// Adds the setup packet of a control Submit
+    pub fn set_setup(&mut self, request_type: u8, request: u8, value: u16, index: u16, length: u16) {
+        self.head.setup_request = 0;
+        self.head.bm_request_type = request_type;
+        self.head.b_request = request;
+        self.head.descriptor_index = value as u8;
+        self.head.descriptor_type = (value >> 8) as u8;
+        self.head.language_id = index;
+        self.head.w_length = length;
+    }
+
+    pub fn set_address(&mut self, bus_id: u16, device: u8) {
+        self.head.bus_id = bus_id;
+        self.head.device = device;
+    }
+
+    pub fn set_time(&mut self, sec: u64, usec: u32) {
+        self.head.sec = sec;
+        self.head.usec = usec;
+    }
+
+    /// Detaches this packet from the buffer it was parsed from.
+    pub fn into_owned(self) -> Packet<'static> {
+        Packet { head: self.head, data: Cow::Owned(self.data.into_owned()) }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.head.id
+    }
+    pub fn get_urb_type(&self) -> UrbType {
+        UrbType::from(self.head.urb_type)
+    }
+    pub fn get_transfer_type(&self) -> TransferType {
+        TransferType::from(self.head.transfer_type)
+    }
+    pub fn get_direction(&self) -> Direction {
+        Direction::from((self.head.endpoint_direction & 0x80) == 0x80)
+    }
+    pub fn get_endpoint(&self) -> u8 {
+        self.head.endpoint_direction & 0x7f
+    }
+    pub fn get_endpoint_direction(&self) -> u8 {
+        self.head.endpoint_direction
+    }
+    pub fn get_device(&self) -> u8 {
+        self.head.device
+    }
+    pub fn get_bus_id(&self) -> u16 {
+        self.head.bus_id
+    }
+    pub fn get_setup_request(&self) -> u8 {
+        self.head.setup_request
+    }
+    pub fn is_data_present(&self) -> bool {
+        // yep, you read correctly!
+        // if data is present, the value is actually 0x00
+        self.head.data_present == 0x00
+    }
+    pub fn get_sec(&self) -> u64 {
+        self.head.sec
+    }
+    pub fn get_usec(&self) -> u32 {
+        self.head.usec
+    }
+    pub fn get_status(&self) -> UrbStatus {
+        UrbStatus::from(self.head.status)
+    }
+    pub fn get_length(&self) -> u32 {
+        self.head.length
+    }
+    pub fn get_data_length(&self) -> u32 {
+        self.head.data_length
+    }
+    pub fn get_bm_request_type(&self) -> u8 {
+        self.head.bm_request_type
+    }
+    pub fn get_b_request(&self) -> u8 {
+        self.head.b_request
+    }
+    pub fn get_descriptor_index(&self) -> u8 {
+        self.head.descriptor_index
+    }
+    pub fn get_descriptor_type(&self) -> u8 {
+        self.head.descriptor_type
+    }
+    pub fn get_value(&self) -> u16 {
+        (self.head.descriptor_type as u16) << 8 | self.head.descriptor_index as u16
+    }
+    pub fn get_language_id(&self) -> u16 {
+        self.head.language_id
+    }
+    /// `wIndex` of the setup packet, which is the language id only for
+    /// string descriptors
+    pub fn get_index(&self) -> u16 {
+        self.head.language_id
+    }
+    pub fn get_w_length(&self) -> u16 {
+        self.head.w_length
+    }
+    pub fn get_interval(&self) -> u32 {
+        self.head.interval
+    }
+    pub fn get_start_frame(&self) -> u32 {
+        self.head.start_frame
+    }
+    pub fn get_transfer_flags(&self) -> u32 {
+        self.head.transfer_flags
+    }
+    pub fn get_num_iso_desc(&self) -> u32 {
+        self.head.num_iso_desc
+    }
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+    /// Replaces the data, adjusting the lengths of the header
+    pub fn set_data(&mut self, data: Vec<u8>) {
+        self.head.length = data.len() as u32;
+        self.head.data_length = data.len() as u32;
+        if self.has_setup() {
+            self.head.w_length = data.len() as u16;
+        }
+        self.data = Cow::Owned(data);
+    }
+    pub fn has_setup(&self) -> bool {
+        // like data_present, 0x00 means the setup packet is present
+        self.head.transfer_type == 0x02 && self.head.setup_request == 0x00
+    }
+    /// Decodes the setup packet of control Submits
+    pub fn get_request(&self) -> Option<SetupRequest> {
+        if !self.has_setup() {
+            return None;
+        }
+        Some(SetupRequest::decode(self.head.bm_request_type, self.head.b_request,
+                                  self.get_value(), self.head.language_id, self.head.w_length))
+    }
+
+    /// Compares this packet with another packet, ignoring autogenerated
+    /// and session based headers
+    pub fn same(&self, other: &Self) -> bool {
+        self.head.urb_type == other.head.urb_type
+            && self.head.transfer_type == other.head.transfer_type
+            && self.head.endpoint_direction == other.head.endpoint_direction
+            && self.head.setup_request == other.head.setup_request
+            && self.head.data_present == other.head.data_present
+            && self.head.status == other.head.status
+            && self.head.length == other.head.length
+            && self.head.data_length == other.head.data_length
+            && self.head.bm_request_type == other.head.bm_request_type
+            && self.head.b_request == other.head.b_request
+            && self.head.descriptor_index == other.head.descriptor_index
+            && self.head.descriptor_type == other.head.descriptor_type
+            && self.head.language_id == other.head.language_id
+            && self.head.w_length == other.head.w_length
+            && self.head.transfer_flags == other.head.transfer_flags
+            && self.head.num_iso_desc == other.head.num_iso_desc
+            && self.data == other.data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UrbType {
+    Submit, Complete, Error, Unknown(u8)
+}
+
+impl UrbType {
+    /// Strict conversion, failing for values usbmon never writes
+    pub fn try_from(byte: u8) -> Result<UrbType, ParseError> {
+        match UrbType::from(byte) {
+            UrbType::Unknown(b) => Err(ParseError::InvalidField { field: "urb_type", value: b as u64 }),
+            t => Ok(t),
+        }
+    }
+}
+
+impl From<u8> for UrbType {
+    fn from(byte: u8) -> Self {
+        return match byte {
+            0x43u8 => UrbType::Complete,
+            0x45u8 => UrbType::Error,
+            0x53u8 => UrbType::Submit,
+            b => UrbType::Unknown(b),
+        }
+    }
+}
+
+impl From<UrbType> for u8 {
+    fn from(urb_type: UrbType) -> Self {
+        match urb_type {
+            UrbType::Complete => 0x43,
+            UrbType::Error => 0x45,
+            UrbType::Submit => 0x53,
+            UrbType::Unknown(b) => b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferType {
+    Isochronous, Interrupt, Control, Bulk, Unknown(u8)
+}
+
+impl TransferType {
+    /// Strict conversion, failing for values usbmon never writes
+    pub fn try_from(byte: u8) -> Result<TransferType, ParseError> {
+        match TransferType::from(byte) {
+            TransferType::Unknown(b) => Err(ParseError::InvalidField { field: "transfer_type", value: b as u64 }),
+            t => Ok(t),
+        }
+    }
+}
+
+impl From<u8> for TransferType {
+    fn from(byte: u8) -> Self {
+        return match byte {
+            0x00 => TransferType::Isochronous,
+            0x01 => TransferType::Interrupt,
+            0x02 => TransferType::Control,
+            0x03 => TransferType::Bulk,
+            b => TransferType::Unknown(b),
+        }
+    }
+}
+
+impl From<TransferType> for u8 {
+    fn from(transfer_type: TransferType) -> Self {
+        match transfer_type {
+            TransferType::Isochronous => 0x00,
+            TransferType::Interrupt => 0x01,
+            TransferType::Control => 0x02,
+            TransferType::Bulk => 0x03,
+            TransferType::Unknown(b) => b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    In, Out
+}
+
+impl From<bool> for Direction {
+    fn from(b: bool) -> Self {
+        return match b {
+            true => Direction::In,
+            false => Direction::Out
+        }
+    }
+}
+
+/// URB status as negative Linux errno, see `Documentation/usb/error-codes.txt`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UrbStatus {
+    Success,
+    /// -EPERM
+    Perm,
+    /// -ENOENT
+    NoEnt,
+    /// -EIO
+    Io,
+    /// -ENXIO
+    NxIo,
+    /// -ENOEXEC
+    NoExec,
+    /// -EAGAIN
+    Again,
+    /// -ENOMEM
+    NoMem,
+    /// -EBUSY
+    Busy,
+    /// -EXDEV
+    XDev,
+    /// -ENODEV
+    NoDev,
+    /// -EINVAL
+    Inval,
+    /// -EFBIG
+    FBig,
+    /// -ENOSPC
+    NoSpc,
+    /// -EPIPE
+    Pipe,
+    /// -ETIME
+    Time,
+    /// -ENOSR
+    NoSr,
+    /// -ECOMM
+    Comm,
+    /// -EPROTO
+    Proto,
+    /// -EOVERFLOW
+    Overflow,
+    /// -EILSEQ
+    IlSeq,
+    /// -EMSGSIZE
+    MsgSize,
+    /// -ECONNRESET
+    ConnReset,
+    /// -ESHUTDOWN
+    Shutdown,
+    /// -ETIMEDOUT
+    TimedOut,
+    /// -EHOSTUNREACH
+    HostUnreach,
+    /// -EINPROGRESS
+    InProgress,
+    /// -EREMOTEIO
+    RemoteIo,
+    Unknown(i32),
+}
+
+const URB_STATUS_ERRNOS: [(UrbStatus, i32); 28] = [
+    (UrbStatus::Success, 0),
+    (UrbStatus::Perm, 1),
+    (UrbStatus::NoEnt, 2),
+    (UrbStatus::Io, 5),
+    (UrbStatus::NxIo, 6),
+    (UrbStatus::NoExec, 8),
+    (UrbStatus::Again, 11),
+    (UrbStatus::NoMem, 12),
+    (UrbStatus::Busy, 16),
+    (UrbStatus::XDev, 18),
+    (UrbStatus::NoDev, 19),
+    (UrbStatus::Inval, 22),
+    (UrbStatus::FBig, 27),
+    (UrbStatus::NoSpc, 28),
+    (UrbStatus::Pipe, 32),
+    (UrbStatus::Time, 62),
+    (UrbStatus::NoSr, 63),
+    (UrbStatus::Comm, 70),
+    (UrbStatus::Proto, 71),
+    (UrbStatus::Overflow, 75),
+    (UrbStatus::IlSeq, 84),
+    (UrbStatus::MsgSize, 90),
+    (UrbStatus::ConnReset, 104),
+    (UrbStatus::Shutdown, 108),
+    (UrbStatus::TimedOut, 110),
+    (UrbStatus::HostUnreach, 113),
+    (UrbStatus::InProgress, 115),
+    (UrbStatus::RemoteIo, 121),
+];
+
+impl UrbStatus {
+    /// Strict conversion, failing for errnos not used as URB status
+    pub fn try_from(i: u32) -> Result<UrbStatus, ParseError> {
+        match UrbStatus::from(i) {
+            UrbStatus::Unknown(_) => Err(ParseError::InvalidField { field: "status", value: i as u64 }),
+            s => Ok(s),
+        }
+    }
+
+    /// Returns the status as (negative) errno
+    pub fn errno(&self) -> i32 {
+        if let UrbStatus::Unknown(i) = *self {
+            return i;
+        }
+        URB_STATUS_ERRNOS.iter()
+            .find(|&&(status, _)| status == *self)
+            .map(|&(_, errno)| -errno)
+            .unwrap()
+    }
+
+    /// Status usbmon most likely recorded for a transfer libusb reports
+    /// as failed with `err`
+    pub fn from_usb_error(err: Error) -> UrbStatus {
+        match err {
+            Error::Pipe => UrbStatus::Pipe,
+            Error::NoDevice => UrbStatus::NoDev,
+            // libusb cancels timed out transfers
+            Error::Timeout | Error::NotFound => UrbStatus::NoEnt,
+            Error::Overflow => UrbStatus::Overflow,
+            Error::Busy => UrbStatus::Busy,
+            Error::InvalidParam => UrbStatus::Inval,
+            Error::NoMem => UrbStatus::NoMem,
+            Error::Access => UrbStatus::Perm,
+            _ => UrbStatus::Io,
+        }
+    }
+
+    /// Error libusb reports for a transfer completed with this status,
+    /// `None` for success and statuses libusb has no error for, like
+    /// `InProgress` or unknown errnos
+    pub fn to_usb_error(&self) -> Option<Error> {
+        match *self {
+            UrbStatus::Success => None,
+            UrbStatus::Pipe => Some(Error::Pipe),
+            UrbStatus::NoDev | UrbStatus::Shutdown => Some(Error::NoDevice),
+            UrbStatus::TimedOut | UrbStatus::Time | UrbStatus::NoEnt => Some(Error::Timeout),
+            UrbStatus::Overflow => Some(Error::Overflow),
+            UrbStatus::Busy => Some(Error::Busy),
+            UrbStatus::Inval => Some(Error::InvalidParam),
+            UrbStatus::NoMem => Some(Error::NoMem),
+            UrbStatus::Perm => Some(Error::Access),
+            UrbStatus::Io | UrbStatus::Proto | UrbStatus::IlSeq
+                | UrbStatus::Comm | UrbStatus::NoSr | UrbStatus::RemoteIo => Some(Error::Io),
+            UrbStatus::NxIo | UrbStatus::NoExec | UrbStatus::Again | UrbStatus::XDev | UrbStatus::FBig
+                | UrbStatus::NoSpc | UrbStatus::MsgSize | UrbStatus::ConnReset | UrbStatus::HostUnreach
+                => Some(Error::Other),
+            UrbStatus::InProgress | UrbStatus::Unknown(_) => None,
+        }
+    }
+}
+
+impl From<u32> for UrbStatus {
+    fn from(i: u32) -> Self {
+        let errno = (i as i32).wrapping_neg();
+        URB_STATUS_ERRNOS.iter()
+            .find(|&&(_, e)| e == errno)
+            .map(|&(status, _)| status)
+            .unwrap_or(UrbStatus::Unknown(i as i32))
+    }
+}
+
+impl From<UrbStatus> for u32 {
+    fn from(status: UrbStatus) -> Self {
+        status.errno() as u32
+    }
+}
+
+impl PartialEq<Error> for UrbStatus {
+    fn eq(&self, err: &Error) -> bool {
+        self.to_usb_error() == Some(*err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, NativeEndian};
+    use libusb::Error;
+    use capture::Capture;
+    use super::{Packet, ParseError, UrbType, TransferType, UrbStatus, MMAPPED_HEADER_LEN, URB_STATUS_ERRNOS};
+
+    fn handshake() -> Vec<Packet<'static>> {
+        Capture::from_file("pcap/g910/handshake/handshake.pcap").unwrap().read_all().unwrap()
+    }
+
+    #[test]
+    fn decodes_encoded_packets() {
+        for packet in handshake() {
+            assert_eq!(Packet::from_bytes(&packet.to_bytes()).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let packets = handshake();
+        let bytes = packets.iter().find(|p| p.get_data().len() > 1).unwrap().to_bytes();
+        for len in 0..MMAPPED_HEADER_LEN {
+            assert_eq!(Packet::from_bytes(&bytes[..len]),
+                       Err(ParseError::Truncated { field: "header", needed: MMAPPED_HEADER_LEN, available: len }));
+        }
+        let len = bytes.len();
+        assert_eq!(Packet::from_bytes(&bytes[..len - 1]),
+                   Err(ParseError::Truncated { field: "data", needed: len, available: len - 1 }));
+        // bytes beyond the announced data are not part of the packet
+        let mut longer = bytes.clone();
+        longer.push(0xaa);
+        assert_eq!(Packet::from_bytes(&longer).unwrap().get_data(), &bytes[MMAPPED_HEADER_LEN..]);
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        let bytes = handshake()[0].to_bytes();
+        let mut invalid = bytes.clone();
+        invalid[8] = b'X';
+        assert_eq!(Packet::from_bytes(&invalid), Err(ParseError::InvalidField { field: "urb_type", value: 0x58 }));
+        let mut invalid = bytes.clone();
+        invalid[9] = 4;
+        assert_eq!(Packet::from_bytes(&invalid), Err(ParseError::InvalidField { field: "transfer_type", value: 4 }));
+        let mut invalid = bytes.clone();
+        NativeEndian::write_u32(&mut invalid[24..28], 1_000_000);
+        assert_eq!(Packet::from_bytes(&invalid), Err(ParseError::InvalidField { field: "usec", value: 1_000_000 }));
+    }
+
+    #[test]
+    fn skips_isochronous_descriptors() {
+        let submit = Packet::submit(1, TransferType::Isochronous, 0x01, 3, vec![1, 2, 3]).to_bytes();
+        let mut bytes = submit[..MMAPPED_HEADER_LEN].to_vec();
+        NativeEndian::write_u32(&mut bytes[60..64], 2);
+        bytes.extend_from_slice(&[0xee; 32]);
+        bytes.extend_from_slice(&submit[MMAPPED_HEADER_LEN..]);
+        let packet = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!((packet.get_num_iso_desc(), packet.get_data()), (2, &[1, 2, 3][..]));
+        assert_eq!(Packet::from_bytes(&bytes[..bytes.len() - 1]),
+                   Err(ParseError::Truncated { field: "data", needed: bytes.len(), available: bytes.len() - 1 }));
+    }
+
+    #[test]
+    fn converts_fields_losslessly() {
+        for b in 0..256 {
+            let b = b as u8;
+            assert_eq!(u8::from(UrbType::from(b)), b);
+            assert_eq!(u8::from(TransferType::from(b)), b);
+        }
+        for &(status, errno) in URB_STATUS_ERRNOS.iter() {
+            assert_eq!(UrbStatus::from(u32::from(status)), status);
+            assert_eq!(status.errno(), -errno);
+        }
+        let unknown = UrbStatus::from(-200i32 as u32);
+        assert_eq!((unknown, u32::from(unknown)), (UrbStatus::Unknown(-200), -200i32 as u32));
+        assert_eq!(UrbStatus::try_from(-200i32 as u32),
+                   Err(ParseError::InvalidField { field: "status", value: -200i32 as u32 as u64 }));
+        assert_eq!(UrbType::try_from(b'X'), Err(ParseError::InvalidField { field: "urb_type", value: 0x58 }));
+    }
+
+    #[test]
+    fn maps_statuses_to_libusb_errors() {
+        assert_eq!(UrbStatus::Success.to_usb_error(), None);
+        assert_eq!(UrbStatus::Pipe.to_usb_error(), Some(Error::Pipe));
+        assert_eq!(UrbStatus::NoEnt.to_usb_error(), Some(Error::Timeout));
+        assert_eq!(UrbStatus::ConnReset.to_usb_error(), Some(Error::Other));
+        // still running or not an URB status at all
+        assert_eq!(UrbStatus::InProgress.to_usb_error(), None);
+        assert_eq!(UrbStatus::Unknown(-200).to_usb_error(), None);
+        assert!(UrbStatus::Pipe == Error::Pipe);
+        assert!(UrbStatus::InProgress != Error::Other);
+        assert!(UrbStatus::Unknown(-200) != Error::Other);
+        assert_eq!(UrbStatus::from_usb_error(Error::Timeout), UrbStatus::NoEnt);
+    }
+}
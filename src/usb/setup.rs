@@ -0,0 +1,293 @@
+use std::fmt;
+
+/// Type of a request, bits 5 and 6 of `bmRequestType`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestKind {
+    Standard, Class, Vendor, Reserved
+}
+
+impl From<u8> for RequestKind {
+    fn from(request_type: u8) -> Self {
+        match (request_type >> 5) & 0x03 {
+            0 => RequestKind::Standard,
+            1 => RequestKind::Class,
+            2 => RequestKind::Vendor,
+            _ => RequestKind::Reserved,
+        }
+    }
+}
+
+/// Recipient of a request, bits 0 to 4 of `bmRequestType`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recipient {
+    Device, Interface, Endpoint, Other, Unknown(u8)
+}
+
+impl From<u8> for Recipient {
+    fn from(request_type: u8) -> Self {
+        match request_type & 0x1f {
+            0 => Recipient::Device,
+            1 => Recipient::Interface,
+            2 => Recipient::Endpoint,
+            3 => Recipient::Other,
+            r => Recipient::Unknown(r),
+        }
+    }
+}
+
+/// Descriptor type, the high byte of `wValue` of GET_DESCRIPTOR
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DescriptorType {
+    Device,
+    Configuration,
+    String,
+    Interface,
+    Endpoint,
+    DeviceQualifier,
+    OtherSpeedConfiguration,
+    InterfacePower,
+    Bos,
+    Hid,
+    Report,
+    Physical,
+    Unknown(u8),
+}
+
+impl From<u8> for DescriptorType {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0x01 => DescriptorType::Device,
+            0x02 => DescriptorType::Configuration,
+            0x03 => DescriptorType::String,
+            0x04 => DescriptorType::Interface,
+            0x05 => DescriptorType::Endpoint,
+            0x06 => DescriptorType::DeviceQualifier,
+            0x07 => DescriptorType::OtherSpeedConfiguration,
+            0x08 => DescriptorType::InterfacePower,
+            0x0f => DescriptorType::Bos,
+            0x21 => DescriptorType::Hid,
+            0x22 => DescriptorType::Report,
+            0x23 => DescriptorType::Physical,
+            b => DescriptorType::Unknown(b),
+        }
+    }
+}
+
+/// HID report type, the high byte of `wValue` of GET_REPORT and SET_REPORT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportType {
+    Input, Output, Feature, Unknown(u8)
+}
+
+impl From<u8> for ReportType {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0x01 => ReportType::Input,
+            0x02 => ReportType::Output,
+            0x03 => ReportType::Feature,
+            b => ReportType::Unknown(b),
+        }
+    }
+}
+
+/// Decoded setup packet of a control transfer.
+///
+/// Class requests addressed to an interface are assumed to be HID requests,
+/// as those are the only class requests the supported devices understand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetupRequest {
+    GetStatus { recipient: Recipient, index: u16 },
+    ClearFeature { recipient: Recipient, feature: u16, index: u16 },
+    SetFeature { recipient: Recipient, feature: u16, index: u16 },
+    SetAddress { address: u16 },
+    GetDescriptor { kind: DescriptorType, index: u8, lang: u16, length: u16 },
+    SetDescriptor { kind: DescriptorType, index: u8, lang: u16, length: u16 },
+    GetConfiguration,
+    SetConfiguration { config: u8 },
+    GetInterface { interface: u16 },
+    SetInterface { interface: u16, alt_setting: u16 },
+    SynchFrame { endpoint: u16 },
+    GetReport { report_type: ReportType, report_id: u8, interface: u16, length: u16 },
+    GetIdle { report_id: u8, interface: u16 },
+    GetProtocol { interface: u16 },
+    SetReport { report_type: ReportType, report_id: u8, interface: u16 },
+    /// `duration` is given in units of 4ms, 0 means indefinite
+    SetIdle { duration: u8, report_id: u8, interface: u16 },
+    SetProtocol { protocol: u16, interface: u16 },
+    Other { request_type: u8, request: u8, value: u16, index: u16, length: u16 },
+}
+
+impl SetupRequest {
+    pub fn decode(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> SetupRequest {
+        let (high, low) = ((value >> 8) as u8, value as u8);
+        let recipient = Recipient::from(request_type);
+        match (RequestKind::from(request_type), recipient, request) {
+            (RequestKind::Standard, _, 0x00) =>
+                SetupRequest::GetStatus { recipient: recipient, index: index },
+            (RequestKind::Standard, _, 0x01) =>
+                SetupRequest::ClearFeature { recipient: recipient, feature: value, index: index },
+            (RequestKind::Standard, _, 0x03) =>
+                SetupRequest::SetFeature { recipient: recipient, feature: value, index: index },
+            (RequestKind::Standard, Recipient::Device, 0x05) =>
+                SetupRequest::SetAddress { address: value },
+            (RequestKind::Standard, _, 0x06) => SetupRequest::GetDescriptor {
+                kind: DescriptorType::from(high),
+                index: low,
+                lang: index,
+                length: length,
+            },
+            (RequestKind::Standard, _, 0x07) => SetupRequest::SetDescriptor {
+                kind: DescriptorType::from(high),
+                index: low,
+                lang: index,
+                length: length,
+            },
+            (RequestKind::Standard, Recipient::Device, 0x08) => SetupRequest::GetConfiguration,
+            (RequestKind::Standard, Recipient::Device, 0x09) =>
+                SetupRequest::SetConfiguration { config: low },
+            (RequestKind::Standard, Recipient::Interface, 0x0a) =>
+                SetupRequest::GetInterface { interface: index },
+            (RequestKind::Standard, Recipient::Interface, 0x0b) =>
+                SetupRequest::SetInterface { interface: index, alt_setting: value },
+            (RequestKind::Standard, Recipient::Endpoint, 0x0c) =>
+                SetupRequest::SynchFrame { endpoint: index },
+            (RequestKind::Class, Recipient::Interface, 0x01) => SetupRequest::GetReport {
+                report_type: ReportType::from(high),
+                report_id: low,
+                interface: index,
+                length: length,
+            },
+            (RequestKind::Class, Recipient::Interface, 0x02) =>
+                SetupRequest::GetIdle { report_id: low, interface: index },
+            (RequestKind::Class, Recipient::Interface, 0x03) =>
+                SetupRequest::GetProtocol { interface: index },
+            (RequestKind::Class, Recipient::Interface, 0x09) => SetupRequest::SetReport {
+                report_type: ReportType::from(high),
+                report_id: low,
+                interface: index,
+            },
+            (RequestKind::Class, Recipient::Interface, 0x0a) =>
+                SetupRequest::SetIdle { duration: high, report_id: low, interface: index },
+            (RequestKind::Class, Recipient::Interface, 0x0b) =>
+                SetupRequest::SetProtocol { protocol: value, interface: index },
+            _ => SetupRequest::Other {
+                request_type: request_type,
+                request: request,
+                value: value,
+                index: index,
+                length: length,
+            },
+        }
+    }
+
+    /// Name of the request as used in the USB and HID specifications
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SetupRequest::GetStatus { .. } => "GET_STATUS",
+            SetupRequest::ClearFeature { .. } => "CLEAR_FEATURE",
+            SetupRequest::SetFeature { .. } => "SET_FEATURE",
+            SetupRequest::SetAddress { .. } => "SET_ADDRESS",
+            SetupRequest::GetDescriptor { .. } => "GET_DESCRIPTOR",
+            SetupRequest::SetDescriptor { .. } => "SET_DESCRIPTOR",
+            SetupRequest::GetConfiguration => "GET_CONFIGURATION",
+            SetupRequest::SetConfiguration { .. } => "SET_CONFIGURATION",
+            SetupRequest::GetInterface { .. } => "GET_INTERFACE",
+            SetupRequest::SetInterface { .. } => "SET_INTERFACE",
+            SetupRequest::SynchFrame { .. } => "SYNCH_FRAME",
+            SetupRequest::GetReport { .. } => "GET_REPORT",
+            SetupRequest::GetIdle { .. } => "GET_IDLE",
+            SetupRequest::GetProtocol { .. } => "GET_PROTOCOL",
+            SetupRequest::SetReport { .. } => "SET_REPORT",
+            SetupRequest::SetIdle { .. } => "SET_IDLE",
+            SetupRequest::SetProtocol { .. } => "SET_PROTOCOL",
+            SetupRequest::Other { .. } => "UNKNOWN",
+        }
+    }
+}
+
+impl fmt::Display for SetupRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.name()));
+        match *self {
+            SetupRequest::GetStatus { recipient, index } =>
+                write!(f, " {:?} index={}", recipient, index),
+            SetupRequest::ClearFeature { recipient, feature, index }
+                | SetupRequest::SetFeature { recipient, feature, index } =>
+                write!(f, " {:?} feature={} index={}", recipient, feature, index),
+            SetupRequest::SetAddress { address } => write!(f, " address={}", address),
+            SetupRequest::GetDescriptor { kind, index, lang, length }
+                | SetupRequest::SetDescriptor { kind, index, lang, length } =>
+                write!(f, " {:?} index={} lang=0x{:04x} length={}", kind, index, lang, length),
+            SetupRequest::GetConfiguration => Ok(()),
+            SetupRequest::SetConfiguration { config } => write!(f, " config={}", config),
+            SetupRequest::GetInterface { interface } => write!(f, " interface={}", interface),
+            SetupRequest::SetInterface { interface, alt_setting } =>
+                write!(f, " interface={} alt_setting={}", interface, alt_setting),
+            SetupRequest::SynchFrame { endpoint } => write!(f, " endpoint={}", endpoint),
+            SetupRequest::GetReport { report_type, report_id, interface, length } =>
+                write!(f, " {:?} report_id={} interface={} length={}",
+                       report_type, report_id, interface, length),
+            SetupRequest::GetIdle { report_id, interface } =>
+                write!(f, " report_id={} interface={}", report_id, interface),
+            SetupRequest::GetProtocol { interface } => write!(f, " interface={}", interface),
+            SetupRequest::SetReport { report_type, report_id, interface } =>
+                write!(f, " {:?} report_id={} interface={}", report_type, report_id, interface),
+            SetupRequest::SetIdle { duration, report_id, interface } =>
+                write!(f, " duration={} report_id={} interface={}", duration, report_id, interface),
+            SetupRequest::SetProtocol { protocol, interface } =>
+                write!(f, " protocol={} interface={}", protocol, interface),
+            SetupRequest::Other { request_type, request, value, index, length } =>
+                write!(f, " bmRequestType=0x{:02x} bRequest=0x{:02x} wValue=0x{:04x} \
+                       wIndex=0x{:04x} wLength={}", request_type, request, value, index, length),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use capture::Capture;
+    use super::{SetupRequest, Recipient, DescriptorType, ReportType};
+
+    #[test]
+    fn decodes_captured_requests() {
+        let packets = Capture::from_file("pcap/g910/handshake/handshake.pcap").unwrap().read_all().unwrap();
+        let requests: Vec<_> = packets.iter().filter_map(|p| p.get_request()).collect();
+        assert_eq!(requests.len(), 389);
+        assert_eq!(requests[0], SetupRequest::GetDescriptor {
+            kind: DescriptorType::Device, index: 0, lang: 0, length: 40,
+        });
+        assert_eq!(requests[1].to_string(), "GET_DESCRIPTOR Device index=0 lang=0x0000 length=18");
+        let count = |request: SetupRequest| requests.iter().filter(|&&r| r == request).count();
+        assert_eq!(count(SetupRequest::SetIdle { duration: 0, report_id: 0, interface: 1 }), 1);
+        assert_eq!(count(SetupRequest::GetDescriptor {
+            kind: DescriptorType::Report, index: 0, lang: 1, length: 183,
+        }), 1);
+        assert_eq!(count(SetupRequest::SetReport { report_type: ReportType::Output, report_id: 0x11, interface: 1 }),
+                   241);
+        assert_eq!(count(SetupRequest::SetReport { report_type: ReportType::Output, report_id: 0x12, interface: 1 }),
+                   18);
+        assert!(requests.iter().all(|r| r.name() != "UNKNOWN"));
+    }
+
+    #[test]
+    fn decodes_requests_by_type_and_recipient() {
+        assert_eq!(SetupRequest::decode(0x00, 0x09, 0x0001, 0, 0), SetupRequest::SetConfiguration { config: 1 });
+        assert_eq!(SetupRequest::decode(0x02, 0x01, 0x0000, 0x81, 0), SetupRequest::ClearFeature {
+            recipient: Recipient::Endpoint, feature: 0, index: 0x81,
+        });
+        assert_eq!(SetupRequest::decode(0xa1, 0x01, 0x0310, 1, 7), SetupRequest::GetReport {
+            report_type: ReportType::Feature, report_id: 0x10, interface: 1, length: 7,
+        });
+        assert_eq!(SetupRequest::decode(0x21, 0x0a, 0x7d00, 0, 0).to_string(),
+                   "SET_IDLE duration=125 report_id=0 interface=0");
+        // SET_ADDRESS is only defined for devices, vendor requests are opaque
+        for &(request_type, request) in &[(0x01, 0x05), (0xc0, 0x01), (0x1f, 0x0a)] {
+            assert_eq!(SetupRequest::decode(request_type, request, 2, 3, 4), SetupRequest::Other {
+                request_type: request_type, request: request, value: 2, index: 3, length: 4,
+            });
+        }
+        assert_eq!(SetupRequest::decode(0x80, 0x06, 0x0aee, 0, 18), SetupRequest::GetDescriptor {
+            kind: DescriptorType::Unknown(0x0a), index: 0xee, lang: 0, length: 18,
+        });
+    }
+}
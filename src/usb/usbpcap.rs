@@ -0,0 +1,382 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use byteorder::{ByteOrder, LittleEndian};
+use super::{Packet, PacketHead, ParseError};
+
+/// Length of the USBPcap header common to all transfer types
+pub const MIN_HEADER_LEN: usize = 27;
+/// Length of the USBPcap header of control transfers, including the stage
+const CONTROL_HEADER_LEN: usize = 28;
+const SETUP_LEN: usize = 8;
+
+/// Set in `info` if the packet travels from the device to the host
+const INFO_PDO_TO_FDO: u8 = 0x01;
+const TRANSFER_CONTROL: u8 = 2;
+
+/// Stage of a control transfer as reported by USBPcap
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlStage {
+    Setup,
+    Data,
+    Status,
+    Complete,
+    Unknown(u8),
+}
+
+impl From<u8> for ControlStage {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0 => ControlStage::Setup,
+            1 => ControlStage::Data,
+            2 => ControlStage::Status,
+            3 => ControlStage::Complete,
+            b => ControlStage::Unknown(b),
+        }
+    }
+}
+
+/// A single packet of a USBPcap capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record<'a> {
+    irp_id: u64,
+    status: u32,
+    info: u8,
+    bus: u16,
+    device: u16,
+    endpoint: u8,
+    transfer: u8,
+    stage: Option<ControlStage>,
+    data: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Record<'a>, ParseError> {
+        if bytes.len() < MIN_HEADER_LEN {
+            return Err(ParseError::Truncated {
+                field: "header",
+                needed: MIN_HEADER_LEN,
+                available: bytes.len()
+            });
+        }
+        let header_len = LittleEndian::read_u16(&bytes[0..2]) as usize;
+        if header_len < MIN_HEADER_LEN {
+            return Err(ParseError::InvalidField { field: "header_len", value: header_len as u64 });
+        }
+        if bytes.len() < header_len {
+            return Err(ParseError::Truncated {
+                field: "header",
+                needed: header_len,
+                available: bytes.len()
+            });
+        }
+        let transfer = bytes[22];
+        let stage = if transfer == TRANSFER_CONTROL {
+            if header_len < CONTROL_HEADER_LEN {
+                return Err(ParseError::Truncated {
+                    field: "stage",
+                    needed: CONTROL_HEADER_LEN,
+                    available: header_len
+                });
+            }
+            Some(ControlStage::from(bytes[27]))
+        } else {
+            None
+        };
+        let data_length = LittleEndian::read_u32(&bytes[23..27]) as usize;
+        let end = header_len + data_length;
+        if bytes.len() < end {
+            return Err(ParseError::Truncated {
+                field: "data",
+                needed: end,
+                available: bytes.len()
+            });
+        }
+        Ok(Record {
+            irp_id: LittleEndian::read_u64(&bytes[2..10]),
+            status: LittleEndian::read_u32(&bytes[10..14]),
+            info: bytes[16],
+            bus: LittleEndian::read_u16(&bytes[17..19]),
+            device: LittleEndian::read_u16(&bytes[19..21]),
+            endpoint: bytes[21],
+            transfer: transfer,
+            stage: stage,
+            data: &bytes[header_len..end],
+        })
+    }
+
+    fn is_completion(&self) -> bool {
+        self.info & INFO_PDO_TO_FDO == INFO_PDO_TO_FDO
+    }
+
+    /// Converts this record on its own into a packet, without taking other
+    /// stages of the same control transfer into account.
+    pub fn into_packet(self, sec: u64, usec: u32) -> Packet<'a> {
+        let completion = self.is_completion();
+        let mut head = PacketHead {
+            id: self.irp_id,
+            urb_type: if completion { b'C' } else { b'S' },
+            transfer_type: self.transfer,
+            endpoint_direction: self.endpoint,
+            device: self.device as u8,
+            bus_id: self.bus,
+            setup_request: b'-',
+            data_present: 0,
+            sec: sec,
+            usec: usec,
+            status: usbd_status_to_urb_status(self.status),
+            length: self.data.len() as u32,
+            data_length: self.data.len() as u32,
+            bm_request_type: 0,
+            b_request: 0,
+            descriptor_index: 0,
+            descriptor_type: 0,
+            language_id: 0,
+            w_length: 0,
+            interval: 0,
+            start_frame: 0,
+            transfer_flags: 0,
+            num_iso_desc: 0,
+        };
+        let mut data = self.data;
+        if self.stage == Some(ControlStage::Setup) && data.len() >= SETUP_LEN {
+            let setup = data;
+            head.setup_request = 0;
+            head.bm_request_type = setup[0];
+            head.b_request = setup[1];
+            head.descriptor_index = setup[2];
+            head.descriptor_type = setup[3];
+            head.language_id = LittleEndian::read_u16(&setup[4..6]);
+            head.w_length = LittleEndian::read_u16(&setup[6..8]);
+            // USBPcap reports endpoint 0 without direction for control transfers
+            head.endpoint_direction = (head.endpoint_direction & 0x7f) | (setup[0] & 0x80);
+            head.length = head.w_length as u32;
+            data = &setup[SETUP_LEN..];
+            head.data_length = data.len() as u32;
+        }
+        update_data_present(&mut head, data);
+        Packet { head: head, data: Cow::Borrowed(data) }
+    }
+}
+
+/// usbmon marks absent data with '<' or '>' depending on the direction
+fn update_data_present(head: &mut PacketHead, data: &[u8]) {
+    head.data_present = if !data.is_empty() {
+        0
+    } else if head.endpoint_direction & 0x80 == 0x80 {
+        b'<'
+    } else {
+        b'>'
+    };
+}
+
+/// Translates a Windows `USBD_STATUS` into the negative errno usbmon would
+/// have reported. Unknown codes are kept as is.
+fn usbd_status_to_urb_status(status: u32) -> u32 {
+    let errno: i32 = match status {
+        0x00000000 => 0,
+        // USBD_STATUS_PENDING
+        0x40000000 => 115,
+        // USBD_STATUS_CRC
+        0xc0000001 => 84,
+        // USBD_STATUS_BTSTUFF
+        0xc0000002 => 71,
+        // USBD_STATUS_STALL_PID, USBD_STATUS_ENDPOINT_HALTED
+        0xc0000004 | 0xc0000030 => 32,
+        // USBD_STATUS_DEV_NOT_RESPONDING
+        0xc0000005 => 110,
+        // USBD_STATUS_DATA_OVERRUN, USBD_STATUS_BUFFER_OVERRUN
+        0xc0000008 | 0xc000000c => 75,
+        // USBD_STATUS_DATA_UNDERRUN, USBD_STATUS_BUFFER_UNDERRUN
+        0xc0000009 | 0xc000000d => 121,
+        // USBD_STATUS_DEVICE_GONE
+        0xc0007000 => 19,
+        // USBD_STATUS_CANCELED
+        0xc0010000 => 2,
+        // USBD_STATUS_TIMEOUT
+        0xc0006000 => 110,
+        _ => return status,
+    };
+    (-errno) as u32
+}
+
+/// Reassembles USBPcap records into usbmon-like Submit and Complete packets.
+///
+/// USBPcap splits control transfers into a setup, an optional data and a
+/// status stage and does not record the requested length of IN transfers.
+/// The decoder merges stages sharing an IRP id into a single packet at the
+/// position of the first stage and takes the requested length from the
+/// amount of data the completion actually returned.
+pub struct UsbPcapDecoder {
+    packets: Vec<Packet<'static>>,
+    submits: HashMap<u64, usize>,
+    completions: HashMap<u64, usize>,
+}
+
+impl UsbPcapDecoder {
+    pub fn new() -> UsbPcapDecoder {
+        UsbPcapDecoder {
+            packets: Vec::new(),
+            submits: HashMap::new(),
+            completions: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8], sec: u64, usec: u32) -> Result<(), ParseError> {
+        let record = try!(Record::parse(bytes));
+        let irp_id = record.irp_id;
+        let completion = record.is_completion();
+        let stage = record.stage;
+        let packet = record.into_packet(sec, usec).into_owned();
+
+        if !completion {
+            // OUT data following the setup stage
+            if stage == Some(ControlStage::Data) {
+                if let Some(&i) = self.submits.get(&irp_id) {
+                    let submit = &mut self.packets[i];
+                    submit.head.data_length = packet.data.len() as u32;
+                    update_data_present(&mut submit.head, &packet.data);
+                    submit.data = packet.data;
+                    return Ok(());
+                }
+            }
+            self.submits.insert(irp_id, self.packets.len());
+            self.packets.push(packet);
+            return Ok(());
+        }
+
+        // IN data creates the completion, the following status stage only
+        // contributes its status
+        let i = match self.completions.get(&irp_id) {
+            Some(&i) => {
+                self.packets[i].head.status = packet.head.status;
+                i
+            },
+            None => {
+                self.packets.push(packet);
+                self.packets.len() - 1
+            }
+        };
+        if let Some(&s) = self.submits.get(&irp_id) {
+            let submit = self.packets[s].head;
+            let actual = self.packets[i].data.len();
+            {
+                let complete = &mut self.packets[i];
+                complete.head.endpoint_direction = submit.endpoint_direction;
+                update_data_present(&mut complete.head, &complete.data);
+            }
+            let is_in = submit.endpoint_direction & 0x80 == 0x80;
+            // control submits know their length from the setup packet
+            if is_in && submit.setup_request != 0 {
+                self.packets[s].head.length = actual as u32;
+            }
+        }
+        if stage == Some(ControlStage::Data) {
+            self.completions.insert(irp_id, i);
+        } else {
+            self.completions.remove(&irp_id);
+            self.submits.remove(&irp_id);
+        }
+        Ok(())
+    }
+
+    /// Appends a packet of a different link type, keeping the order of
+    /// captures which mix USBPcap with other interfaces.
+    pub fn push_packet(&mut self, packet: Packet<'static>) {
+        self.packets.push(packet);
+    }
+
+    /// Returns all packets decoded so far.
+    pub fn finish(self) -> Vec<Packet<'static>> {
+        self.packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+    use usb::{Packet, ParseError, UrbType, UrbStatus, TransferType, SetupRequest, ReportType};
+    use super::{UsbPcapDecoder, MIN_HEADER_LEN};
+
+    /// USBPcap packet, `stage` is only written for control transfers
+    fn record(irp_id: u64, status: u32, info: u8, endpoint: u8, transfer: u8, stage: Option<u8>,
+              data: &[u8]) -> Vec<u8> {
+        let header_len = if stage.is_some() { 28 } else { 27 };
+        let mut bytes = vec![0u8; header_len];
+        LittleEndian::write_u16(&mut bytes[0..2], header_len as u16);
+        LittleEndian::write_u64(&mut bytes[2..10], irp_id);
+        LittleEndian::write_u32(&mut bytes[10..14], status);
+        bytes[16] = info;
+        LittleEndian::write_u16(&mut bytes[17..19], 1);
+        LittleEndian::write_u16(&mut bytes[19..21], 3);
+        bytes[21] = endpoint;
+        bytes[22] = transfer;
+        LittleEndian::write_u32(&mut bytes[23..27], data.len() as u32);
+        if let Some(stage) = stage {
+            bytes[27] = stage;
+        }
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn summary(p: &Packet) -> (u64, UrbType, u8, u32, UrbStatus, Vec<u8>) {
+        (p.get_id(), p.get_urb_type(), p.get_endpoint_direction(), p.get_length(), p.get_status(),
+         p.get_data().to_vec())
+    }
+
+    #[test]
+    fn merges_control_stages() {
+        let get_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00];
+        let set_report = [0x21, 0x09, 0x11, 0x02, 0x01, 0x00, 0x14, 0x00];
+        let set_idle = [0x21, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let report = [0x11; 20];
+        let mut decoder = UsbPcapDecoder::new();
+        for (i, r) in [
+            record(1, 0, 0, 0x80, 2, Some(0), &get_descriptor),
+            record(4, 0, 0, 0x82, 1, None, &[]),
+            record(1, 0, 1, 0x80, 2, Some(1), &[0x12; 18]),
+            record(1, 0, 1, 0x80, 2, Some(2), &[]),
+            record(2, 0, 0, 0x00, 2, Some(0), &set_report),
+            record(2, 0, 0, 0x00, 2, Some(1), &report),
+            record(4, 0, 1, 0x82, 1, None, &report),
+            record(2, 0, 1, 0x00, 2, Some(2), &[]),
+            record(3, 0, 0, 0x00, 2, Some(0), &set_idle),
+            // USBD_STATUS_STALL_PID
+            record(3, 0xc0000004, 1, 0x00, 2, Some(2), &[]),
+        ].iter().enumerate() {
+            decoder.push(r, 1, i as u32).unwrap();
+        }
+        let packets = decoder.finish();
+        let summaries: Vec<_> = packets.iter().map(summary).collect();
+        assert_eq!(summaries, vec![
+            (1, UrbType::Submit, 0x80, 64, UrbStatus::Success, vec![]),
+            (4, UrbType::Submit, 0x82, 20, UrbStatus::Success, vec![]),
+            (1, UrbType::Complete, 0x80, 18, UrbStatus::Success, vec![0x12; 18]),
+            (2, UrbType::Submit, 0x00, 20, UrbStatus::Success, report.to_vec()),
+            (4, UrbType::Complete, 0x82, 20, UrbStatus::Success, report.to_vec()),
+            (2, UrbType::Complete, 0x00, 0, UrbStatus::Success, vec![]),
+            (3, UrbType::Submit, 0x00, 0, UrbStatus::Success, vec![]),
+            (3, UrbType::Complete, 0x00, 0, UrbStatus::Pipe, vec![]),
+        ]);
+        assert_eq!(packets[3].get_request(), Some(SetupRequest::SetReport {
+            report_type: ReportType::Output, report_id: 0x11, interface: 1,
+        }));
+        assert_eq!(packets[1].get_transfer_type(), TransferType::Interrupt);
+        // usbmon's markers of absent data
+        assert_eq!(packets.iter().map(|p| p.is_data_present()).collect::<Vec<_>>(),
+                   vec![false, false, true, true, true, false, false, false]);
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let mut decoder = UsbPcapDecoder::new();
+        let r = record(1, 0, 1, 0x82, 1, None, &[1, 2, 3]);
+        assert_eq!(decoder.push(&r[..10], 0, 0),
+                   Err(ParseError::Truncated { field: "header", needed: MIN_HEADER_LEN, available: 10 }));
+        assert_eq!(decoder.push(&r[..29], 0, 0),
+                   Err(ParseError::Truncated { field: "data", needed: 30, available: 29 }));
+        let control = record(1, 0, 0, 0x80, 2, None, &[]);
+        assert_eq!(decoder.push(&control, 0, 0),
+                   Err(ParseError::Truncated { field: "stage", needed: 28, available: 27 }));
+        assert!(decoder.finish().is_empty());
+    }
+}